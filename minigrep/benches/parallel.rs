@@ -0,0 +1,82 @@
+//! Sequential against parallel search over a generated directory of files.
+//!
+//! Run with `cargo bench`. There's no harness: each configuration is timed over a few runs and the
+//! best time is printed, which is enough to see whether the threads pay for themselves.
+
+use minigrep::matcher::Matcher;
+use minigrep::output::{PrintOptions, Printer};
+use minigrep::{search_files, walk, Config};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const FILES: usize = 400;
+const LINES_PER_FILE: usize = 5_000;
+const RUNS: usize = 3;
+
+fn generate(dir: &Path) {
+    fs::create_dir_all(dir).unwrap();
+    for f in 0..FILES {
+        let mut contents = String::new();
+        for n in 0..LINES_PER_FILE {
+            contents.push_str(&format!("file {} line {}: the quick brown fox jumps over the lazy dog\n", f, n));
+            if n % 997 == 0 {
+                contents.push_str("a needle in the haystack\n");
+            }
+        }
+        fs::write(dir.join(format!("{:04}.txt", f)), contents).unwrap();
+    }
+}
+
+fn best_of(runs: usize, mut search: impl FnMut() -> usize) -> (Duration, usize) {
+    let mut best = Duration::MAX;
+    let mut found = 0;
+    for _ in 0..runs {
+        let start = Instant::now();
+        found = search();
+        best = best.min(start.elapsed());
+    }
+    (best, found)
+}
+
+fn main() {
+    let dir = std::env::temp_dir().join(format!("minigrep-bench-{}", std::process::id()));
+    generate(&dir);
+
+    for args in [&["minigrep", "needle"][..], &["minigrep", "-i", "NEEDLE"][..]] {
+        let args = args.iter().map(|s| s.to_string()).chain(Some(dir.display().to_string()));
+        let config = Config::new(args).unwrap();
+        let matcher = Matcher::new(&config).unwrap();
+        let files = walk::files(&config.paths);
+
+        let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+        let mut counts: Vec<usize> = vec![1, 2, 4, cpus];
+        counts.sort_unstable();
+        counts.dedup();
+
+        println!("{} ({} files, {} lines each)", config.patterns[0], files.len(), LINES_PER_FILE);
+        let mut sequential = None;
+        for threads in counts {
+            let (time, found) = best_of(RUNS, || {
+                let options = PrintOptions {
+                    with_path: true,
+                    ..PrintOptions::default()
+                };
+                let mut printer = Printer::new(io::sink(), options);
+                search_files(&config, &matcher, &files, threads, &mut printer).unwrap().lines
+            });
+            let baseline = *sequential.get_or_insert(time);
+            println!(
+                "  {:>2} thread(s): {:>8.1?}  {:.2}x  ({} lines)",
+                threads,
+                time,
+                baseline.as_secs_f64() / time.as_secs_f64(),
+                found
+            );
+        }
+    }
+
+    fs::remove_dir_all(dir).unwrap();
+}
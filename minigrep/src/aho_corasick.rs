@@ -0,0 +1,185 @@
+//! Searching for many literal patterns at once with an Aho-Corasick automaton.
+//!
+//! The patterns are stored in a trie of bytes. Each node also gets a failure link to the node for
+//! the longest proper suffix of its path that is also in the trie, and the list of patterns that
+//! end there, including those reached through failure links. Searching then reads each byte of the
+//! text once, however many patterns there are.
+
+use std::collections::VecDeque;
+
+struct Node {
+    /// Sorted by byte, for binary search.
+    children: Vec<(u8, usize)>,
+    fail: usize,
+    /// Lengths of the patterns that end at this node.
+    lengths: Vec<usize>,
+}
+
+impl Node {
+    fn new() -> Node {
+        Node {
+            children: Vec::new(),
+            fail: 0,
+            lengths: Vec::new(),
+        }
+    }
+
+    fn child(&self, byte: u8) -> Option<usize> {
+        self.children
+            .binary_search_by_key(&byte, |&(b, _)| b)
+            .ok()
+            .map(|i| self.children[i].1)
+    }
+}
+
+pub struct AhoCorasick {
+    nodes: Vec<Node>,
+    longest: usize,
+    ascii_case_insensitive: bool,
+}
+
+impl AhoCorasick {
+    /// With `ascii_case_insensitive`, uppercase ASCII letters in the text match the lowercase ones
+    /// in the patterns, which should have no uppercase ASCII of their own.
+    pub fn new<S: AsRef<str>>(patterns: &[S], ascii_case_insensitive: bool) -> AhoCorasick {
+        let mut nodes = vec![Node::new()];
+        let mut longest = 0;
+
+        for pattern in patterns {
+            let pattern = pattern.as_ref().as_bytes();
+            let mut node = 0;
+            for &byte in pattern {
+                node = match nodes[node].child(byte) {
+                    Some(child) => child,
+                    None => {
+                        nodes.push(Node::new());
+                        let child = nodes.len() - 1;
+                        let children = &mut nodes[node].children;
+                        let at = children.partition_point(|&(b, _)| b < byte);
+                        children.insert(at, (byte, child));
+                        child
+                    }
+                };
+            }
+            if !nodes[node].lengths.contains(&pattern.len()) {
+                nodes[node].lengths.push(pattern.len());
+            }
+            longest = longest.max(pattern.len());
+        }
+
+        // Breadth first, so every node's failure link is finished before its children need it.
+        let mut queue: VecDeque<usize> = nodes[0].children.iter().map(|&(_, c)| c).collect();
+        while let Some(node) = queue.pop_front() {
+            for (byte, child) in nodes[node].children.clone() {
+                let mut fail = nodes[node].fail;
+                let fail = loop {
+                    if let Some(next) = nodes[fail].child(byte) {
+                        break next;
+                    }
+                    if fail == 0 {
+                        break 0;
+                    }
+                    fail = nodes[fail].fail;
+                };
+                nodes[child].fail = fail;
+                let inherited = nodes[fail].lengths.clone();
+                for length in inherited {
+                    if !nodes[child].lengths.contains(&length) {
+                        nodes[child].lengths.push(length);
+                    }
+                }
+                queue.push_back(child);
+            }
+        }
+
+        AhoCorasick {
+            nodes,
+            longest,
+            ascii_case_insensitive,
+        }
+    }
+
+    fn next(&self, mut node: usize, byte: u8) -> usize {
+        let byte = if self.ascii_case_insensitive {
+            byte.to_ascii_lowercase()
+        } else {
+            byte
+        };
+        loop {
+            if let Some(next) = self.nodes[node].child(byte) {
+                return next;
+            }
+            if node == 0 {
+                return 0;
+            }
+            node = self.nodes[node].fail;
+        }
+    }
+
+    /// The leftmost match starting at or after `start`, the longest if several start there, among
+    /// those for which `accept(start, end)` holds.
+    pub fn find<F>(&self, text: &[u8], start: usize, mut accept: F) -> Option<(usize, usize)>
+    where
+        F: FnMut(usize, usize) -> bool,
+    {
+        let mut node = 0;
+        let mut best: Option<(usize, usize)> = None;
+        let mut end = start;
+        loop {
+            for &length in &self.nodes[node].lengths {
+                let s = end - length;
+                let better = best.is_none_or(|(bs, be)| s < bs || (s == bs && end > be));
+                if better && accept(s, end) {
+                    best = Some((s, end));
+                }
+            }
+            // Nothing ending further on can be long enough to start at or before the best match.
+            if best.is_some_and(|(bs, _)| end >= bs + self.longest) || end == text.len() {
+                return best;
+            }
+            node = self.next(node, text[end]);
+            end += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find_all(automaton: &AhoCorasick, text: &str) -> Vec<(usize, usize)> {
+        let mut found = Vec::new();
+        let mut start = 0;
+        while let Some((s, e)) = automaton.find(text.as_bytes(), start, |_, _| true) {
+            found.push((s, e));
+            start = e.max(s + 1);
+        }
+        found
+    }
+
+    #[test]
+    fn finds_leftmost_longest_matches() {
+        let automaton = AhoCorasick::new(&["he", "she", "his", "hers"], false);
+
+        assert_eq!(find_all(&automaton, "ushers"), vec![(1, 4)]);
+        assert_eq!(find_all(&automaton, "his hers"), vec![(0, 3), (4, 8)]);
+        assert_eq!(find_all(&automaton, "HERS"), vec![]);
+    }
+
+    #[test]
+    fn accepts_ascii_case_insensitively_and_filters() {
+        let automaton = AhoCorasick::new(&["abc", "bcd"], true);
+
+        assert_eq!(automaton.find(b"xABCD", 0, |_, _| true), Some((1, 4)));
+        assert_eq!(automaton.find(b"xABCD", 0, |s, _| s != 1), Some((2, 5)));
+        assert_eq!(automaton.find(b"xABCD", 2, |_, _| true), Some((2, 5)));
+    }
+
+    #[test]
+    fn empty_pattern_matches_everywhere() {
+        let automaton = AhoCorasick::new(&["", "b"], false);
+
+        assert_eq!(automaton.find(b"ab", 0, |_, _| true), Some((0, 0)));
+        assert_eq!(automaton.find(b"ab", 1, |_, _| true), Some((1, 2)));
+    }
+}
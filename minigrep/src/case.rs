@@ -0,0 +1,98 @@
+//! Unicode case folding, for matching text regardless of case.
+//!
+//! Lowercasing isn't enough for that: "STRASSE" lowercases to "strasse" but "straße" stays as it
+//! is, and "ΣΑΣ" lowercases to "σασ" where the word would be written "σας". Full case folding, as
+//! defined by Unicode's CaseFolding.txt, maps all of these to one form, expanding characters like
+//! `ß` to `ss` on the way. The standard library has no case folding of its own, so it is derived
+//! here from the case mappings it does have.
+
+/// Append the full case folding of `c` to `folded`.
+///
+/// Lowercasing, uppercasing and lowercasing again reaches the folded form for every character but
+/// one: the Turkish dotless `ı`, which the default folding leaves alone rather than merging with
+/// `i`. (`İ` folds to `i` followed by a combining dot, which the round trip gets right.) Cherokee
+/// ends up lowercase where CaseFolding.txt picks uppercase, which makes no difference to which
+/// strings compare equal.
+pub fn fold_char(c: char, folded: &mut String) {
+    if c.is_ascii() {
+        folded.push(c.to_ascii_lowercase());
+    } else if c == 'ı' {
+        folded.push(c);
+    } else {
+        for lower in c.to_lowercase() {
+            for upper in lower.to_uppercase() {
+                folded.extend(upper.to_lowercase());
+            }
+        }
+    }
+}
+
+pub fn fold(text: &str) -> String {
+    let mut folded = String::with_capacity(text.len());
+    for c in text.chars() {
+        fold_char(c, &mut folded);
+    }
+    folded
+}
+
+/// Fold `text`, also returning for every byte of the result (plus one past the end) the offset in
+/// `text` of the character it came from. Folding can change a character's length in UTF-8, so
+/// match positions in the folded text have to be mapped back through this table.
+pub fn fold_with_offsets(text: &str) -> (String, Vec<usize>) {
+    let mut folded = String::with_capacity(text.len());
+    let mut offsets = Vec::with_capacity(text.len() + 1);
+    for (i, c) in text.char_indices() {
+        fold_char(c, &mut folded);
+        offsets.resize(folded.len(), i);
+    }
+    offsets.push(text.len());
+    (folded, offsets)
+}
+
+/// Whether a query has any uppercase letters, for smart case. In a regex, the letter after a `\` is
+/// part of an escape such as `\W` rather than something to match, so it doesn't count.
+pub fn has_uppercase(query: &str, regex: bool) -> bool {
+    let mut escaped = false;
+    for c in query.chars() {
+        if escaped {
+            escaped = false;
+        } else if regex && c == '\\' {
+            escaped = true;
+        } else if c.is_uppercase() {
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn folds_expansions_and_special_cases() {
+        assert_eq!(fold("Straße"), fold("STRASSE"));
+        assert_eq!(fold("ẞ"), "ss");
+        assert_eq!(fold("ΣΑΣ"), fold("σας"));
+        assert_eq!(fold("ﬁle"), "file");
+        assert_eq!(fold("İ"), "i\u{307}");
+        assert_ne!(fold("ı"), fold("I"));
+        assert_eq!(fold("K"), "k");
+    }
+
+    #[test]
+    fn offsets_point_back_into_the_original() {
+        let (folded, offsets) = fold_with_offsets("aßc");
+        assert_eq!(folded, "assc");
+        assert_eq!(offsets, vec![0, 1, 1, 3, 4]);
+    }
+
+    #[test]
+    fn smart_case_ignores_regex_escapes() {
+        assert!(!has_uppercase("straße", false));
+        assert!(has_uppercase("Straße", false));
+        assert!(has_uppercase(r"\W", false));
+        assert!(!has_uppercase(r"\w+\W\d", true));
+        assert!(has_uppercase(r"\wA", true));
+    }
+}
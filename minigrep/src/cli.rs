@@ -0,0 +1,537 @@
+//! Command-line parsing for `Config::new`.
+
+use crate::fuzzy;
+use crate::output::ColorChoice;
+use crate::{Command, Config};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+
+pub const USAGE: &str = "Usage: minigrep [OPTIONS] PATTERN PATH...
+       minigrep [OPTIONS] (-e PATTERN | -f FILE)... PATH...
+       minigrep index DIR...
+       minigrep --interactive [OPTIONS] [-e PATTERN] [PATH...]";
+
+pub const HELP: &str = "\
+Usage: minigrep [OPTIONS] PATTERN PATH...
+       minigrep [OPTIONS] (-e PATTERN | -f FILE)... PATH...
+       minigrep index DIR...
+       minigrep --interactive [OPTIONS] [-e PATTERN] [PATH...]
+
+Search for PATTERN, or for any of the patterns given with -e and -f, in each
+PATH. Directories are searched recursively, skipping binary files and anything
+excluded by .gitignore or .ignore files. A PATH of - reads standard input.
+Compressed input is searched decompressed.
+
+minigrep index builds an index of each DIR, or brings it up to date, so that
+searches of DIR with --index only read the files that could match. To search
+for the word index, use -e index or put -- before it.
+
+minigrep --interactive searches each PATH, or the current directory, as the
+pattern is typed. Enter prints the selected match as PATH:LINE; Ctrl-O opens it
+in $VISUAL or $EDITOR. Ctrl-L redraws the screen after the terminal is resized.
+
+Options:
+  -e, --regexp PATTERN       search for PATTERN; may be repeated
+  -f, --file FILE            search for each line of FILE (- for standard input)
+  -i, --ignore-case          match regardless of case (also set by CASE_INSENSITIVE)
+  -S, --smart-case           match regardless of case unless PATTERN has uppercase
+  -v, --invert-match         select lines that do not match
+  -n, --line-number          prefix each line with its line number
+  -b, --byte-offset          prefix each line with its byte offset in the file
+  -A, --after-context NUM    print NUM lines of context after each match
+  -B, --before-context NUM   print NUM lines of context before each match
+  -C, --context NUM          print NUM lines of context around each match
+      --json                 print each line found, and a summary, as JSON
+  -r, --replace TEXT         print lines with each match replaced by TEXT, where
+                             $1 or ${1} is a capture group and $0 the whole match
+      --dry-run              with --replace, print a diff of the changes instead
+      --in-place             with --replace, rewrite the files
+      --color[=WHEN]         highlight matches: always, never or auto (the
+                             default, which colors only output to a terminal)
+  -c, --count                print only a count of selected lines per file
+  -m, --max-count NUM        stop reading a file after NUM selected lines
+  -q, --quiet, --silent      print nothing and stop at the first selected line
+  -l, --files-with-matches   print only the names of files with selected lines
+  -w, --word-regexp          only match whole words
+  -x, --line-regexp          only match whole lines
+      --regex                treat PATTERN as a regular expression
+      --fuzzy N              match PATTERN with up to N characters inserted,
+                             deleted or changed, showing the edits needed as ~N
+      --no-decompress        search gzip, zstd and xz files as they are rather
+                             than their contents
+      --index                skip files that the index of a directory being
+                             searched rules out
+      --interactive          search as the pattern is typed, in a full screen view
+  -j, --threads NUM          search up to NUM files at once (default: one per CPU)
+  -h, --help                 print this help and exit
+  -V, --version              print the version and exit
+      --                     treat all following arguments as PATTERN and PATHs
+
+Short options can be combined, as in -inv, and take values as -C2 or -C 2.
+
+The exit status is 0 if a line was selected, 1 if none was, and 2 if there was
+an error, unless -q found a line.
+";
+
+/// Why no `Config` was produced. `Help` and `Version` aren't failures, but `main` still has to
+/// print something and stop.
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    Help,
+    Version,
+    Usage(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Help => write!(f, "{}", HELP),
+            ConfigError::Version => write!(f, "minigrep {}", env!("CARGO_PKG_VERSION")),
+            ConfigError::Usage(message) => write!(f, "{}\n{}", message, USAGE),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn usage<T>(message: String) -> Result<T, ConfigError> {
+    Err(ConfigError::Usage(message))
+}
+
+fn number(option: &str, value: Option<String>) -> Result<usize, ConfigError> {
+    match value {
+        Some(value) => value
+            .parse()
+            .or_else(|_| usage(format!("invalid number '{}' for {}", value, option))),
+        None => usage(format!("option {} requires an argument", option)),
+    }
+}
+
+fn required(option: &str, value: Option<String>) -> Result<String, ConfigError> {
+    match value {
+        Some(value) => Ok(value),
+        None => usage(format!("option {} requires an argument", option)),
+    }
+}
+
+/// The patterns in a `-f` file, one per line.
+fn pattern_file(path: &str) -> Result<Vec<String>, ConfigError> {
+    let mut contents = String::new();
+    let read = if path == crate::STDIN_PATH {
+        io::stdin().read_to_string(&mut contents).map(|_| ())
+    } else {
+        fs::read_to_string(path).map(|read| contents = read)
+    };
+    match read {
+        Ok(()) => Ok(contents.lines().map(String::from).collect()),
+        Err(e) => usage(format!("{}: {}", path, e)),
+    }
+}
+
+fn color_choice(value: &str) -> Result<ColorChoice, ConfigError> {
+    match value {
+        "always" => Ok(ColorChoice::Always),
+        "never" => Ok(ColorChoice::Never),
+        "auto" => Ok(ColorChoice::Auto),
+        _ => usage(format!("invalid color choice '{}'", value)),
+    }
+}
+
+/// Parse the arguments after the program name.
+pub fn parse<I>(args: I) -> Result<Config, ConfigError>
+where
+    I: Iterator<Item = String>,
+{
+    let mut config = Config {
+        command: Command::Search,
+        patterns: Vec::new(),
+        paths: Vec::new(),
+        // We’re using the is_err method on the Result to check whether it’s an error and therefore
+        // unset, which means it should do a case-sensitive search. If the CASE_INSENSITIVE
+        // environment variable is set to anything, is_err will return false and the program will
+        // perform a case-insensitive search.
+        case_sensitive: env::var("CASE_INSENSITIVE").is_err(),
+        smart_case: false,
+        regex: false,
+        invert: false,
+        line_number: false,
+        count: false,
+        files_with_matches: false,
+        word: false,
+        line_regexp: false,
+        byte_offset: false,
+        before_context: 0,
+        after_context: 0,
+        color: ColorChoice::Auto,
+        threads: 0,
+        fuzzy: None,
+        json: false,
+        replace: None,
+        dry_run: false,
+        in_place: false,
+        decompress: true,
+        use_index: false,
+        interactive: false,
+        quiet: false,
+        max_count: None,
+    };
+
+    let mut args = args.peekable();
+    if args.peek().is_some_and(|arg| arg == "index") {
+        args.next();
+        config.command = Command::Index;
+        for arg in args {
+            match arg.as_str() {
+                "-h" | "--help" => return Err(ConfigError::Help),
+                _ if arg.starts_with('-') => return usage(format!("unknown option '{}'", arg)),
+                _ => config.paths.push(arg),
+            }
+        }
+        if config.paths.is_empty() {
+            return usage(String::from("minigrep index needs a directory"));
+        }
+        return Ok(config);
+    }
+
+    let mut positional = Vec::new();
+    let mut options_done = false;
+    // Once a pattern comes from -e or -f, every positional argument is a path.
+    let mut patterns_given = false;
+
+    while let Some(arg) = args.next() {
+        if options_done || arg == "-" || !arg.starts_with('-') {
+            positional.push(arg);
+        } else if arg == "--" {
+            options_done = true;
+        } else if let Some(long) = arg.strip_prefix("--") {
+            // Long options take their value either as `--name=value` or as the next argument.
+            let (long, inline) = match long.find('=') {
+                Some(i) => (&long[..i], Some(long[i + 1..].to_string())),
+                None => (long, None),
+            };
+            let option = format!("--{}", long);
+            match long {
+                "after-context" => {
+                    config.after_context = number(&option, inline.or_else(|| args.next()))?
+                }
+                "before-context" => {
+                    config.before_context = number(&option, inline.or_else(|| args.next()))?
+                }
+                "context" => {
+                    let lines = number(&option, inline.or_else(|| args.next()))?;
+                    config.before_context = lines;
+                    config.after_context = lines;
+                }
+                "threads" => config.threads = number(&option, inline.or_else(|| args.next()))?,
+                "max-count" => {
+                    config.max_count = Some(number(&option, inline.or_else(|| args.next()))?)
+                }
+                "regexp" => {
+                    config.patterns.push(required(&option, inline.or_else(|| args.next()))?);
+                    patterns_given = true;
+                }
+                "fuzzy" => config.fuzzy = Some(number(&option, inline.or_else(|| args.next()))?),
+                "replace" => {
+                    config.replace = Some(required(&option, inline.or_else(|| args.next()))?)
+                }
+                "file" => {
+                    let path = required(&option, inline.or_else(|| args.next()))?;
+                    config.patterns.extend(pattern_file(&path)?);
+                    patterns_given = true;
+                }
+                "color" | "colour" => {
+                    config.color = color_choice(inline.as_deref().unwrap_or("auto"))?
+                }
+                _ if inline.is_some() => {
+                    return usage(format!("option '{}' doesn't take a value", option))
+                }
+                "ignore-case" => {
+                    config.case_sensitive = false;
+                    config.smart_case = false;
+                }
+                "smart-case" => config.smart_case = true,
+                "invert-match" => config.invert = true,
+                "line-number" => config.line_number = true,
+                "byte-offset" => config.byte_offset = true,
+                "count" => config.count = true,
+                "files-with-matches" => config.files_with_matches = true,
+                "word-regexp" => config.word = true,
+                "line-regexp" => config.line_regexp = true,
+                "regex" => config.regex = true,
+                "json" => config.json = true,
+                "dry-run" => config.dry_run = true,
+                "in-place" => config.in_place = true,
+                "no-decompress" => config.decompress = false,
+                "index" => config.use_index = true,
+                "interactive" => config.interactive = true,
+                "quiet" | "silent" => config.quiet = true,
+                "help" => return Err(ConfigError::Help),
+                "version" => return Err(ConfigError::Version),
+                _ => return usage(format!("unknown option '{}'", arg)),
+            }
+        } else {
+            let flags = &arg[1..];
+            for (i, flag) in flags.char_indices() {
+                if let 'A' | 'B' | 'C' | 'j' | 'm' | 'e' | 'f' | 'r' = flag {
+                    // The rest of the cluster, or else the next argument, is the value.
+                    let rest = &flags[i + 1..];
+                    let value = if rest.is_empty() {
+                        args.next()
+                    } else {
+                        Some(rest.to_string())
+                    };
+                    let option = format!("-{}", flag);
+                    match flag {
+                        'A' => config.after_context = number(&option, value)?,
+                        'B' => config.before_context = number(&option, value)?,
+                        'C' => {
+                            config.before_context = number(&option, value)?;
+                            config.after_context = config.before_context;
+                        }
+                        'j' => config.threads = number(&option, value)?,
+                        'm' => config.max_count = Some(number(&option, value)?),
+                        'r' => config.replace = Some(required(&option, value)?),
+                        'e' => {
+                            config.patterns.push(required(&option, value)?);
+                            patterns_given = true;
+                        }
+                        _ => {
+                            config.patterns.extend(pattern_file(&required(&option, value)?)?);
+                            patterns_given = true;
+                        }
+                    }
+                    break;
+                }
+                match flag {
+                    'i' => {
+                        config.case_sensitive = false;
+                        config.smart_case = false;
+                    }
+                    'S' => config.smart_case = true,
+                    'q' => config.quiet = true,
+                    'v' => config.invert = true,
+                    'n' => config.line_number = true,
+                    'b' => config.byte_offset = true,
+                    'c' => config.count = true,
+                    'l' => config.files_with_matches = true,
+                    'w' => config.word = true,
+                    'x' => config.line_regexp = true,
+                    'h' => return Err(ConfigError::Help),
+                    'V' => return Err(ConfigError::Version),
+                    _ => return usage(format!("unknown option '-{}'", flag)),
+                }
+            }
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    // The interactive search's pattern is typed in, so the arguments are all paths.
+    if !patterns_given && !config.interactive {
+        match positional.next() {
+            Some(query) => config.patterns.push(query),
+            None => return usage(String::from("Didn't get a query string")),
+        }
+    }
+    config.paths = positional.collect();
+    if config.paths.is_empty() {
+        if !config.interactive {
+            return usage(String::from("Didn't get a file name"));
+        }
+        config.paths.push(String::from("."));
+    }
+    if config.interactive {
+        if config.patterns.len() > 1 {
+            return usage(String::from("--interactive starts from at most one pattern"));
+        }
+        if config.json || config.count || config.files_with_matches || config.replace.is_some() {
+            return usage(String::from(
+                "--interactive can't be combined with --json, --count, --files-with-matches or --replace",
+            ));
+        }
+    }
+    if config.json && (config.count || config.files_with_matches) {
+        return usage(String::from("--json can't be combined with --count or --files-with-matches"));
+    }
+    if config.fuzzy.is_some() {
+        if config.regex {
+            return usage(String::from("--fuzzy can't be combined with --regex"));
+        }
+        if config.patterns.iter().any(|p| p.chars().count() > fuzzy::MAX_PATTERN_LEN) {
+            return usage(format!(
+                "--fuzzy patterns can be at most {} characters long",
+                fuzzy::MAX_PATTERN_LEN
+            ));
+        }
+    }
+    if config.replace.is_some() && config.invert {
+        return usage(String::from("--replace can't be combined with --invert-match"));
+    }
+    if (config.dry_run || config.in_place) && config.replace.is_none() {
+        return usage(String::from("--dry-run and --in-place need --replace"));
+    }
+    if config.dry_run && config.in_place {
+        return usage(String::from("--dry-run and --in-place can't be combined"));
+    }
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Config, ConfigError> {
+        parse(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn combined_short_flags_and_long_options() {
+        let config = parse_args(&["-inv", "--count", "to", "poem.txt", "src"]).unwrap();
+
+        assert!(!config.case_sensitive);
+        assert!(config.invert && config.line_number && config.count);
+        assert!(!config.files_with_matches && !config.word && !config.regex);
+        assert_eq!(config.patterns, vec!["to"]);
+        assert_eq!(config.paths, vec!["poem.txt", "src"]);
+    }
+
+    #[test]
+    fn context_and_color_values() {
+        let config = parse_args(&["-nC2", "-A", "5", "--color=never", "to", "poem.txt"]).unwrap();
+        assert!(config.line_number);
+        assert_eq!((config.before_context, config.after_context), (2, 5));
+        assert_eq!(config.color, ColorChoice::Never);
+
+        let config = parse_args(&["--before-context", "1", "--color", "to", "poem.txt"]).unwrap();
+        assert_eq!((config.before_context, config.after_context), (1, 0));
+        assert_eq!(config.color, ColorChoice::Auto);
+
+        assert!(matches!(parse_args(&["-A"]), Err(ConfigError::Usage(_))));
+        assert!(matches!(parse_args(&["-Bx", "to", "poem.txt"]), Err(ConfigError::Usage(_))));
+        assert!(matches!(parse_args(&["--count=3", "to", "poem.txt"]), Err(ConfigError::Usage(_))));
+    }
+
+    #[test]
+    fn last_of_ignore_case_and_smart_case_wins() {
+        assert!(parse_args(&["-iS", "to", "src"]).unwrap().smart_case);
+        let config = parse_args(&["-S", "--ignore-case", "to", "src"]).unwrap();
+        assert!(!config.smart_case && !config.case_sensitive);
+    }
+
+    #[test]
+    fn patterns_from_options_and_files() {
+        let file = std::env::temp_dir().join(format!("minigrep-patterns-{}", std::process::id()));
+        fs::write(&file, "alpha\nbeta\n").unwrap();
+        let file = file.to_str().unwrap();
+
+        let config = parse_args(&["-xe", "one", "--regexp=two", "-f", file, "poem.txt"]).unwrap();
+        assert!(config.line_regexp);
+        assert_eq!(config.patterns, vec!["one", "two", "alpha", "beta"]);
+        assert_eq!(config.paths, vec!["poem.txt"]);
+
+        assert!(matches!(parse_args(&["-e"]), Err(ConfigError::Usage(_))));
+        assert!(matches!(parse_args(&["-f", "no/such/file", "poem.txt"]), Err(ConfigError::Usage(_))));
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn json_only_prints_lines() {
+        assert!(parse_args(&["--json", "-C1", "to", "poem.txt"]).unwrap().json);
+        assert!(matches!(parse_args(&["--json", "-l", "to", "poem.txt"]), Err(ConfigError::Usage(_))));
+    }
+
+    #[test]
+    fn replace_options() {
+        let config = parse_args(&["-r$1", "--in-place", "(a)", "poem.txt"]).unwrap();
+        assert_eq!(config.replace.as_deref(), Some("$1"));
+        assert!(config.in_place && !config.dry_run);
+
+        assert!(matches!(parse_args(&["--dry-run", "a", "poem.txt"]), Err(ConfigError::Usage(_))));
+        assert!(matches!(parse_args(&["-vr", "b", "a", "poem.txt"]), Err(ConfigError::Usage(_))));
+    }
+
+    #[test]
+    fn fuzzy_limits() {
+        assert_eq!(parse_args(&["--fuzzy=2", "to", "poem.txt"]).unwrap().fuzzy, Some(2));
+        assert!(matches!(parse_args(&["--fuzzy", "1", "--regex", "t.", "poem.txt"]), Err(ConfigError::Usage(_))));
+        let long = "x".repeat(65);
+        assert!(matches!(parse_args(&["--fuzzy=1", &long, "poem.txt"]), Err(ConfigError::Usage(_))));
+    }
+
+    #[test]
+    fn index_command() {
+        let config = parse_args(&["index", "src", "tests"]).unwrap();
+        assert_eq!(config.command, Command::Index);
+        assert_eq!(config.paths, vec!["src", "tests"]);
+        assert!(parse_args(&["index"]).is_err());
+        assert!(parse_args(&["index", "-n", "src"]).is_err());
+
+        // Only as the first argument; otherwise it's a pattern or path as usual.
+        let config = parse_args(&["--", "index", "src"]).unwrap();
+        assert_eq!((config.command, config.patterns), (Command::Search, vec![String::from("index")]));
+        let config = parse_args(&["--index", "-n", "x", "src"]).unwrap();
+        assert!(config.use_index && config.command == Command::Search);
+    }
+
+    #[test]
+    fn interactive_arguments_are_paths() {
+        let config = parse_args(&["--interactive", "src", "tests"]).unwrap();
+        assert!(config.interactive && config.patterns.is_empty());
+        assert_eq!(config.paths, vec!["src", "tests"]);
+        assert_eq!(parse_args(&["--interactive", "-e", "to"]).unwrap().paths, vec!["."]);
+        assert!(parse_args(&["--interactive", "-e", "a", "-e", "b"]).is_err());
+        assert!(parse_args(&["--interactive", "-c"]).is_err());
+    }
+
+    #[test]
+    fn quiet_and_max_count() {
+        let config = parse_args(&["-qm3", "to", "poem.txt"]).unwrap();
+        assert!(config.quiet);
+        assert_eq!(config.max_count, Some(3));
+        let config = parse_args(&["--max-count=0", "--silent", "to", "poem.txt"]).unwrap();
+        assert!(config.quiet);
+        assert_eq!(config.max_count, Some(0));
+        assert!(parse_args(&["-m", "x", "to", "poem.txt"]).is_err());
+    }
+
+    #[test]
+    fn decompression_can_be_turned_off() {
+        assert!(parse_args(&["to", "logs"]).unwrap().decompress);
+        assert!(!parse_args(&["--no-decompress", "to", "logs"]).unwrap().decompress);
+    }
+
+    #[test]
+    fn thread_count() {
+        assert_eq!(parse_args(&["to", "src"]).unwrap().threads, 0);
+        assert_eq!(parse_args(&["-cj4", "to", "src"]).unwrap().threads, 4);
+        assert_eq!(parse_args(&["--threads", "2", "to", "src"]).unwrap().threads, 2);
+    }
+
+    #[test]
+    fn double_dash_ends_options() {
+        let config = parse_args(&["-w", "--", "-v", "poem.txt"]).unwrap();
+
+        assert!(config.word && !config.invert);
+        assert_eq!(config.patterns, vec!["-v"]);
+    }
+
+    #[test]
+    fn reports_usage_errors() {
+        assert_eq!(
+            parse_args(&["-z", "to", "poem.txt"]).err(),
+            Some(ConfigError::Usage(String::from("unknown option '-z'")))
+        );
+        assert!(matches!(parse_args(&["to"]), Err(ConfigError::Usage(_))));
+        assert!(matches!(parse_args(&[]), Err(ConfigError::Usage(_))));
+        assert_eq!(parse_args(&["to", "-h"]).err(), Some(ConfigError::Help));
+        assert_eq!(parse_args(&["--version"]).err(), Some(ConfigError::Version));
+    }
+
+    #[test]
+    fn former_case_flag_is_now_a_path() {
+        let config = parse_args(&["to", "poem.txt", "yes"]).unwrap();
+        assert_eq!(config.paths, vec!["poem.txt", "yes"]);
+    }
+}
@@ -0,0 +1,162 @@
+//! Searching compressed files as if they weren't, for rotated logs and the like.
+//!
+//! The format is recognised by the first bytes of the input rather than by the file name, so
+//! standard input works too. gzip is always read; zstd and xz need the `zstd` and `xz` cargo
+//! features, and without them such input is reported rather than skipped as binary. Several gzip
+//! members one after another, as `cat a.gz b.gz` makes, are read as one stream.
+
+use flate2::bufread::MultiGzDecoder;
+use std::io::{self, BufRead, BufReader};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: [u8; 6] = [0xfd, b'7', b'z', b'X', b'Z', 0];
+
+impl Format {
+    /// The compression format that data starting with `prefix` is in, if any.
+    pub fn detect(prefix: &[u8]) -> Option<Format> {
+        if prefix.starts_with(&GZIP_MAGIC) {
+            Some(Format::Gzip)
+        } else if prefix.starts_with(&ZSTD_MAGIC) {
+            Some(Format::Zstd)
+        } else if prefix.starts_with(&XZ_MAGIC) {
+            Some(Format::Xz)
+        } else {
+            None
+        }
+    }
+}
+
+/// `reader`, decompressed if it starts like a compressed stream.
+pub fn reader<'a, R: BufRead + 'a>(mut reader: R) -> io::Result<Box<dyn BufRead + 'a>> {
+    Ok(match Format::detect(reader.fill_buf()?) {
+        None => Box::new(reader),
+        Some(Format::Gzip) => Box::new(BufReader::new(MultiGzDecoder::new(reader))),
+        Some(Format::Zstd) => zstd(reader)?,
+        Some(Format::Xz) => xz(reader)?,
+    })
+}
+
+#[cfg(feature = "zstd")]
+fn zstd<'a, R: BufRead + 'a>(reader: R) -> io::Result<Box<dyn BufRead + 'a>> {
+    Ok(Box::new(BufReader::new(
+        zstd::stream::read::Decoder::with_buffer(reader)?,
+    )))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd<'a, R: BufRead + 'a>(_: R) -> io::Result<Box<dyn BufRead + 'a>> {
+    Err(unsupported("zstd"))
+}
+
+#[cfg(feature = "xz")]
+fn xz<'a, R: BufRead + 'a>(reader: R) -> io::Result<Box<dyn BufRead + 'a>> {
+    Ok(Box::new(BufReader::new(
+        xz2::bufread::XzDecoder::new_multi_decoder(reader),
+    )))
+}
+
+#[cfg(not(feature = "xz"))]
+fn xz<'a, R: BufRead + 'a>(_: R) -> io::Result<Box<dyn BufRead + 'a>> {
+    Err(unsupported("xz"))
+}
+
+#[cfg(not(all(feature = "zstd", feature = "xz")))]
+fn unsupported(feature: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "{} compressed, which needs minigrep built with the `{}` feature",
+            feature, feature
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    const POEM: &str = include_str!("../poem.txt");
+
+    fn read(bytes: &[u8]) -> io::Result<String> {
+        let mut text = String::new();
+        reader(bytes)?.read_to_string(&mut text)?;
+        Ok(text)
+    }
+
+    #[test]
+    fn detects_formats_by_their_magic_bytes() {
+        assert_eq!(
+            Format::detect(include_bytes!("../tests/fixtures/poem.txt.gz")),
+            Some(Format::Gzip)
+        );
+        assert_eq!(
+            Format::detect(include_bytes!("../tests/fixtures/poem.txt.zst")),
+            Some(Format::Zstd)
+        );
+        assert_eq!(
+            Format::detect(include_bytes!("../tests/fixtures/poem.txt.xz")),
+            Some(Format::Xz)
+        );
+        assert_eq!(Format::detect(POEM.as_bytes()), None);
+        assert_eq!(Format::detect(&[0x1f]), None);
+    }
+
+    #[test]
+    fn passes_other_input_through() {
+        assert_eq!(read(POEM.as_bytes()).unwrap(), POEM);
+        assert_eq!(
+            read(include_bytes!("../tests/fixtures/poem.txt.gz")).unwrap(),
+            POEM
+        );
+    }
+
+    #[test]
+    fn reads_several_gzip_members_as_one_stream() {
+        // A log, then a second member with the poem.
+        let text = read(include_bytes!("../tests/fixtures/log.gz")).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+
+        assert_eq!(lines.len(), 5000 + 9);
+        assert_eq!(
+            lines[0],
+            "2024-01-01 00:00:00 INFO request 0 served in 0 ms"
+        );
+        assert_eq!(
+            lines[4999],
+            "2024-01-01 01:23:19 WARN request 4999 served in 999 ms"
+        );
+        assert!(text.ends_with(POEM));
+    }
+
+    #[test]
+    fn passes_on_decompression_errors() {
+        let bytes = include_bytes!("../tests/fixtures/poem.txt.gz");
+        assert!(read(&bytes[..bytes.len() - 20]).is_err());
+    }
+
+    #[test]
+    fn zstd_and_xz_need_their_features() {
+        let zstd = read(include_bytes!("../tests/fixtures/poem.txt.zst"));
+        let xz = read(include_bytes!("../tests/fixtures/poem.txt.xz"));
+
+        if cfg!(feature = "zstd") {
+            assert_eq!(zstd.unwrap(), POEM);
+        } else {
+            assert_eq!(zstd.unwrap_err().kind(), io::ErrorKind::Unsupported);
+        }
+        if cfg!(feature = "xz") {
+            assert_eq!(xz.unwrap(), POEM);
+        } else {
+            assert_eq!(xz.unwrap_err().kind(), io::ErrorKind::Unsupported);
+        }
+    }
+}
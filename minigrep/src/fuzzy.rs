@@ -0,0 +1,165 @@
+//! Approximate matching for `--fuzzy`: finding a pattern within a number of edits, where an edit
+//! is inserting, deleting or substituting one character.
+//!
+//! The search uses Myers' bit-parallel algorithm, which keeps a column of the edit distance table
+//! as bit vectors of the differences between neighbouring cells, one bit per pattern character, so
+//! each character of the line costs a handful of word operations. That finds where a match ends
+//! and its distance; the start is then found with an ordinary table over the few characters before
+//! the end, which is as far back as a match within the allowed edits can reach.
+
+use std::collections::HashMap;
+
+/// The bit vectors are single `u64`s, one bit per pattern character.
+pub const MAX_PATTERN_LEN: usize = 64;
+
+pub struct Fuzzy {
+    pattern: Vec<char>,
+    /// For each character of the pattern, the bits of the positions where it appears.
+    masks: HashMap<char, u64>,
+    max_edits: usize,
+    case_insensitive: bool,
+}
+
+impl Fuzzy {
+    /// Case-insensitive comparisons are character by character, so they use simple lowercasing
+    /// rather than full case folding.
+    pub fn new(pattern: &str, max_edits: usize, case_insensitive: bool) -> Fuzzy {
+        let normalize = |c: char| normalize(c, case_insensitive);
+        let pattern: Vec<char> = pattern.chars().map(normalize).collect();
+        assert!(pattern.len() <= MAX_PATTERN_LEN, "fuzzy pattern too long");
+
+        let mut masks = HashMap::new();
+        for (i, &c) in pattern.iter().enumerate() {
+            *masks.entry(c).or_insert(0) |= 1 << i;
+        }
+        Fuzzy {
+            pattern,
+            masks,
+            max_edits,
+            case_insensitive,
+        }
+    }
+
+    /// The leftmost match starting at or after byte offset `start`, as its byte range and the
+    /// number of edits it needs. Where a match could end in several neighbouring places, the one
+    /// with the fewest edits is taken, and of those the longest: "frg" within one edit matches all
+    /// of "frog", not just "fr".
+    pub fn find_at(&self, text: &str, start: usize) -> Option<(usize, usize, usize)> {
+        let m = self.pattern.len();
+        if m <= self.max_edits {
+            // Deleting the whole pattern is within the limit, so the empty string matches.
+            return Some((start, start, m));
+        }
+
+        let chars: Vec<(usize, char)> = text[start..]
+            .char_indices()
+            .map(|(i, c)| (start + i, normalize(c, self.case_insensitive)))
+            .collect();
+        let high = 1u64 << (m - 1);
+        let mut positive: u64 = !0;
+        let mut negative: u64 = 0;
+        let mut score = m;
+        // The end, edits and length of the best match so far.
+        let mut best: Option<(usize, usize, usize)> = None;
+
+        for (j, &(_, c)) in chars.iter().enumerate() {
+            let eq = self.masks.get(&c).copied().unwrap_or(0);
+            let xv = eq | negative;
+            let xh = ((eq & positive).wrapping_add(positive) ^ positive) | eq;
+            let mut ph = negative | !(xh | positive);
+            let mut mh = positive & xh;
+            if ph & high != 0 {
+                score += 1;
+            } else if mh & high != 0 {
+                score -= 1;
+            }
+            // Shifting in zeros rather than a one lets a match start anywhere in the text.
+            ph <<= 1;
+            mh <<= 1;
+            positive = mh | !(xv | ph);
+            negative = ph & xv;
+
+            let end = j + 1;
+            match best {
+                None if score > self.max_edits => {}
+                Some((_, edits, _)) if score > edits => break,
+                Some((best_end, edits, length)) if score == edits => {
+                    // Only a longer version of the same match, not one that starts further on.
+                    let longer = self.match_length(&chars, end, score);
+                    if end - longer != best_end - length {
+                        break;
+                    }
+                    best = Some((end, score, longer));
+                }
+                _ => best = Some((end, score, self.match_length(&chars, end, score))),
+            }
+        }
+
+        let (end, edits, length) = best?;
+        let start_byte = chars[end - length..].first().map_or(start, |&(i, _)| i);
+        let end_byte = chars.get(end).map_or(text.len(), |&(i, _)| i);
+        Some((start_byte, end_byte, edits))
+    }
+
+    /// How many characters before `end` the best match ending there covers. Of the lengths that
+    /// need only `edits` edits, the longest is taken, so a typo at the start is kept in the match
+    /// rather than left out of it.
+    fn match_length(&self, chars: &[(usize, char)], end: usize, edits: usize) -> usize {
+        let window = end.min(self.pattern.len() + self.max_edits);
+        // Row i holds the distance between the last i pattern characters and the last n text
+        // characters before `end`, for each n.
+        let mut row: Vec<usize> = (0..=window).collect();
+        for (i, &p) in self.pattern.iter().rev().enumerate() {
+            let mut diagonal = row[0];
+            row[0] = i + 1;
+            for n in 1..=window {
+                let substitute = diagonal + (chars[end - n].1 != p) as usize;
+                diagonal = row[n];
+                row[n] = substitute.min(row[n] + 1).min(row[n - 1] + 1);
+            }
+        }
+        (0..=window).rev().find(|&n| row[n] == edits).unwrap_or(0)
+    }
+}
+
+fn normalize(c: char, case_insensitive: bool) -> char {
+    if case_insensitive {
+        c.to_lowercase().next().unwrap_or(c)
+    } else {
+        c
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_matches_within_the_edit_limit() {
+        let fuzzy = Fuzzy::new("somebody", 2, false);
+
+        assert_eq!(fuzzy.find_at("How dreary to be somebody!", 0), Some((17, 25, 0)));
+        assert_eq!(fuzzy.find_at("to be smebdy", 0), Some((6, 12, 2)));
+        assert_eq!(fuzzy.find_at("to be sxmebxdy", 0), Some((6, 14, 2)));
+        assert_eq!(fuzzy.find_at("to be nobody", 0), None);
+    }
+
+    #[test]
+    fn prefers_fewer_edits_and_keeps_leading_typos() {
+        let fuzzy = Fuzzy::new("frog", 1, false);
+
+        assert_eq!(fuzzy.find_at("a frogs", 0), Some((2, 6, 0)));
+        assert_eq!(fuzzy.find_at("a grog", 0), Some((2, 6, 1)));
+        assert_eq!(fuzzy.find_at("frg frog", 1), Some((4, 8, 0)));
+        assert_eq!(Fuzzy::new("frg", 1, false).find_at("a frog", 0), Some((2, 6, 1)));
+        assert_eq!(Fuzzy::new("aaa", 1, false).find_at("aaaa", 0), Some((0, 3, 0)));
+    }
+
+    #[test]
+    fn unicode_and_case() {
+        let fuzzy = Fuzzy::new("straße", 1, true);
+
+        assert_eq!(fuzzy.find_at("die STRAßE", 0), Some((4, 11, 0)));
+        assert_eq!(fuzzy.find_at("die Strase", 0), Some((4, 10, 1)));
+    }
+}
@@ -0,0 +1,609 @@
+//! A trigram index of a directory, so that searching it again and again only reads the files that
+//! could match.
+//!
+//! `minigrep index DIR` records, for each file below DIR, every three-byte sequence in its lines
+//! once they are case folded, and keeps that in `DIR/.minigrep-index`. A search with `--index`
+//! works out trigrams that any matching line has to contain and skips the files without them.
+//! Folding goes a character at a time, so a folded pattern is always part of a folded line it
+//! matches, whether or not the search ignores case; one index serves both.
+//!
+//! Each file's modification time and size are kept too. Running `minigrep index` again only
+//! rereads the files where those have changed, and a search reads any file that has changed since
+//! it was indexed, or has been added, whatever the index says about it.
+
+use crate::case;
+use crate::decompress;
+use crate::regex;
+use crate::walk;
+use crate::{is_binary, Config};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::UNIX_EPOCH;
+
+/// The index's name within the directory it indexes.
+pub const INDEX_FILE: &str = ".minigrep-index";
+
+const MAGIC: &[u8] = b"minigrep index\n";
+const VERSION: u8 = 1;
+
+/// When a file was last changed, as far as the index can tell.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Stamp {
+    seconds: u64,
+    nanos: u32,
+    size: u64,
+}
+
+impl Stamp {
+    /// `None` where the file system doesn't record modification times, since then there would be
+    /// no knowing whether an indexed file has changed.
+    fn of(metadata: &fs::Metadata) -> Option<Stamp> {
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(Stamp {
+            seconds: modified.as_secs(),
+            nanos: modified.subsec_nanos(),
+            size: metadata.len(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    stamp: Stamp,
+    /// Binary files are never searched, so they never need reading.
+    binary: bool,
+    /// Sorted, each as three bytes in the low bits.
+    trigrams: Vec<u32>,
+}
+
+/// What `update` did.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Update {
+    /// Files in the index now.
+    pub files: usize,
+    /// Files that were new or had changed, and so were read.
+    pub read: usize,
+    /// Files that were in the index but are gone.
+    pub removed: usize,
+}
+
+pub struct Index {
+    root: PathBuf,
+    /// By path below `root`, with `/` separators.
+    files: BTreeMap<String, Entry>,
+}
+
+fn key(relative: &Path) -> String {
+    relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn corrupt<T>(dir: &Path) -> io::Result<T> {
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "index is corrupt; rebuild it with `minigrep index {}`",
+            dir.display()
+        ),
+    ))
+}
+
+impl Index {
+    pub fn load(dir: &Path) -> io::Result<Index> {
+        let bytes = match fs::read(dir.join(INDEX_FILE)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!(
+                        "no index; build one with `minigrep index {}`",
+                        dir.display()
+                    ),
+                ))
+            }
+            Err(e) => return Err(e),
+        };
+        match decode(&bytes) {
+            Some(files) => Ok(Index {
+                root: dir.to_path_buf(),
+                files,
+            }),
+            None => corrupt(dir),
+        }
+    }
+
+    /// Write the index, replacing the old one in one step so a search never sees half of it.
+    fn save(&self) -> io::Result<()> {
+        let temporary = self
+            .root
+            .join(format!("{}.{}.tmp", INDEX_FILE, process::id()));
+        fs::write(&temporary, encode(&self.files))?;
+        fs::rename(&temporary, self.root.join(INDEX_FILE)).inspect_err(|_| {
+            let _ = fs::remove_file(&temporary);
+        })
+    }
+
+    /// Whether the file at `path` could have a line matching `query`. Files the index doesn't
+    /// know as they are now could have anything.
+    pub fn may_match(&self, path: &Path, query: &Query) -> bool {
+        let entry = match path.strip_prefix(&self.root) {
+            Ok(relative) => self.files.get(&key(relative)),
+            Err(_) => None,
+        };
+        let entry = match entry {
+            Some(entry) => entry,
+            None => return true,
+        };
+        let current = fs::metadata(path).ok().and_then(|m| Stamp::of(&m));
+        if current != Some(entry.stamp) {
+            return true;
+        }
+        !entry.binary && query.matches(&entry.trigrams)
+    }
+}
+
+/// Build the index of `dir`, or bring an existing one up to date. A corrupt index is rebuilt.
+pub fn update(dir: &Path) -> io::Result<Update> {
+    let mut old = Index::load(dir)
+        .map(|index| index.files)
+        .unwrap_or_default();
+    let mut files = BTreeMap::new();
+    let mut update = Update::default();
+    let mut trigrams = TrigramSet::new();
+
+    if !dir.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a directory"));
+    }
+    for path in walk::files(&[dir.to_string_lossy().into_owned()]) {
+        let key = key(path.strip_prefix(dir).unwrap_or(&path));
+        let stamp = match fs::metadata(&path).map(|m| Stamp::of(&m)) {
+            Ok(Some(stamp)) => stamp,
+            // Left out, so searches always read it.
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                continue;
+            }
+        };
+        let entry = match old.remove(&key) {
+            Some(entry) if entry.stamp == stamp => entry,
+            _ => match scan(&path, stamp, &mut trigrams) {
+                Ok(entry) => {
+                    update.read += 1;
+                    entry
+                }
+                Err(e) => {
+                    eprintln!("{}: {}", path.display(), e);
+                    continue;
+                }
+            },
+        };
+        files.insert(key, entry);
+    }
+
+    update.files = files.len();
+    update.removed = old.len();
+    Index {
+        root: dir.to_path_buf(),
+        files,
+    }
+    .save()?;
+    Ok(update)
+}
+
+/// Read a file the way a search would, collecting its trigrams.
+fn scan(path: &Path, stamp: Stamp, trigrams: &mut TrigramSet) -> io::Result<Entry> {
+    let mut reader = decompress::reader(BufReader::new(File::open(path)?))?;
+    if is_binary(reader.fill_buf()?) {
+        return Ok(Entry {
+            stamp,
+            binary: true,
+            trigrams: Vec::new(),
+        });
+    }
+
+    let mut buffer = Vec::new();
+    loop {
+        buffer.clear();
+        if reader.read_until(b'\n', &mut buffer)? == 0 {
+            break;
+        }
+        let mut end = buffer.len();
+        if buffer[..end].ends_with(b"\n") {
+            end -= 1;
+        }
+        if buffer[..end].ends_with(b"\r") {
+            end -= 1;
+        }
+        let line = case::fold(&String::from_utf8_lossy(&buffer[..end]));
+        for window in line.as_bytes().windows(3) {
+            trigrams.insert(trigram(window));
+        }
+    }
+    Ok(Entry {
+        stamp,
+        binary: false,
+        trigrams: trigrams.take(),
+    })
+}
+
+fn trigram(bytes: &[u8]) -> u32 {
+    u32::from(bytes[0]) << 16 | u32::from(bytes[1]) << 8 | u32::from(bytes[2])
+}
+
+/// A set of trigrams as a bit for each possible one, which is 2 MiB, but adding to it is as cheap
+/// as it gets. It's reused from file to file, clearing only the bits that were set.
+struct TrigramSet {
+    bits: Vec<u64>,
+    found: Vec<u32>,
+}
+
+impl TrigramSet {
+    fn new() -> TrigramSet {
+        TrigramSet {
+            bits: vec![0; (1 << 24) / 64],
+            found: Vec::new(),
+        }
+    }
+
+    fn insert(&mut self, trigram: u32) {
+        let (word, bit) = (trigram as usize / 64, trigram % 64);
+        if self.bits[word] & 1 << bit == 0 {
+            self.bits[word] |= 1 << bit;
+            self.found.push(trigram);
+        }
+    }
+
+    /// The trigrams added since last time, sorted, leaving the set empty.
+    fn take(&mut self) -> Vec<u32> {
+        for &trigram in &self.found {
+            self.bits[trigram as usize / 64] = 0;
+        }
+        let mut found = std::mem::take(&mut self.found);
+        found.sort_unstable();
+        found
+    }
+}
+
+/// The trigrams a file needs for a search to find anything in it: all those of at least one of
+/// the alternatives, one for each pattern.
+pub struct Query {
+    /// `None` if the search could find something in any file.
+    alternatives: Option<Vec<Vec<u32>>>,
+}
+
+impl Query {
+    pub fn new(config: &Config) -> Query {
+        // An inverted search selects lines without the pattern, a fuzzy one lines without it
+        // exactly, and `-c` prints a count even for files with nothing in them.
+        if config.invert || config.fuzzy.is_some() || config.count {
+            return Query { alternatives: None };
+        }
+        let mut alternatives = Vec::new();
+        for pattern in &config.patterns {
+            let literals = if config.regex {
+                regex::required_literals(pattern)
+            } else {
+                vec![pattern.clone()]
+            };
+            let mut needed = Vec::new();
+            for literal in literals {
+                let folded = case::fold(&literal);
+                needed.extend(folded.as_bytes().windows(3).map(trigram));
+            }
+            if needed.is_empty() {
+                return Query { alternatives: None };
+            }
+            needed.sort_unstable();
+            needed.dedup();
+            alternatives.push(needed);
+        }
+        Query {
+            alternatives: Some(alternatives),
+        }
+    }
+
+    /// Whether the query rules nothing out, so there's no point loading an index.
+    pub fn is_everything(&self) -> bool {
+        self.alternatives.is_none()
+    }
+
+    fn matches(&self, trigrams: &[u32]) -> bool {
+        match &self.alternatives {
+            None => true,
+            Some(alternatives) => alternatives
+                .iter()
+                .any(|needed| needed.iter().all(|t| trigrams.binary_search(t).is_ok())),
+        }
+    }
+}
+
+/// Narrow `files` down to those that the indexes of the directories being searched say could
+/// match. A directory without an index, or with a broken one, is reported and searched in full.
+pub fn narrow(config: &Config, mut files: Vec<PathBuf>) -> Vec<PathBuf> {
+    let query = Query::new(config);
+    // The index is of what compressed files hold, not of their bytes.
+    if query.is_everything() || !config.decompress {
+        return files;
+    }
+    for dir in &config.paths {
+        let dir = Path::new(dir);
+        if !dir.is_dir() {
+            continue;
+        }
+        match Index::load(dir) {
+            Ok(index) => files.retain(|path| index.may_match(path, &query)),
+            Err(e) => eprintln!("{}: {}", dir.display(), e),
+        }
+    }
+    files
+}
+
+// The file is the magic bytes and version, then a count of files and each of them in order, and
+// a CRC-32 of all that. Numbers are variable length, seven bits to a byte, and each file's sorted
+// trigrams are stored as the differences between them, which are mostly small.
+
+fn encode(files: &BTreeMap<String, Entry>) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    write_number(&mut out, files.len() as u64);
+    for (path, entry) in files {
+        write_number(&mut out, path.len() as u64);
+        out.extend_from_slice(path.as_bytes());
+        write_number(&mut out, entry.stamp.seconds);
+        write_number(&mut out, u64::from(entry.stamp.nanos));
+        write_number(&mut out, entry.stamp.size);
+        out.push(entry.binary as u8);
+        write_number(&mut out, entry.trigrams.len() as u64);
+        let mut previous = 0;
+        for &trigram in &entry.trigrams {
+            write_number(&mut out, u64::from(trigram - previous));
+            previous = trigram;
+        }
+    }
+    let crc = crc32fast::hash(&out);
+    out.extend_from_slice(&crc.to_le_bytes());
+    out
+}
+
+fn write_number(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl Decoder<'_> {
+    fn take(&mut self, n: usize) -> Option<&[u8]> {
+        let taken = self.bytes.get(self.at..self.at.checked_add(n)?)?;
+        self.at += n;
+        Some(taken)
+    }
+
+    fn number(&mut self) -> Option<u64> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            n |= u64::from(byte & 0x7f).checked_shl(shift)?;
+            if byte & 0x80 == 0 {
+                return Some(n);
+            }
+        }
+        None
+    }
+}
+
+/// The files in an encoded index, or `None` if it isn't one.
+fn decode(bytes: &[u8]) -> Option<BTreeMap<String, Entry>> {
+    let (body, crc) = bytes.split_at(bytes.len().checked_sub(4)?);
+    if crc32fast::hash(body).to_le_bytes() != crc {
+        return None;
+    }
+    let mut decoder = Decoder { bytes: body, at: 0 };
+    if decoder.take(MAGIC.len())? != MAGIC || decoder.take(1)? != [VERSION] {
+        return None;
+    }
+
+    let mut files = BTreeMap::new();
+    for _ in 0..decoder.number()? {
+        let length = decoder.number()? as usize;
+        let path = String::from_utf8(decoder.take(length)?.to_vec()).ok()?;
+        let stamp = Stamp {
+            seconds: decoder.number()?,
+            nanos: u32::try_from(decoder.number()?).ok()?,
+            size: decoder.number()?,
+        };
+        let binary = decoder.take(1)?[0] != 0;
+        let count = decoder.number()? as usize;
+        // Each trigram takes at least a byte, which keeps a bad count from allocating too much.
+        let mut trigrams = Vec::with_capacity(count.min(body.len()));
+        let mut trigram = 0u32;
+        for _ in 0..count {
+            trigram = trigram.checked_add(u32::try_from(decoder.number()?).ok()?)?;
+            trigrams.push(trigram);
+        }
+        files.insert(
+            path,
+            Entry {
+                stamp,
+                binary,
+                trigrams,
+            },
+        );
+    }
+    if decoder.at != body.len() {
+        return None;
+    }
+    Some(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    fn query(args: &[&str]) -> Query {
+        let mut args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+        args.push(String::from("dir"));
+        Query::new(&crate::cli::parse(args.into_iter()).unwrap())
+    }
+
+    fn trigrams_of(text: &str) -> Vec<u32> {
+        let mut set = TrigramSet::new();
+        for window in case::fold(text).as_bytes().windows(3) {
+            set.insert(trigram(window));
+        }
+        set.take()
+    }
+
+    #[test]
+    fn queries_need_the_trigrams_of_their_patterns() {
+        let file = trigrams_of("fn parse_args() -> Config");
+
+        assert!(query(&["parse"]).matches(&file));
+        assert!(query(&["-i", "PARSE_ARGS"]).matches(&file));
+        assert!(query(&["-e", "nothing", "-e", "config"]).matches(&file));
+        assert!(!query(&["nothing"]).matches(&file));
+        assert!(query(&["--regex", r"fn \w+_args"]).matches(&file));
+        assert!(!query(&["--regex", r"fn \w+_argv"]).matches(&file));
+        // These could match anywhere.
+        assert!(query(&["fn"]).is_everything());
+        assert!(query(&["-v", "nothing"]).is_everything());
+        assert!(query(&["--fuzzy", "1", "nothing"]).is_everything());
+        assert!(query(&["--regex", "a|b"]).is_everything());
+    }
+
+    #[test]
+    fn folding_covers_every_way_of_ignoring_case() {
+        // Folding turns the ligature into "fi" and the sharp s into "ss".
+        let file = trigrams_of("the ﬁle on the STRAßE");
+
+        assert!(query(&["-i", "file"]).matches(&file));
+        assert!(query(&["-i", "strasse"]).matches(&file));
+        assert!(query(&["strasse"]).matches(&file));
+        // Regexes fold their literals the same way.
+        assert!(query(&["--regex", "-i", "file"]).matches(&file));
+        assert!(query(&["--regex", "-i", "straße"]).matches(&file));
+    }
+
+    #[test]
+    fn never_rules_out_a_line_a_regex_ignoring_case_matches() {
+        let patterns = ["ßtr", "strasse", "ſtr", "kelvin", "is", "İs", "ﬁle", "file"];
+        let lines = ["Str", "STRAẞE", "STRASSE", "ſtraße", "KELVIN", "İstanbul", "ISLE", "ﬁle"];
+        for pattern in &patterns {
+            let re = regex::Regex::new_case_insensitive(pattern).unwrap();
+            let query = query(&["--regex", "-i", pattern]);
+            for line in &lines {
+                if re.is_match(line) {
+                    assert!(query.matches(&trigrams_of(line)), "{} in {}", pattern, line);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn encoding_round_trips_and_detects_corruption() {
+        let mut files = BTreeMap::new();
+        let stamp = Stamp {
+            seconds: 1_700_000_000,
+            nanos: 123_456_789,
+            size: 42,
+        };
+        files.insert(
+            String::from("src/lib.rs"),
+            Entry {
+                stamp,
+                binary: false,
+                trigrams: trigrams_of("pub fn run(config: Config)"),
+            },
+        );
+        files.insert(
+            String::from("logo.png"),
+            Entry {
+                stamp,
+                binary: true,
+                trigrams: Vec::new(),
+            },
+        );
+
+        let bytes = encode(&files);
+        assert_eq!(decode(&bytes), Some(files));
+        for i in [0, MAGIC.len() + 3, bytes.len() - 1] {
+            let mut corrupted = bytes.clone();
+            corrupted[i] ^= 0x10;
+            assert_eq!(decode(&corrupted), None);
+        }
+        assert_eq!(decode(&bytes[..bytes.len() - 1]), None);
+    }
+
+    #[test]
+    fn narrows_searches_and_updates_incrementally() {
+        let root = std::env::temp_dir().join(format!("minigrep-index-{}", process::id()));
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(root.join("src/a.rs"), "fn alpha() {}\n").unwrap();
+        fs::write(root.join("src/b.rs"), "fn beta() {}\n").unwrap();
+        fs::write(root.join("notes.txt"), "alpha and beta\n").unwrap();
+        let dir = root.to_string_lossy().to_string();
+        let search = |pattern: &str| {
+            let config = crate::cli::parse(
+                vec!["--index".to_string(), pattern.to_string(), dir.clone()].into_iter(),
+            )
+            .unwrap();
+            narrow(&config, walk::files(&config.paths))
+                .iter()
+                .map(|p| key(p.strip_prefix(&root).unwrap()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            update(&root).unwrap(),
+            Update {
+                files: 3,
+                read: 3,
+                removed: 0
+            }
+        );
+        assert_eq!(search("alpha"), vec!["notes.txt", "src/a.rs"]);
+        assert_eq!(search("gamma"), Vec::<String>::new());
+
+        // A changed file is read by searches until the index is updated, and then by that alone.
+        thread::sleep(Duration::from_millis(10));
+        fs::write(root.join("src/b.rs"), "fn beta() { gamma() }\n").unwrap();
+        fs::remove_file(root.join("notes.txt")).unwrap();
+        fs::write(root.join("new.txt"), "delta\n").unwrap();
+        assert_eq!(search("gamma"), vec!["new.txt", "src/b.rs"]);
+        assert_eq!(
+            update(&root).unwrap(),
+            Update {
+                files: 3,
+                read: 2,
+                removed: 1
+            }
+        );
+        assert_eq!(search("gamma"), vec!["src/b.rs"]);
+        assert_eq!(
+            update(&root).unwrap(),
+            Update {
+                files: 3,
+                read: 0,
+                removed: 0
+            }
+        );
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
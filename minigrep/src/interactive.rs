@@ -0,0 +1,719 @@
+//! `--interactive`: a full-screen search that runs again as the query is typed.
+//!
+//! The screen has the query at the top, the matching lines below it, and a preview of the lines
+//! around the selected one. Enter prints the selected match as `path:line` and exits, so the
+//! result can be handed to another program; Ctrl-O opens it in `$VISUAL` or `$EDITOR` and comes
+//! back afterwards.
+//!
+//! The terminal is put into raw mode with `stty`, so this only works on Unix-like systems. Reads
+//! from it time out after a tenth of a second, which lets keys typed in quick succession be taken
+//! together and searched for once rather than once each. The search itself runs on another thread
+//! so that keys keep being read while it goes on, and it is abandoned as soon as the query changes.
+//! The size of the terminal is read when starting, after the editor exits and on Ctrl-L.
+
+use crate::decompress;
+use crate::fuzzy;
+use crate::matcher::Matcher;
+use crate::output::{COLOR_MATCH, COLOR_NUMBER, COLOR_PATH, COLOR_RESET};
+use crate::{is_binary, Config};
+use std::env;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+/// Searching stops after this many matching lines, which is more than anyone will scroll through.
+const MAX_HITS: usize = 1000;
+
+const REVERSE: &str = "\x1b[7m";
+const DIM: &str = "\x1b[2m";
+const HELP: &str =
+    "↑/↓ select  Enter print path:line  Ctrl-O open in editor  Ctrl-L redraw  Esc quit";
+
+/// The terminal, in raw mode on the alternate screen until dropped.
+struct Terminal {
+    input: File,
+    output: File,
+    /// The settings to go back to, as `stty -g` gives them.
+    saved: String,
+    /// Rows and columns, as of the last `measure`.
+    size: (usize, usize),
+}
+
+fn stty(tty: &File, args: &[&str]) -> io::Result<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(tty.try_clone()?)
+        .stderr(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!("stty {} failed", args.join(" "))));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+impl Terminal {
+    fn open() -> io::Result<Terminal> {
+        let input = File::open("/dev/tty").map_err(|e| {
+            io::Error::new(e.kind(), format!("--interactive needs a terminal: {}", e))
+        })?;
+        let output = OpenOptions::new().write(true).open("/dev/tty")?;
+        let saved = stty(&input, &["-g"])?;
+        let mut terminal = Terminal {
+            input,
+            output,
+            saved,
+            size: (24, 80),
+        };
+        terminal.enter()?;
+        terminal.measure();
+        Ok(terminal)
+    }
+
+    /// Raw mode, where reads give up after a tenth of a second, and the alternate screen.
+    fn enter(&mut self) -> io::Result<()> {
+        stty(&self.input, &["raw", "-echo", "min", "0", "time", "1"])?;
+        write!(self.output, "\x1b[?1049h")?;
+        self.output.flush()
+    }
+
+    fn leave(&mut self) -> io::Result<()> {
+        write!(self.output, "\x1b[?1049l\x1b[?25h")?;
+        self.output.flush()?;
+        stty(&self.input, &[&self.saved]).map(|_| ())
+    }
+
+    /// Find out the rows and columns again, which takes running `stty`.
+    fn measure(&mut self) {
+        let size = stty(&self.input, &["size"]).unwrap_or_default();
+        let mut numbers = size.split_whitespace().map(|n| n.parse().unwrap_or(0));
+        self.size = match (numbers.next(), numbers.next()) {
+            (Some(rows), Some(columns)) if rows > 0 && columns > 0 => (rows, columns),
+            _ => (24, 80),
+        };
+    }
+
+    /// Whatever has been typed, waiting up to a tenth of a second for something.
+    fn read(&mut self) -> io::Result<Vec<u8>> {
+        let mut buffer = [0; 256];
+        let n = self.input.read(&mut buffer)?;
+        Ok(buffer[..n].to_vec())
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let _ = self.leave();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Key {
+    Char(char),
+    Backspace,
+    ClearQuery,
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Enter,
+    Open,
+    Redraw,
+    Quit,
+}
+
+/// The keys in what the terminal sent. An escape on its own, rather than starting a sequence such
+/// as an arrow key's, is `Quit`.
+fn keys(bytes: &[u8]) -> Vec<Key> {
+    let mut keys = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let key = match bytes[i] {
+            0x1b if i + 1 == bytes.len() => Some(Key::Quit),
+            // Alt with a key.
+            0x1b if !matches!(bytes[i + 1], b'[' | b'O') => {
+                i += 1;
+                None
+            }
+            0x1b => {
+                // CSI or SS3: parameters, then a final byte from @ to ~.
+                let end = bytes[i + 1..]
+                    .iter()
+                    .skip(1)
+                    .position(|b| (0x40..=0x7e).contains(b))
+                    .map_or(bytes.len() - 1, |p| i + 2 + p);
+                let key = match &bytes[i + 1..=end] {
+                    b"[A" | b"OA" => Some(Key::Up),
+                    b"[B" | b"OB" => Some(Key::Down),
+                    b"[5~" => Some(Key::PageUp),
+                    b"[6~" => Some(Key::PageDown),
+                    _ => None,
+                };
+                i = end;
+                key
+            }
+            b'\r' | b'\n' => Some(Key::Enter),
+            0x7f | 0x08 => Some(Key::Backspace),
+            0x03 | 0x04 => Some(Key::Quit),
+            0x0c => Some(Key::Redraw),
+            0x0e => Some(Key::Down),
+            0x0f => Some(Key::Open),
+            0x10 => Some(Key::Up),
+            0x15 => Some(Key::ClearQuery),
+            b if b < 0x20 => None,
+            _ => {
+                // A run of text, which may be several bytes to a character.
+                let end = bytes[i..]
+                    .iter()
+                    .position(|&b| b < 0x20 || b == 0x7f)
+                    .map_or(bytes.len(), |p| i + p);
+                keys.extend(
+                    String::from_utf8_lossy(&bytes[i..end])
+                        .chars()
+                        .map(Key::Char),
+                );
+                i = end;
+                continue;
+            }
+        };
+        keys.extend(key);
+        i += 1;
+    }
+    keys
+}
+
+/// A matching line.
+struct Hit {
+    file: usize,
+    number: usize,
+    text: String,
+    spans: Vec<(usize, usize)>,
+}
+
+/// The lines of `path`, decompressed if need be, or nothing for a binary file.
+fn open(decompress: bool, path: &PathBuf) -> io::Result<Option<Box<dyn BufRead>>> {
+    let reader: Box<dyn BufRead> = Box::new(BufReader::new(File::open(path)?));
+    let mut reader = if decompress {
+        decompress::reader(reader)?
+    } else {
+        reader
+    };
+    Ok(if is_binary(reader.fill_buf()?) {
+        None
+    } else {
+        Some(reader)
+    })
+}
+
+fn lines(reader: Box<dyn BufRead>) -> impl Iterator<Item = String> {
+    reader.split(b'\n').map_while(Result::ok).map(|mut line| {
+        if line.ends_with(b"\r") {
+            line.pop();
+        }
+        String::from_utf8_lossy(&line).into_owned()
+    })
+}
+
+/// What `search` needs of the `Config`, which stays behind on the thread reading keys.
+#[derive(Clone, Copy)]
+struct SearchOptions {
+    decompress: bool,
+    invert: bool,
+}
+
+/// The matching lines of `files`, or `None` if `cancelled` was set before the search finished.
+fn search(
+    options: SearchOptions,
+    matcher: &Matcher,
+    files: &[PathBuf],
+    cancelled: &AtomicBool,
+) -> Option<Vec<Hit>> {
+    let mut hits = Vec::new();
+    for (file, path) in files.iter().enumerate() {
+        let reader = match open(options.decompress, path) {
+            Ok(Some(reader)) => reader,
+            _ => continue,
+        };
+        for (i, text) in lines(reader).enumerate() {
+            if cancelled.load(Ordering::Relaxed) {
+                return None;
+            }
+            let spans = matcher.find_iter(&text);
+            if spans.is_empty() == options.invert {
+                let spans = if options.invert { Vec::new() } else { spans };
+                hits.push(Hit {
+                    file,
+                    number: i + 1,
+                    text,
+                    spans,
+                });
+                if hits.len() == MAX_HITS {
+                    return Some(hits);
+                }
+            }
+        }
+    }
+    Some(hits)
+}
+
+/// A search running on another thread.
+struct Running {
+    cancelled: Arc<AtomicBool>,
+    results: mpsc::Receiver<Vec<Hit>>,
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+/// `text` made to fit in `width` columns, as escape sequences and all, with `spans` highlighted.
+/// A match that would be off the right edge is brought into view by cutting the start off
+/// instead. Tabs become spaces and other control characters are left out.
+fn fit(text: &str, spans: &[(usize, usize)], width: usize, after: &str) -> String {
+    if width == 0 {
+        return String::new();
+    }
+    let chars: Vec<(usize, char)> = text
+        .char_indices()
+        .map(|(i, c)| (i, if c == '\t' { ' ' } else { c }))
+        .filter(|&(_, c)| !c.is_control())
+        .collect();
+    let first = spans.first().map_or(0, |&(s, _)| {
+        chars.iter().take_while(|&&(i, _)| i < s).count()
+    });
+    let skip = if first + 1 > width {
+        (first + 1 - width / 2).min(chars.len())
+    } else {
+        0
+    };
+
+    let mut fitted = String::new();
+    let mut shown = 0;
+    let mut highlighted = false;
+    if skip > 0 {
+        fitted.push('…');
+        shown += 1;
+    }
+    for &(i, c) in &chars[skip..] {
+        if shown == width {
+            break;
+        }
+        let inside = spans.iter().any(|&(s, e)| s <= i && i < e);
+        if inside != highlighted {
+            fitted.push_str(if inside { COLOR_MATCH } else { COLOR_RESET });
+            if !inside {
+                fitted.push_str(after);
+            }
+            highlighted = inside;
+        }
+        fitted.push(c);
+        shown += 1;
+    }
+    if highlighted {
+        fitted.push_str(COLOR_RESET);
+        fitted.push_str(after);
+    }
+    fitted
+}
+
+struct Screen {
+    query: String,
+    hits: Vec<Hit>,
+    /// Why there are no hits, if the query didn't compile.
+    error: Option<String>,
+    /// The search for the current query, until it finishes. Replacing it cancels it.
+    running: Option<Running>,
+    selected: usize,
+    /// The first hit shown.
+    top: usize,
+}
+
+impl Screen {
+    /// Start searching for the query, giving up on any search still running.
+    fn rerun(&mut self, config: &mut Config, files: &Arc<Vec<PathBuf>>) {
+        self.selected = 0;
+        self.top = 0;
+        self.hits.clear();
+        self.error = None;
+        self.running = None;
+        if self.query.is_empty() {
+            return;
+        }
+        // The patterns given to start with have been checked already, but not what's typed.
+        if config.fuzzy.is_some() && self.query.chars().count() > fuzzy::MAX_PATTERN_LEN {
+            self.error = Some(format!(
+                "--fuzzy patterns can be at most {} characters long",
+                fuzzy::MAX_PATTERN_LEN
+            ));
+            return;
+        }
+        config.patterns = vec![self.query.clone()];
+        let matcher = match Matcher::new(config) {
+            Ok(matcher) => matcher,
+            Err(e) => {
+                self.error = Some(e.to_string());
+                return;
+            }
+        };
+
+        let options = SearchOptions {
+            decompress: config.decompress,
+            invert: config.invert,
+        };
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let (sender, results) = mpsc::channel();
+        let files = Arc::clone(files);
+        let flag = Arc::clone(&cancelled);
+        thread::spawn(move || {
+            if let Some(hits) = search(options, &matcher, &files, &flag) {
+                sender.send(hits).ok();
+            }
+        });
+        self.running = Some(Running { cancelled, results });
+    }
+
+    /// Take the hits of the running search if it has finished. Returns whether it has.
+    fn collect(&mut self) -> bool {
+        let hits = match &self.running {
+            Some(running) => match running.results.try_recv() {
+                Ok(hits) => hits,
+                Err(mpsc::TryRecvError::Empty) => return false,
+                Err(mpsc::TryRecvError::Disconnected) => Vec::new(),
+            },
+            None => return false,
+        };
+        self.hits = hits;
+        self.running = None;
+        true
+    }
+
+    fn select(&mut self, by: isize) {
+        let last = self.hits.len().saturating_sub(1) as isize;
+        self.selected = (self.selected as isize + by).clamp(0, last) as usize;
+    }
+
+    fn draw(
+        &mut self,
+        terminal: &mut Terminal,
+        config: &Config,
+        files: &[PathBuf],
+    ) -> io::Result<()> {
+        let (rows, columns) = terminal.size;
+        // The query, the list, a rule with the selected path, the preview and the help line.
+        let list_rows = if rows >= 8 {
+            (rows - 3) / 2
+        } else {
+            rows.saturating_sub(2).max(1)
+        };
+        let preview_rows = rows.saturating_sub(list_rows + 3);
+        if self.selected < self.top {
+            self.top = self.selected;
+        } else if self.selected >= self.top + list_rows {
+            self.top = self.selected + 1 - list_rows;
+        }
+
+        let mut screen = String::from("\x1b[?25l");
+        let mut row = 1;
+        let mut line = |screen: &mut String, text: &str| {
+            screen.push_str(&format!("\x1b[{};1H\x1b[2K{}", row, text));
+            row += 1;
+        };
+
+        let status = match &self.error {
+            Some(error) => error.clone(),
+            None if self.running.is_some() => String::from("searching…"),
+            None if self.hits.len() == MAX_HITS => format!("{}+ matches", MAX_HITS),
+            None if self.hits.len() == 1 => String::from("1 match"),
+            None => format!("{} matches", self.hits.len()),
+        };
+        let prompt = format!("> {}", self.query);
+        let gap = columns.saturating_sub(prompt.chars().count() + status.chars().count());
+        line(
+            &mut screen,
+            &format!(
+                "{}{}{}{}{}",
+                prompt,
+                " ".repeat(gap),
+                DIM,
+                status,
+                COLOR_RESET
+            ),
+        );
+
+        for index in self.top..self.top + list_rows {
+            let hit = match self.hits.get(index) {
+                Some(hit) => hit,
+                None => {
+                    line(&mut screen, "");
+                    continue;
+                }
+            };
+            let location = format!("{}:{}:", files[hit.file].display(), hit.number);
+            let (on, off) = if index == self.selected {
+                (REVERSE, COLOR_RESET)
+            } else {
+                ("", "")
+            };
+            let width = columns.saturating_sub(location.chars().count());
+            let text = fit(&hit.text, &hit.spans, width, on);
+            line(
+                &mut screen,
+                &format!(
+                    "{}{}{}{}{}{}{}",
+                    on, COLOR_PATH, location, COLOR_RESET, on, text, off
+                ),
+            );
+        }
+
+        if preview_rows > 0 {
+            let hit = self.hits.get(self.selected);
+            let title = hit.map_or(String::new(), |hit| {
+                format!(" {}:{} ", files[hit.file].display(), hit.number)
+            });
+            let rule = "─".repeat(columns.saturating_sub(title.chars().count() + 2));
+            line(
+                &mut screen,
+                &format!("{}──{}{}{}", DIM, title, rule, COLOR_RESET),
+            );
+            let mut preview = Vec::new();
+            if let Some(hit) = hit {
+                let first = hit.number.saturating_sub(preview_rows / 2).max(1);
+                if let Ok(Some(reader)) = open(config.decompress, &files[hit.file]) {
+                    preview = lines(reader)
+                        .skip(first - 1)
+                        .take(preview_rows)
+                        .enumerate()
+                        .collect();
+                }
+                for (i, text) in preview.iter_mut() {
+                    let number = first + *i;
+                    let spans = if number == hit.number {
+                        hit.spans.clone()
+                    } else {
+                        Vec::new()
+                    };
+                    let gutter = format!("{:>6} ", number);
+                    let width = columns.saturating_sub(gutter.len());
+                    *text = format!(
+                        "{}{}{}{}",
+                        COLOR_NUMBER,
+                        gutter,
+                        COLOR_RESET,
+                        fit(text, &spans, width, "")
+                    );
+                }
+            }
+            for i in 0..preview_rows {
+                line(&mut screen, preview.get(i).map_or("", |(_, text)| text));
+            }
+        }
+
+        line(
+            &mut screen,
+            &format!("{}{}{}", DIM, fit(HELP, &[], columns, ""), COLOR_RESET),
+        );
+        // Leave the cursor at the end of the query.
+        screen.push_str(&format!(
+            "\x1b[1;{}H\x1b[?25h",
+            prompt.chars().count().min(columns) + 1
+        ));
+        terminal.output.write_all(screen.as_bytes())?;
+        terminal.output.flush()
+    }
+}
+
+/// Open `path` at `line` in the user's editor, handing it the terminal until it exits.
+fn edit(terminal: &mut Terminal, path: &PathBuf, line: usize) -> io::Result<()> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| String::from("vi"));
+    let mut words = editor.split_whitespace();
+    let program = words.next().unwrap_or("vi");
+    terminal.leave()?;
+    let status = Command::new(program)
+        .args(words)
+        .arg(format!("+{}", line))
+        .arg(path)
+        .stdin(terminal.input.try_clone()?)
+        .stdout(terminal.output.try_clone()?)
+        .status();
+    terminal.enter()?;
+    // The terminal may have been resized while the editor had it.
+    terminal.measure();
+    status.map(|_| ())
+}
+
+/// Run the interactive search over `files`, starting from the pattern in `config` if there is
+/// one. Returns the `path:line` chosen with Enter, if any.
+pub fn run(mut config: Config, files: Vec<PathBuf>) -> Result<Option<String>, Box<dyn Error>> {
+    let mut terminal = Terminal::open()?;
+    let files = Arc::new(files);
+    let mut screen = Screen {
+        query: config.patterns.first().cloned().unwrap_or_default(),
+        hits: Vec::new(),
+        error: None,
+        running: None,
+        selected: 0,
+        top: 0,
+    };
+    screen.rerun(&mut config, &files);
+    screen.draw(&mut terminal, &config, &files)?;
+
+    // Whether the query has changed since it was last searched for.
+    let mut changed = false;
+    loop {
+        let input = terminal.read()?;
+        if input.is_empty() {
+            if changed {
+                changed = false;
+                screen.rerun(&mut config, &files);
+                screen.draw(&mut terminal, &config, &files)?;
+            } else if screen.collect() {
+                screen.draw(&mut terminal, &config, &files)?;
+            }
+            continue;
+        }
+        screen.collect();
+
+        for key in keys(&input) {
+            match key {
+                Key::Char(c) => {
+                    screen.query.push(c);
+                    changed = true;
+                }
+                Key::Backspace => changed |= screen.query.pop().is_some(),
+                Key::ClearQuery => {
+                    changed |= !screen.query.is_empty();
+                    screen.query.clear();
+                }
+                Key::Up => screen.select(-1),
+                Key::Down => screen.select(1),
+                Key::PageUp => screen.select(-10),
+                Key::PageDown => screen.select(10),
+                Key::Enter => {
+                    return Ok(screen
+                        .hits
+                        .get(screen.selected)
+                        .map(|hit| format!("{}:{}", files[hit.file].display(), hit.number)))
+                }
+                Key::Open => {
+                    if let Some(hit) = screen.hits.get(screen.selected) {
+                        edit(&mut terminal, &files[hit.file], hit.number)?;
+                    }
+                }
+                Key::Redraw => terminal.measure(),
+                Key::Quit => return Ok(None),
+            }
+        }
+        if changed {
+            // Whatever it finds is for a query that's gone.
+            screen.running = None;
+        }
+        screen.draw(&mut terminal, &config, &files)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_keys_and_escape_sequences() {
+        assert_eq!(
+            keys(b"ab\x7f\x1b[A\x1b[B\x1b[5~\r"),
+            vec![
+                Key::Char('a'),
+                Key::Char('b'),
+                Key::Backspace,
+                Key::Up,
+                Key::Down,
+                Key::PageUp,
+                Key::Enter
+            ]
+        );
+        assert_eq!(
+            keys("fé\x0f\x0c".as_bytes()),
+            vec![Key::Char('f'), Key::Char('é'), Key::Open, Key::Redraw]
+        );
+        assert_eq!(keys(b"\x1b"), vec![Key::Quit]);
+        // Sequences that mean nothing here are skipped whole.
+        assert_eq!(keys(b"\x1b[1;5Cx\x1bOA"), vec![Key::Char('x'), Key::Up]);
+    }
+
+    #[test]
+    fn fits_lines_to_the_width_keeping_matches_in_view() {
+        let m = COLOR_MATCH;
+        let r = COLOR_RESET;
+
+        assert_eq!(
+            fit("a\tfrog", &[(2, 6)], 10, ""),
+            format!("a {}frog{}", m, r)
+        );
+        assert_eq!(fit("abcdef", &[], 4, ""), "abcd");
+        let line = "0123456789 frog";
+        assert_eq!(fit(line, &[(11, 15)], 8, ""), format!("…89 {}frog{}", m, r));
+        // No room at all, for an empty line or a match at the very end.
+        assert_eq!(fit("", &[], 0, ""), "");
+        assert_eq!(fit("frog", &[(4, 4)], 1, ""), "…");
+        assert_eq!(fit("frog", &[(4, 4)], 0, ""), "");
+    }
+
+    #[test]
+    fn reports_a_typed_fuzzy_query_that_is_too_long() {
+        let args = ["--interactive", "--fuzzy", "1"];
+        let mut config = crate::cli::parse(args.iter().map(|s| s.to_string())).unwrap();
+        let mut screen = Screen {
+            query: "a".repeat(fuzzy::MAX_PATTERN_LEN + 1),
+            hits: Vec::new(),
+            error: None,
+            running: None,
+            selected: 0,
+            top: 0,
+        };
+        let files = Arc::new(Vec::new());
+        screen.rerun(&mut config, &files);
+        assert_eq!(
+            screen.error.as_deref(),
+            Some("--fuzzy patterns can be at most 64 characters long")
+        );
+
+        screen.query.pop();
+        screen.rerun(&mut config, &files);
+        assert_eq!(screen.error, None);
+    }
+
+    #[test]
+    fn searches_in_the_background_until_cancelled() {
+        let args = ["--interactive", "frog"];
+        let mut config = crate::cli::parse(args.iter().map(|s| s.to_string())).unwrap();
+        let files = Arc::new(vec![PathBuf::from("poem.txt")]);
+        let mut screen = Screen {
+            query: String::from("o"),
+            hits: Vec::new(),
+            error: None,
+            running: None,
+            selected: 0,
+            top: 0,
+        };
+
+        screen.rerun(&mut config, &files);
+        while !screen.collect() {
+            thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert!(screen.running.is_none());
+        assert_eq!(screen.hits.len(), 8);
+
+        let options = SearchOptions {
+            decompress: false,
+            invert: false,
+        };
+        let matcher = Matcher::new(&config).unwrap();
+        assert!(search(options, &matcher, &files, &AtomicBool::new(true)).is_none());
+    }
+}
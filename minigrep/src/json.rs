@@ -0,0 +1,185 @@
+//! Search results as JSON, one object per line, for `--json`.
+//!
+//! Each selected line becomes `{"type":"match",...}` with its path, line number, the byte offset of
+//! the line in the file and the byte offsets of each match within the line, along with the edits
+//! each match needed when matching is fuzzy. Context lines have the
+//! same shape with `"type":"context"` and no submatches. A final `{"type":"summary",...}` object
+//! has the totals and how long the search took.
+//!
+//! Text is given as a `"text"` string when it's valid UTF-8, and otherwise as its original bytes
+//! in base64, as `"bytes"`.
+
+use crate::Stats;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// `text` as a JSON string, quotes included.
+pub fn string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c < ' ' => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// `"text":...` for valid UTF-8, or else `"bytes":...` so the bytes come through unchanged.
+fn text_or_bytes(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => format!("\"text\":{}", string(text)),
+        Err(_) => format!("\"bytes\":\"{}\"", base64(bytes)),
+    }
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Where byte `at` of `String::from_utf8_lossy(line)` came from in `line`.
+fn original_offset(line: &[u8], at: usize) -> usize {
+    let (mut decoded, mut original) = (0, 0);
+    for chunk in line.utf8_chunks() {
+        let valid = chunk.valid().len();
+        if at <= decoded + valid {
+            return original + at - decoded;
+        }
+        decoded += valid;
+        original += valid;
+        if !chunk.invalid().is_empty() {
+            if at < decoded + char::REPLACEMENT_CHARACTER.len_utf8() {
+                return original;
+            }
+            decoded += char::REPLACEMENT_CHARACTER.len_utf8();
+            original += chunk.invalid().len();
+        }
+    }
+    original
+}
+
+/// `spans` are byte ranges of `String::from_utf8_lossy(line)`, which is what gets searched; the
+/// record has them as byte offsets in `line` itself.
+pub fn line_record(
+    matched: bool,
+    path: &str,
+    number: usize,
+    offset: usize,
+    line: &[u8],
+    spans: &[(usize, usize)],
+    edits: &[usize],
+) -> String {
+    let mut record = format!(
+        "{{\"type\":\"{}\",\"path\":{},\"line_number\":{},\"byte_offset\":{},{}",
+        if matched { "match" } else { "context" },
+        string(path),
+        number,
+        offset,
+        text_or_bytes(line)
+    );
+    if matched {
+        record.push_str(",\"submatches\":[");
+        for (i, &(start, end)) in spans.iter().enumerate() {
+            if i > 0 {
+                record.push(',');
+            }
+            let (start, end) = (original_offset(line, start), original_offset(line, end));
+            let _ = write!(
+                record,
+                "{{\"start\":{},\"end\":{},{}",
+                start,
+                end,
+                text_or_bytes(line.get(start..end).unwrap_or(b""))
+            );
+            if let Some(edits) = edits.get(i) {
+                let _ = write!(record, ",\"edits\":{}", edits);
+            }
+            record.push('}');
+        }
+        record.push(']');
+    }
+    record.push('}');
+    record
+}
+
+pub fn summary_record(stats: &Stats, elapsed: Duration) -> String {
+    format!(
+        "{{\"type\":\"summary\",\"files_searched\":{},\"files_matched\":{},\"matched_lines\":{},\
+         \"elapsed_secs\":{:.6}}}",
+        stats.files_searched,
+        stats.files_matched,
+        stats.lines,
+        elapsed.as_secs_f64()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_strings() {
+        assert_eq!(string("a \"b\" \\ c\n\u{1}é"), r#""a \"b\" \\ c\n\u0001é""#);
+    }
+
+    #[test]
+    fn records() {
+        assert_eq!(
+            line_record(true, "poem.txt", 6, 120, b"How dreary", &[(4, 10)], &[]),
+            r#"{"type":"match","path":"poem.txt","line_number":6,"byte_offset":120,"text":"How dreary","submatches":[{"start":4,"end":10,"text":"dreary"}]}"#
+        );
+        assert_eq!(
+            line_record(false, "poem.txt", 7, 131, b"frog", &[], &[]),
+            r#"{"type":"context","path":"poem.txt","line_number":7,"byte_offset":131,"text":"frog"}"#
+        );
+        assert_eq!(
+            line_record(true, "poem.txt", 7, 131, b"a frg", &[(2, 5)], &[1]),
+            r#"{"type":"match","path":"poem.txt","line_number":7,"byte_offset":131,"text":"a frg","submatches":[{"start":2,"end":5,"text":"frg","edits":1}]}"#
+        );
+
+        // "frog" is at 6..10 in the file, though at 8..12 once the bad byte is decoded as U+FFFD.
+        assert_eq!(
+            line_record(true, "bad.txt", 1, 0, b"ab\xffcd frog", &[(8, 12)], &[]),
+            r#"{"type":"match","path":"bad.txt","line_number":1,"byte_offset":0,"bytes":"YWL/Y2QgZnJvZw==","submatches":[{"start":6,"end":10,"text":"frog"}]}"#
+        );
+        assert_eq!(
+            line_record(true, "bad.txt", 1, 0, b"a\xff\xfeb", &[(0, 8)], &[]),
+            r#"{"type":"match","path":"bad.txt","line_number":1,"byte_offset":0,"bytes":"Yf/+Yg==","submatches":[{"start":0,"end":4,"bytes":"Yf/+Yg=="}]}"#
+        );
+
+        let stats = Stats {
+            files_searched: 3,
+            files_matched: 1,
+            lines: 2,
+            errors: 0,
+        };
+        assert_eq!(
+            summary_record(&stats, Duration::from_millis(1500)),
+            r#"{"type":"summary","files_searched":3,"files_matched":1,"matched_lines":2,"elapsed_secs":1.500000}"#
+        );
+    }
+}
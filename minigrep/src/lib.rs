@@ -1,8 +1,30 @@
-use std::env;
-use std::fs;
-use std::process;
+use std::fs::File;
 use std::error::Error;
-use std::str::FromStr;
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Instant;
+
+mod aho_corasick;
+pub mod case;
+pub mod cli;
+pub mod decompress;
+pub mod fuzzy;
+pub mod index;
+mod interactive;
+pub mod json;
+pub mod matcher;
+pub mod output;
+mod parallel;
+pub mod regex;
+pub mod replace;
+pub mod walk;
+
+pub use crate::cli::ConfigError;
+use crate::matcher::Matcher;
+use crate::output::{ColorChoice, PrintOptions, Printer};
+use crate::regex::Regex;
+use crate::replace::Template;
 
 // CASE_INSENSITIVE=1 cargo run to poem.txt
 
@@ -10,74 +32,437 @@ use std::str::FromStr;
 // Error trait, but we don’t have to specify what particular type the return value will be. This gives
 // us flexibility to return error values that may be of different types in different error cases.
 // The dyn keyword is short for “dynamic.”
-pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
-    let contents = fs::read_to_string(config.filename)?;
+pub fn run(config: Config) -> Result<Outcome, Box<dyn Error>> {
+    let started = Instant::now();
+    if config.command == Command::Index {
+        index_dirs(&config)?;
+        return Ok(Outcome::default());
+    }
 
-    let results = if config.case_sensitive {
-        search(&config.query, &contents)
-    } else {
-        search_case_insensitive(&config.query, &contents)
+    // Compile the pattern before touching the file so a bad pattern is reported straight away, and
+    // only once however many lines there are to search.
+    let matcher = Matcher::new(&config)?;
+
+    let mut files = walk::files(&config.paths);
+    if config.use_index {
+        files = index::narrow(&config, files);
+    }
+    if config.interactive {
+        let selected = interactive::run(config, files)?;
+        if let Some(selected) = &selected {
+            println!("{}", selected);
+        }
+        return Ok(Outcome {
+            matched: selected.is_some(),
+            ..Outcome::default()
+        });
+    }
+    if config.in_place || config.dry_run {
+        return rewrite_files(&config, &matcher, &files);
+    }
+
+    // With more than one file to look at, each match needs to say where it came from.
+    let with_path = config.paths.len() > 1 || Path::new(&config.paths[0]).is_dir();
+
+    let color = match config.color {
+        _ if config.json => false,
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => io::stdout().is_terminal(),
     };
+    let stdout = io::stdout();
+    let mut printer = Printer::new(
+        stdout.lock(),
+        PrintOptions {
+            with_path,
+            line_number: config.line_number,
+            byte_offset: config.byte_offset,
+            before: config.before_context,
+            after: config.after_context,
+            color,
+            json: config.json,
+        },
+    );
 
-    for line in results {
-        println!("{}", line);
+    let threads = match config.threads {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    };
+    let stats = match search_files(&config, &matcher, &files, threads, &mut printer) {
+        // Nobody is reading any more, as with `minigrep x big.txt | head`. Whatever was printed
+        // matched something.
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(Outcome::default()),
+        result => result?,
+    };
+    if config.json && !config.quiet {
+        printer.raw(&json::summary_record(&stats, started.elapsed()))?;
     }
 
-    Ok(())
+    printer.flush()?;
+    Ok(Outcome {
+        matched: stats.lines > 0,
+        errors: stats.errors > 0,
+        quiet: config.quiet,
+    })
 }
 
-pub struct Config {
-    pub query: String,
-    pub filename: String,
-    pub case_sensitive: bool,
+/// How a run went, as far as the exit status is concerned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Outcome {
+    /// Whether anything was selected.
+    pub matched: bool,
+    /// Whether something couldn't be read. Each problem has been reported on stderr already.
+    pub errors: bool,
+    pub quiet: bool,
 }
 
-impl Config {
-    //pub fn new(args: &[String]) -> Result<Config, &'static str> {
-    //  Because we’re taking ownership of args and we’ll be mutating args by iterating over it, we
-    // can add the mut keyword into the specification of the args parameter to make it mutable.
-    pub fn new(mut args: std::env::Args) -> Result<Config, &'static str> {
-        //if args.len() < 3 {
-        //    return Err("not enough arguments");
-        //}
+/// Success, for runs that don't search for anything, such as `minigrep index`.
+impl Default for Outcome {
+    fn default() -> Outcome {
+        Outcome {
+            matched: true,
+            errors: false,
+            quiet: false,
+        }
+    }
+}
 
-        args.next();
+impl Outcome {
+    /// grep's exit status: 0 if a line was selected, 1 if none was, and 2 if there was an error,
+    /// except that with `-q` finding a line is enough for 0.
+    pub fn exit_code(self) -> i32 {
+        if self.errors && !(self.quiet && self.matched) {
+            2
+        } else if self.matched {
+            0
+        } else {
+            1
+        }
+    }
+}
 
-        let query = match args.next() {
-            Some(arg) => arg,
-            None => return Err("Didn't get a query string")
-        };
+/// `minigrep index`: build or update the index of each directory.
+fn index_dirs(config: &Config) -> Result<(), Box<dyn Error>> {
+    for dir in &config.paths {
+        let update = index::update(Path::new(dir)).map_err(|e| format!("{}: {}", dir, e))?;
+        println!(
+            "{}: {} files indexed, {} read, {} removed",
+            dir, update.files, update.read, update.removed
+        );
+    }
+    Ok(())
+}
+
+/// `--in-place` and `--dry-run`: replace matches in whole files rather than print lines.
+fn rewrite_files(config: &Config, matcher: &Matcher, files: &[PathBuf]) -> Result<Outcome, Box<dyn Error>> {
+    let template = Template::parse(config.replace.as_deref().unwrap_or_default());
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut outcome = Outcome {
+        matched: false,
+        ..Outcome::default()
+    };
 
-        let filename = match args.next() {
-            Some(arg) => arg,
-            None => return Err("Didn't get a file name")
+    for path in files {
+        let is_stdin = path == Path::new(STDIN_PATH);
+        let result = if config.in_place {
+            if is_stdin {
+                Err(io::Error::new(io::ErrorKind::InvalidInput, "can't rewrite standard input in place"))
+            } else {
+                replace::in_place(matcher, &template, path)
+            }
+        } else if is_stdin {
+            replace::diff(matcher, &template, "(standard input)", io::stdin().lock(), &mut out)
+        } else {
+            let name = path.display().to_string();
+            File::open(path)
+                .and_then(|file| replace::diff(matcher, &template, &name, BufReader::new(file), &mut out))
         };
+        match result {
+            Ok(replaced) => outcome.matched |= replaced > 0,
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(Outcome::default()),
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                outcome.errors = true;
+            }
+        }
+    }
+
+    out.flush()?;
+    Ok(outcome)
+}
+
+/// What a search found.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Stats {
+    /// Files that were read, whether or not anything was selected in them.
+    pub files_searched: usize,
+    pub files_matched: usize,
+    /// Selected lines, across all files.
+    pub lines: usize,
+    /// Files that couldn't be read.
+    pub errors: usize,
+}
+
+impl Stats {
+    fn add_file(&mut self, lines: usize) {
+        self.files_searched += 1;
+        if lines > 0 {
+            self.files_matched += 1;
+        }
+        self.lines += lines;
+    }
+}
+
+/// The path that stands for standard input, as with most Unix tools.
+pub const STDIN_PATH: &str = "-";
+
+/// Search each of `files` in turn, printing the results in that order, and return what was found.
+/// With more than one thread the files are searched concurrently, but the output is the same as if
+/// they hadn't been.
+///
+/// A file that can't be read is reported on stderr and skipped. A broken pipe stops the search and
+/// is returned, since nothing more can be printed. With `-q` the search stops at the first file
+/// with anything selected.
+pub fn search_files<W: Write>(
+    config: &Config,
+    matcher: &Matcher,
+    files: &[PathBuf],
+    threads: usize,
+    printer: &mut Printer<W>,
+) -> io::Result<Stats> {
+    // A quiet search is usually over quickly, and one thread stops soonest.
+    if threads > 1 && files.len() > 1 && !config.quiet {
+        return parallel::search_files(config, matcher, files, threads, printer);
+    }
+
+    let mut stats = Stats::default();
+    for path in files {
+        match search_path(config, matcher, path, printer) {
+            Ok(lines) => stats.add_file(lines),
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Err(e),
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                stats.errors += 1;
+            }
+        }
+        if config.quiet && stats.lines > 0 {
+            break;
+        }
+    }
+    Ok(stats)
+}
+
+/// Search one file, or standard input for `-`, decompressing it first if need be.
+fn search_path<W: Write>(
+    config: &Config,
+    matcher: &Matcher,
+    path: &Path,
+    printer: &mut Printer<W>,
+) -> io::Result<usize> {
+    let reader: Box<dyn BufRead> = if path == Path::new(STDIN_PATH) {
+        printer.begin_file("(standard input)");
+        Box::new(io::stdin().lock())
+    } else {
+        printer.begin_file(&path.display().to_string());
+        Box::new(BufReader::new(File::open(path)?))
+    };
+    if config.decompress {
+        search_reader(config, matcher, decompress::reader(reader)?, printer)
+    } else {
+        search_reader(config, matcher, reader, printer)
+    }
+}
+
+/// Search `reader` line by line, printing what `config` asks for, and return how many lines were
+/// selected.
+///
+/// Only one line is held in memory at a time (plus any lines kept back for `-B` context), so input
+/// of any size can be searched, including a pipe. Lines that aren't valid UTF-8 are searched and
+/// printed with the bad bytes replaced by U+FFFD; byte offsets still count the original bytes, and
+/// JSON output gives the original bytes.
+/// Input with a NUL byte in its first block is taken to be binary and skipped.
+///
+/// Reading stops after `max_count` selected lines, once the context after the last of them has
+/// been printed, and with `quiet` after the first.
+pub fn search_reader<R, W>(
+    config: &Config,
+    matcher: &Matcher,
+    mut reader: R,
+    printer: &mut Printer<W>,
+) -> io::Result<usize>
+where
+    R: BufRead,
+    W: Write,
+{
+    if is_binary(reader.fill_buf()?) {
+        return Ok(0);
+    }
 
-        //let query = args[1].clone();
-        //let filename = args[2].clone();
+    let summary_only = config.count || config.files_with_matches || config.quiet;
+    // Match positions are only needed for highlighting; otherwise a yes or no per line is cheaper.
+    // Fuzzy matches always show how many edits they took, so they need finding too.
+    let fuzzy = matcher.is_fuzzy() && !summary_only && !config.invert;
+    let want_spans = (printer.wants_spans() || fuzzy) && !summary_only && !config.invert;
+    let template = config.replace.as_deref().map(Template::parse);
 
-        // We’re using the is_err method on the Result to check whether it’s an error and therefore
-        // unset, which means it should do a case-sensitive search. If the CASE_INSENSITIVE environment
-        // variable is set to anything, is_err will return false and the program will perform a
-        // case-insensitive search. We don’t care about the value of the environment variable, just
-        // whether it’s set or unset, so we’re checking is_err rather than using unwrap, expect, or
-        // any of the other methods we’ve seen on Result.
-        let mut case_sensitive = env::var("CASE_INSENSITIVE").is_err();
+    let mut buffer = Vec::new();
+    let mut count = 0;
+    let mut number = 0;
+    let mut offset = 0;
+    // Lines of context still to print once `max_count` lines have been selected.
+    let mut trailing = config.after_context;
+    loop {
+        let enough = config.max_count.is_some_and(|max| count >= max);
+        if enough && (summary_only || trailing == 0) {
+            break;
+        }
+        buffer.clear();
+        let read = reader.read_until(b'\n', &mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        number += 1;
+        let line_offset = offset;
+        offset += read;
 
-        case_sensitive = match args.next() {
-            Some(arg) => {
-                //println!("***********************{}", arg);
-                bool::from_str(&arg[..]).unwrap()
-            },
-            None => case_sensitive
+        let mut end = buffer.len();
+        if buffer[..end].ends_with(b"\n") {
+            end -= 1;
+        }
+        if buffer[..end].ends_with(b"\r") {
+            end -= 1;
+        }
+        let bytes = &buffer[..end];
+        let line = String::from_utf8_lossy(bytes);
 
+        if enough {
+            trailing -= 1;
+            printer.unmatched(number, line_offset, bytes)?;
+            continue;
+        }
+        let matches = if want_spans {
+            matcher.find_iter_with_edits(&line)
+        } else {
+            Vec::new()
         };
+        let spans: Vec<(usize, usize)> = matches.iter().map(|&(s, e, _)| (s, e)).collect();
+        let selected = if spans.is_empty() {
+            matcher.is_match(&line) != config.invert
+        } else {
+            true
+        };
+
+        if !selected {
+            if !summary_only {
+                printer.unmatched(number, line_offset, bytes)?;
+            }
+            continue;
+        }
+        count += 1;
 
-        //if args.len() == 4 {
-        //    case_sensitive = bool::from_str(&args[3][..]).unwrap();
-        //}
+        if config.files_with_matches || config.quiet {
+            break;
+        }
+        if !config.count {
+            match template.as_ref().and_then(|t| replace::replace_line(matcher, t, &line)) {
+                Some((replaced, spans)) => {
+                    printer.matched(number, line_offset, replaced.as_bytes(), &spans)?
+                }
+                None if fuzzy => {
+                    let edits: Vec<usize> = matches.iter().map(|&(_, _, edits)| edits).collect();
+                    printer.matched_with_edits(number, line_offset, bytes, &spans, &edits)?
+                }
+                None => printer.matched(number, line_offset, bytes, &spans)?,
+            }
+        }
+    }
 
-        Ok(Config { query, filename, case_sensitive })
+    if config.quiet {
+        return Ok(count);
+    }
+    if config.files_with_matches {
+        if count > 0 {
+            printer.path_with("")?;
+        }
+    } else if config.count {
+        if printer.with_path() {
+            printer.path_with(&count.to_string())?;
+        } else {
+            printer.raw(&count.to_string())?;
+        }
+    }
+    Ok(count)
+}
+
+/// Like grep, treat anything with a NUL byte near the start as binary rather than text.
+pub fn is_binary(bytes: &[u8]) -> bool {
+    bytes.iter().take(8192).any(|&b| b == 0)
+}
+
+/// What `run` does with a `Config`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    Search,
+    /// Build or update the trigram index of each of the paths, which are directories.
+    Index,
+}
+
+pub struct Config {
+    pub command: Command,
+    /// A line is selected if any of these match.
+    pub patterns: Vec<String>,
+    pub paths: Vec<String>,
+    pub case_sensitive: bool,
+    /// Ignore case only if the query is all lowercase. Takes precedence over `case_sensitive`.
+    pub smart_case: bool,
+    pub regex: bool,
+    pub invert: bool,
+    pub line_number: bool,
+    pub count: bool,
+    pub files_with_matches: bool,
+    pub word: bool,
+    pub line_regexp: bool,
+    pub byte_offset: bool,
+    pub before_context: usize,
+    pub after_context: usize,
+    pub color: ColorChoice,
+    /// How many files to search at once; 0 means one per CPU.
+    pub threads: usize,
+    /// Match within this many edits, rather than exactly.
+    pub fuzzy: Option<usize>,
+    /// Print results as JSON Lines instead of text.
+    pub json: bool,
+    /// Print selected lines with each match replaced by this template; see the `replace` module.
+    pub replace: Option<String>,
+    /// With `replace`, print a diff of the replacements instead of the lines.
+    pub dry_run: bool,
+    /// With `replace`, rewrite the files.
+    pub in_place: bool,
+    /// Search compressed files' contents rather than skipping them as binary.
+    pub decompress: bool,
+    /// Skip files that the directories' trigram indexes say can't match.
+    pub use_index: bool,
+    /// Search as the query is typed, in a full-screen view; see the `interactive` module.
+    pub interactive: bool,
+    /// Print nothing, stopping at the first selected line; only the exit status says what was found.
+    pub quiet: bool,
+    /// Stop reading each file after this many selected lines.
+    pub max_count: Option<usize>,
+}
+
+impl Config {
+    //pub fn new(args: &[String]) -> Result<Config, &'static str> {
+    //  Because we’re taking ownership of args and we’ll be mutating args by iterating over it, we
+    // can add the mut keyword into the specification of the args parameter to make it mutable.
+    //  Any iterator of strings will do, which lets the tests pass arguments without a process.
+    pub fn new<I>(mut args: I) -> Result<Config, ConfigError>
+    where
+        I: Iterator<Item = String>,
+    {
+        args.next();
+
+        cli::parse(args)
     }
 }
 
@@ -113,8 +498,18 @@ pub fn search_case_insensitive<'a>(query: &str, contents: &'a str) -> Vec<&'a st
     //}
 
     //results
+
+    // Fold the query once rather than for every line, and fold rather than lowercase, so that
+    // "STRASSE" finds "Straße".
+    let query = case::fold(query);
+    contents.lines()
+        .filter(|s| case::fold(s).contains(&query))
+        .collect()
+}
+
+pub fn search_regex<'a>(regex: &Regex, contents: &'a str) -> Vec<&'a str> {
     contents.lines()
-        .filter(|s| s.to_lowercase().contains(&query.to_lowercase()))
+        .filter(|line| regex.is_match(line))
         .collect()
 }
 
@@ -151,4 +546,66 @@ Trust me.";
             search_case_insensitive(query, contents)
         );
     }
+
+    fn stream(args: &[&str], input: &[u8]) -> (usize, String) {
+        let config = cli::parse(args.iter().map(|s| s.to_string())).unwrap();
+        let matcher = Matcher::new(&config).unwrap();
+        let mut printer = Printer::new(Vec::new(), PrintOptions {
+            line_number: config.line_number,
+            byte_offset: config.byte_offset,
+            ..PrintOptions::default()
+        });
+        let count = search_reader(&config, &matcher, io::Cursor::new(input), &mut printer).unwrap();
+        (count, String::from_utf8(printer.into_inner()).unwrap())
+    }
+
+    #[test]
+    fn streams_lines_with_crlf_and_invalid_utf8() {
+        let input = b"one fish\r\ntwo \xff fish\nred\nblue fish";
+
+        assert_eq!(
+            stream(&["-nb", "fish", "-"], input),
+            (3, String::from("1:0:one fish\n2:10:two \u{fffd} fish\n4:25:blue fish\n"))
+        );
+        assert_eq!(stream(&["-c", "fish", "-"], input), (3, String::from("3\n")));
+    }
+
+    #[test]
+    fn skips_binary_input() {
+        assert_eq!(stream(&["fish", "-"], b"fish\0fish\n"), (0, String::new()));
+    }
+
+    #[test]
+    fn searches_compressed_files() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let search = |args: &[&str]| {
+            let config = cli::parse(args.iter().map(|s| s.to_string())).unwrap();
+            let matcher = Matcher::new(&config).unwrap();
+            let files: Vec<PathBuf> = ["poem.txt.gz", "log.gz"].iter().map(|f| fixtures.join(f)).collect();
+            let mut printer = Printer::new(Vec::new(), PrintOptions::default());
+            let stats = search_files(&config, &matcher, &files, 1, &mut printer).unwrap();
+            (stats, String::from_utf8(printer.into_inner()).unwrap())
+        };
+
+        let (stats, output) = search(&["request 4321 ", "-"]);
+        assert_eq!(stats.files_matched, 1);
+        assert_eq!(output, "2024-01-01 01:12:01 INFO request 4321 served in 321 ms\n");
+        assert_eq!(search(&["-c", "frog", "-"]).1, "1\n1\n");
+        assert_eq!(search(&["--no-decompress", "-c", "frog", "-"]).0.lines, 0);
+    }
+
+    #[test]
+    fn regex() {
+        let regex = Regex::new(r"^\w+:$|t(hr|ap)e+").unwrap();
+        let contents = "\
+Rust:
+safe, fast, productive.
+Pick three.
+Duct tape.";
+
+        assert_eq!(
+            vec!["Rust:", "Pick three.", "Duct tape."],
+            search_regex(&regex, contents)
+        );
+    }
 }
\ No newline at end of file
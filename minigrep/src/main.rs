@@ -1,12 +1,20 @@
 use std::env;
 use std::process;
-use minigrep::Config;
+use minigrep::{Config, ConfigError};
 
 fn main() {
 
-    let config = Config::new(env::args()).unwrap_or_else(|err| {
-        eprintln!("Problem parsing arguments: {}", err);
-        process::exit(1);
+    // Help and version output are what the user asked for, so they go to stdout with success; a
+    // usage error is exit code 2, as with grep.
+    let config = Config::new(env::args()).unwrap_or_else(|err| match err {
+        ConfigError::Help | ConfigError::Version => {
+            println!("{}", err);
+            process::exit(0);
+        }
+        ConfigError::Usage(_) => {
+            eprintln!("Problem parsing arguments: {}", err);
+            process::exit(2);
+        }
     });
 
     //let args: Vec<String> = env::args().collect();
@@ -20,9 +28,6 @@ fn main() {
         // process::exit(1);
     //});
 
-    println!("Searching for {}", config.query);
-    println!("In file {}", config.filename);
-
     // We use if let rather than unwrap_or_else to check whether run returns an Err value and call
     // process::exit(1) if it does. The run function doesn’t return a value that we want to unwrap
     // in the same way that Config::new returns the Config instance. Because run returns () in the
@@ -30,10 +35,15 @@ fn main() {
     // the unwrapped value because it would only be ().
     // The bodies of the if let and the unwrap_or_else functions are the same in both cases: we print
     // the error and exit.
-    if let Err(e) = minigrep::run(config) {
-        eprintln!("Application error: {}", e);
+    // As with grep, the exit status is 0 if something was found, 1 if nothing was and 2 for an
+    // error; see `Outcome::exit_code`.
+    match minigrep::run(config) {
+        Ok(outcome) => process::exit(outcome.exit_code()),
+        Err(e) => {
+            eprintln!("Application error: {}", e);
 
-        process::exit(1);
+            process::exit(2);
+        }
     }
 }
 
@@ -0,0 +1,324 @@
+//! Finding the query in a line, whichever way the user asked for it to be matched.
+
+use crate::aho_corasick::AhoCorasick;
+use crate::case;
+use crate::fuzzy::Fuzzy;
+use crate::regex::{self, Regex};
+use crate::Config;
+
+enum Patterns {
+    /// Literal patterns, all searched for in one pass. With `fold` they have been case folded, and
+    /// lines are folded the same way before searching.
+    Literals { automaton: AhoCorasick, fold: bool },
+    Regexes(Vec<Regex>),
+    /// Approximate matches, each within some number of edits.
+    Fuzzy(Vec<Fuzzy>),
+}
+
+pub struct Matcher {
+    patterns: Patterns,
+    word: bool,
+    line: bool,
+}
+
+/// Whether `config` asks for case to matter, once smart case has looked at the patterns.
+pub fn case_sensitive(config: &Config) -> bool {
+    if config.smart_case {
+        config.patterns.iter().any(|p| case::has_uppercase(p, config.regex))
+    } else {
+        config.case_sensitive
+    }
+}
+
+impl Matcher {
+    pub fn new(config: &Config) -> Result<Matcher, regex::Error> {
+        let case_sensitive = case_sensitive(config);
+
+        let patterns = if let Some(max_edits) = config.fuzzy {
+            let fuzzy = config.patterns.iter().map(|p| Fuzzy::new(p, max_edits, !case_sensitive));
+            Patterns::Fuzzy(fuzzy.collect())
+        } else if config.regex {
+            let mut regexes = Vec::with_capacity(config.patterns.len());
+            for pattern in &config.patterns {
+                // Anchoring the whole pattern, rather than checking the match afterwards, lets an
+                // alternative that spans the line win over an earlier one that doesn't.
+                let pattern = if config.line_regexp {
+                    format!("^(?:{})$", pattern)
+                } else {
+                    pattern.clone()
+                };
+                regexes.push(if case_sensitive {
+                    Regex::new(&pattern)?
+                } else {
+                    Regex::new_case_insensitive(&pattern)?
+                });
+            }
+            Patterns::Regexes(regexes)
+        } else if case_sensitive {
+            Patterns::Literals {
+                automaton: AhoCorasick::new(&config.patterns, false),
+                fold: false,
+            }
+        } else {
+            let folded: Vec<String> = config.patterns.iter().map(|p| case::fold(p)).collect();
+            Patterns::Literals {
+                automaton: AhoCorasick::new(&folded, true),
+                fold: true,
+            }
+        };
+
+        Ok(Matcher {
+            patterns,
+            word: config.word,
+            line: config.line_regexp,
+        })
+    }
+
+    /// Whether a match at `start..end` of `line` satisfies the word and whole-line modes.
+    fn accept(&self, line: &str, start: usize, end: usize) -> bool {
+        (!self.word || is_word_bounded(line, start, end))
+            && (!self.line || (start == 0 && end == line.len()))
+    }
+
+    /// The leftmost match at or after byte offset `start`. In word mode a match only counts if it
+    /// isn't glued to a letter, digit or underscore on either side, and in whole-line mode only if
+    /// it is the entire line.
+    pub fn find_at(&self, line: &str, start: usize) -> Option<(usize, usize)> {
+        self.find_with_edits_at(line, start).map(|(s, e, _)| (s, e))
+    }
+
+    /// Like `find_at`, also returning how many edits the match needed, which is always 0 unless
+    /// matching is fuzzy.
+    pub fn find_with_edits_at(&self, line: &str, start: usize) -> Option<(usize, usize, usize)> {
+        let exact = |found: Option<(usize, usize)>| found.map(|(s, e)| (s, e, 0));
+        match &self.patterns {
+            Patterns::Literals { automaton, fold } if !fold || line.is_ascii() => {
+                // ASCII only folds to ASCII, and the automaton takes care of its case, so there's
+                // nothing to fold or map back.
+                exact(automaton.find(line.as_bytes(), start, |s, e| {
+                    line.is_char_boundary(s) && line.is_char_boundary(e) && self.accept(line, s, e)
+                }))
+            }
+            Patterns::Literals { automaton, .. } => {
+                let (folded, offsets) = case::fold_with_offsets(&line[start..]);
+                // A match has to cover whole characters of the line, not part of an expansion such
+                // as the first `s` of the `ss` that `ß` folds to.
+                exact(
+                    automaton
+                        .find(folded.as_bytes(), 0, |s, e| {
+                            is_char_start(&offsets, s)
+                                && is_char_start(&offsets, e)
+                                && self.accept(line, start + offsets[s], start + offsets[e])
+                        })
+                        .map(|(s, e)| (start + offsets[s], start + offsets[e])),
+                )
+            }
+            Patterns::Regexes(regexes) => regexes
+                .iter()
+                .filter_map(|regex| {
+                    self.first_accepted(line, start, |from| exact(regex.find_at(line, from)))
+                })
+                .min_by_key(|&(s, e, _)| (s, usize::MAX - e)),
+            Patterns::Fuzzy(patterns) => patterns
+                .iter()
+                .filter_map(|fuzzy| self.first_accepted(line, start, |from| fuzzy.find_at(line, from)))
+                .min_by_key(|&(s, e, edits)| (s, edits, usize::MAX - e)),
+        }
+    }
+
+    /// Call `find` from `start` onwards until it returns a match that `accept` allows.
+    fn first_accepted<F>(&self, line: &str, start: usize, find: F) -> Option<(usize, usize, usize)>
+    where
+        F: Fn(usize) -> Option<(usize, usize, usize)>,
+    {
+        let mut from = start;
+        loop {
+            let (s, e, edits) = find(from)?;
+            if self.accept(line, s, e) {
+                return Some((s, e, edits));
+            }
+            from = s + line[s..].chars().next()?.len_utf8();
+        }
+    }
+
+    pub fn is_fuzzy(&self) -> bool {
+        matches!(self.patterns, Patterns::Fuzzy(_))
+    }
+
+    pub fn is_match(&self, line: &str) -> bool {
+        self.find_at(line, 0).is_some()
+    }
+
+    /// Every non-overlapping match in the line, left to right.
+    pub fn find_iter(&self, line: &str) -> Vec<(usize, usize)> {
+        self.find_iter_with_edits(line).into_iter().map(|(s, e, _)| (s, e)).collect()
+    }
+
+    /// Every non-overlapping match in the line with the edits it needed, left to right.
+    pub fn find_iter_with_edits(&self, line: &str) -> Vec<(usize, usize, usize)> {
+        let mut matches = Vec::new();
+        let mut start = 0;
+        while let Some((s, e, edits)) = self.find_with_edits_at(line, start) {
+            matches.push((s, e, edits));
+            start = if e > s {
+                e
+            } else {
+                match line[e..].chars().next() {
+                    Some(c) => e + c.len_utf8(),
+                    None => break,
+                }
+            };
+        }
+        matches
+    }
+
+    /// Every non-overlapping match in the line with its capture groups, where group 0 is the whole
+    /// match. Literal and fuzzy patterns have no other groups.
+    pub fn captures_iter(&self, line: &str) -> Vec<Vec<Option<(usize, usize)>>> {
+        self.find_iter(line)
+            .into_iter()
+            .map(|(s, e)| {
+                let whole = vec![Some((s, e))];
+                match &self.patterns {
+                    // Whichever regex made this match will make it again from the same place.
+                    Patterns::Regexes(regexes) => regexes
+                        .iter()
+                        .filter_map(|regex| regex.captures_at(line, s))
+                        .find(|groups| groups[0] == Some((s, e)))
+                        .unwrap_or(whole),
+                    Patterns::Literals { .. } | Patterns::Fuzzy(_) => whole,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Whether byte `i` of some folded text is where the folding of an original character begins.
+fn is_char_start(offsets: &[usize], i: usize) -> bool {
+    i == 0 || i + 1 == offsets.len() || offsets[i] != offsets[i - 1]
+}
+
+fn is_word_char(c: Option<char>) -> bool {
+    c.is_some_and(|c| c.is_alphanumeric() || c == '_')
+}
+
+fn is_word_bounded(line: &str, start: usize, end: usize) -> bool {
+    !is_word_char(line.get(..start).and_then(|s| s.chars().next_back()))
+        && !is_word_char(line.get(end..).and_then(|s| s.chars().next()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(query: &str, configure: fn(&mut Config)) -> Matcher {
+        let mut config = crate::cli::parse(vec![query.to_string(), String::from("-")].into_iter())
+            .unwrap();
+        configure(&mut config);
+        Matcher::new(&config).unwrap()
+    }
+
+    #[test]
+    fn word_mode_skips_partial_words() {
+        let words = matcher("fast", |c| c.word = true);
+
+        assert_eq!(words.find_at("breakfast, fast!", 0), Some((11, 15)));
+        assert!(!words.is_match("breakfasts"));
+    }
+
+    #[test]
+    fn word_mode_works_with_regex_and_case() {
+        let words = matcher("th[a-z]+", |c| {
+            c.word = true;
+            c.regex = true;
+            c.case_sensitive = false;
+        });
+
+        assert_eq!(words.find_at("bath Then", 0), Some((5, 9)));
+    }
+
+    #[test]
+    fn case_insensitive_spans_point_into_the_original_line() {
+        let query = matcher("straße", |c| c.case_sensitive = false);
+        let line = "İ STRAẞE, straße";
+
+        assert_eq!(query.find_iter(line), vec![(3, 11), (13, 20)]);
+    }
+
+    #[test]
+    fn case_folding_expands_and_matches_whole_characters() {
+        let query = matcher("STRASSE", |c| c.case_sensitive = false);
+        assert_eq!(query.find_iter("Straße strasse"), vec![(0, 7), (8, 15)]);
+
+        let query = matcher("s", |c| c.case_sensitive = false);
+        assert_eq!(query.find_iter("ßs"), vec![(2, 3)]);
+
+        let query = matcher("I", |c| c.case_sensitive = false);
+        assert!(!query.is_match("ı"));
+    }
+
+    fn patterns(patterns: &[&str], configure: fn(&mut Config)) -> Matcher {
+        let mut args = Vec::new();
+        for pattern in patterns {
+            args.push(String::from("-e"));
+            args.push(pattern.to_string());
+        }
+        args.push(String::from("-"));
+        let mut config = crate::cli::parse(args.into_iter()).unwrap();
+        configure(&mut config);
+        Matcher::new(&config).unwrap()
+    }
+
+    #[test]
+    fn several_literals_in_one_pass() {
+        let any = patterns(&["fish", "FOX", "dog"], |c| c.case_sensitive = false);
+        assert_eq!(any.find_iter("Dogfish and a fox"), vec![(0, 3), (3, 7), (14, 17)]);
+
+        let words = patterns(&["fish", "dogfish"], |c| c.word = true);
+        assert_eq!(words.find_iter("dogfish catfish fish"), vec![(0, 7), (16, 20)]);
+    }
+
+    #[test]
+    fn whole_line_mode() {
+        let lines = patterns(&["to be", "to"], |c| c.line_regexp = true);
+        assert!(lines.is_match("to"));
+        assert!(!lines.is_match("to be or not"));
+
+        let regexes = patterns(&["a|ab", "x"], |c| {
+            c.line_regexp = true;
+            c.regex = true;
+        });
+        assert_eq!(regexes.find_at("ab", 0), Some((0, 2)));
+    }
+
+    #[test]
+    fn empty_pattern_matches_every_line() {
+        let empty = patterns(&[""], |_| {});
+        assert!(empty.is_match(""));
+        assert_eq!(empty.find_iter("aé"), vec![(0, 0), (1, 1), (3, 3)]);
+    }
+
+    #[test]
+    fn fuzzy_matches_report_their_edits() {
+        let fuzzy = patterns(&["somebody", "frog"], |c| c.fuzzy = Some(1));
+        assert_eq!(
+            fuzzy.find_iter_with_edits("How dreary to be smebody, a frg"),
+            vec![(17, 24, 1), (28, 31, 1)]
+        );
+
+        let words = patterns(&["frog"], |c| {
+            c.fuzzy = Some(1);
+            c.word = true;
+        });
+        assert_eq!(words.find_iter_with_edits("frogs fog"), vec![(6, 9, 1)]);
+    }
+
+    #[test]
+    fn smart_case_depends_on_the_query() {
+        let lower = matcher("rust", |c| c.smart_case = true);
+        assert!(lower.is_match("Rust"));
+
+        let upper = matcher("Rust", |c| c.smart_case = true);
+        assert!(!upper.is_match("rust"));
+    }
+}
@@ -0,0 +1,337 @@
+//! Printing selected lines with their prefixes, context and highlighting.
+
+use crate::json;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+// The same SGR sequences GNU grep uses by default.
+pub const COLOR_MATCH: &str = "\x1b[01;31m";
+pub const COLOR_PATH: &str = "\x1b[35m";
+pub const COLOR_NUMBER: &str = "\x1b[32m";
+pub const COLOR_SEPARATOR: &str = "\x1b[36m";
+pub const COLOR_RESET: &str = "\x1b[0m";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorChoice {
+    Always,
+    Never,
+    /// Color only when stdout is a terminal.
+    Auto,
+}
+
+/// How each printed line is decorated.
+#[derive(Debug, Clone, Default)]
+pub struct PrintOptions {
+    pub with_path: bool,
+    pub line_number: bool,
+    pub byte_offset: bool,
+    pub before: usize,
+    pub after: usize,
+    pub color: bool,
+    /// Print each line as a JSON object instead; see the `json` module.
+    pub json: bool,
+}
+
+struct Line {
+    number: usize,
+    offset: usize,
+    /// As read, so JSON output can give the bytes of a line that isn't UTF-8.
+    bytes: Vec<u8>,
+}
+
+/// Writes the selected lines of one file after another, fed one line at a time.
+///
+/// Lines before a match are held back in a queue of at most `before` lines, and after a match the
+/// next `after` lines are printed as context. Groups of lines that aren't adjacent are separated by
+/// `--`, as in grep. Selected lines use `:` after each prefix and context lines use `-`.
+pub struct Printer<W: Write> {
+    out: W,
+    options: PrintOptions,
+    path: String,
+    before: VecDeque<Line>,
+    after_left: usize,
+    last_printed: Option<usize>,
+    printed_any: bool,
+}
+
+impl<W: Write> Printer<W> {
+    pub fn new(out: W, options: PrintOptions) -> Printer<W> {
+        Printer {
+            out,
+            options,
+            path: String::new(),
+            before: VecDeque::new(),
+            after_left: 0,
+            last_printed: None,
+            printed_any: false,
+        }
+    }
+
+    /// Start on the lines of another file.
+    pub fn begin_file(&mut self, path: &str) {
+        self.path = path.to_string();
+        self.before.clear();
+        self.after_left = 0;
+        self.last_printed = None;
+    }
+
+    /// A selected line. `spans` are the byte ranges to highlight, in the line as decoded by
+    /// `String::from_utf8_lossy`.
+    pub fn matched(
+        &mut self,
+        number: usize,
+        offset: usize,
+        line: &[u8],
+        spans: &[(usize, usize)],
+    ) -> io::Result<()> {
+        self.matched_with_edits(number, offset, line, spans, &[])
+    }
+
+    /// A line selected by fuzzy matching, with how many edits each span needed. Text output shows
+    /// the fewest, as `~N` after the other prefixes; JSON output has them all.
+    pub fn matched_with_edits(
+        &mut self,
+        number: usize,
+        offset: usize,
+        line: &[u8],
+        spans: &[(usize, usize)],
+        edits: &[usize],
+    ) -> io::Result<()> {
+        while let Some(line) = self.before.pop_front() {
+            self.write_line(&line, '-', &[], &[])?;
+        }
+        let line = Line {
+            number,
+            offset,
+            bytes: line.to_vec(),
+        };
+        self.write_line(&line, ':', spans, edits)?;
+        self.after_left = self.options.after;
+        Ok(())
+    }
+
+    /// A line that wasn't selected; printed only if it falls in some match's context.
+    pub fn unmatched(&mut self, number: usize, offset: usize, line: &[u8]) -> io::Result<()> {
+        let line = Line {
+            number,
+            offset,
+            bytes: line.to_vec(),
+        };
+        if self.after_left > 0 {
+            self.after_left -= 1;
+            self.write_line(&line, '-', &[], &[])
+        } else {
+            if self.options.before > 0 {
+                if self.before.len() == self.options.before {
+                    self.before.pop_front();
+                }
+                self.before.push_back(line);
+            }
+            Ok(())
+        }
+    }
+
+    /// Write something other than a line, such as a count or a file name.
+    pub fn raw(&mut self, text: &str) -> io::Result<()> {
+        writeln!(self.out, "{}", text)
+    }
+
+    pub fn path_with(&mut self, suffix: &str) -> io::Result<()> {
+        let path = self.paint(COLOR_PATH, &self.path);
+        if suffix.is_empty() {
+            writeln!(self.out, "{}", path)
+        } else {
+            let separator = self.paint(COLOR_SEPARATOR, ":");
+            writeln!(self.out, "{}{}{}", path, separator, suffix)
+        }
+    }
+
+    /// Write out everything `other`, a printer with the same options, printed for one whole file,
+    /// as though this printer had printed it.
+    pub fn append(&mut self, other: Printer<Vec<u8>>) -> io::Result<()> {
+        if self.has_context() && !self.options.json && self.printed_any && other.printed_any {
+            let dashes = self.paint(COLOR_SEPARATOR, "--");
+            writeln!(self.out, "{}", dashes)?;
+        }
+        self.out.write_all(&other.out)?;
+        self.printed_any |= other.printed_any;
+        Ok(())
+    }
+
+    pub fn options(&self) -> &PrintOptions {
+        &self.options
+    }
+
+    /// Whether `matched` makes any use of the spans it's given.
+    pub fn wants_spans(&self) -> bool {
+        self.options.color || self.options.json
+    }
+
+    pub fn with_path(&self) -> bool {
+        self.options.with_path
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn paint(&self, color: &str, text: &str) -> String {
+        if self.options.color {
+            format!("{}{}{}", color, text, COLOR_RESET)
+        } else {
+            text.to_string()
+        }
+    }
+
+    fn has_context(&self) -> bool {
+        self.options.before > 0 || self.options.after > 0
+    }
+
+    fn write_line(
+        &mut self,
+        line: &Line,
+        separator: char,
+        spans: &[(usize, usize)],
+        edits: &[usize],
+    ) -> io::Result<()> {
+        if self.options.json {
+            let record = json::line_record(
+                separator == ':',
+                &self.path,
+                line.number,
+                line.offset,
+                &line.bytes,
+                spans,
+                edits,
+            );
+            writeln!(self.out, "{}", record)?;
+            self.last_printed = Some(line.number);
+            self.printed_any = true;
+            return Ok(());
+        }
+
+        let gap = match self.last_printed {
+            Some(last) => line.number > last + 1,
+            None => self.printed_any,
+        };
+        if self.has_context() && gap {
+            let dashes = self.paint(COLOR_SEPARATOR, "--");
+            writeln!(self.out, "{}", dashes)?;
+        }
+
+        let separator = self.paint(COLOR_SEPARATOR, &separator.to_string());
+        let mut prefix = String::new();
+        if self.options.with_path {
+            prefix.push_str(&self.paint(COLOR_PATH, &self.path));
+            prefix.push_str(&separator);
+        }
+        if self.options.line_number {
+            prefix.push_str(&self.paint(COLOR_NUMBER, &line.number.to_string()));
+            prefix.push_str(&separator);
+        }
+        if self.options.byte_offset {
+            prefix.push_str(&self.paint(COLOR_NUMBER, &line.offset.to_string()));
+            prefix.push_str(&separator);
+        }
+        if let Some(fewest) = edits.iter().min() {
+            prefix.push_str(&self.paint(COLOR_NUMBER, &format!("~{}", fewest)));
+            prefix.push_str(&separator);
+        }
+
+        let text = String::from_utf8_lossy(&line.bytes);
+        let text = if self.options.color {
+            highlight(&text, spans)
+        } else {
+            text.into_owned()
+        };
+        writeln!(self.out, "{}{}", prefix, text)?;
+
+        self.last_printed = Some(line.number);
+        self.printed_any = true;
+        Ok(())
+    }
+}
+
+fn highlight(text: &str, spans: &[(usize, usize)]) -> String {
+    let mut painted = String::with_capacity(text.len());
+    let mut last = 0;
+    for &(start, end) in spans {
+        if start < last || end <= start || end > text.len() {
+            continue;
+        }
+        painted.push_str(&text[last..start]);
+        painted.push_str(COLOR_MATCH);
+        painted.push_str(&text[start..end]);
+        painted.push_str(COLOR_RESET);
+        last = end;
+    }
+    painted.push_str(&text[last..]);
+    painted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn print(options: PrintOptions, lines: &[&str], query: &str) -> String {
+        let mut printer = Printer::new(Vec::new(), options);
+        printer.begin_file("poem.txt");
+        let mut offset = 0;
+        for (i, text) in lines.iter().enumerate() {
+            match text.find(query) {
+                Some(s) => printer.matched(i + 1, offset, text.as_bytes(), &[(s, s + query.len())]),
+                None => printer.unmatched(i + 1, offset, text.as_bytes()),
+            }
+            .unwrap();
+            offset += text.len() + 1;
+        }
+        String::from_utf8(printer.into_inner()).unwrap()
+    }
+
+    const LINES: [&str; 8] = ["a", "b", "x1", "c", "d", "e", "x2", "f"];
+
+    #[test]
+    fn prints_context_with_separators() {
+        let options = PrintOptions {
+            line_number: true,
+            before: 1,
+            after: 1,
+            ..PrintOptions::default()
+        };
+
+        assert_eq!(
+            print(options, &LINES, "x"),
+            "2-b\n3:x1\n4-c\n--\n6-e\n7:x2\n8-f\n"
+        );
+    }
+
+    #[test]
+    fn adjacent_groups_are_merged() {
+        let options = PrintOptions {
+            after: 3,
+            ..PrintOptions::default()
+        };
+
+        assert_eq!(print(options, &LINES, "x"), "x1\nc\nd\ne\nx2\nf\n");
+    }
+
+    #[test]
+    fn prefixes_path_and_byte_offset_and_highlights() {
+        let options = PrintOptions {
+            with_path: true,
+            byte_offset: true,
+            color: true,
+            ..PrintOptions::default()
+        };
+
+        assert_eq!(
+            print(options, &["ab", "cxd"], "x"),
+            "\x1b[35mpoem.txt\x1b[0m\x1b[36m:\x1b[0m\x1b[32m3\x1b[0m\x1b[36m:\x1b[0m\
+             c\x1b[01;31mx\x1b[0md\n"
+        );
+    }
+}
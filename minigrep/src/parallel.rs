@@ -0,0 +1,196 @@
+//! Searching several files at once.
+//!
+//! A fixed set of worker threads take the next file off a shared counter, search it into a buffer
+//! of their own and send the buffer back. The calling thread writes the buffers out in the order of
+//! the files, holding on to any that finish early, so the output doesn't depend on which thread
+//! happened to be quickest. Workers only run a few files ahead of the one being written, so a file
+//! that's slow to search doesn't leave the output of all the rest waiting in memory behind it.
+
+use crate::matcher::Matcher;
+use crate::output::Printer;
+use crate::{search_path, Config, Stats};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+/// How many files each worker may be ahead of the one being written.
+const AHEAD_PER_THREAD: usize = 4;
+
+type Searched = (usize, io::Result<(usize, Printer<Vec<u8>>)>);
+
+/// Which files workers may search: those fewer than `size` after the next one to write.
+struct Window {
+    size: usize,
+    /// How many files have been written, and whether to stop searching altogether.
+    state: Mutex<(usize, bool)>,
+    moved: Condvar,
+}
+
+impl Window {
+    fn new(size: usize) -> Window {
+        Window {
+            size,
+            state: Mutex::new((0, false)),
+            moved: Condvar::new(),
+        }
+    }
+
+    /// Waits until file `index` may be searched, returning false if the search has been stopped.
+    fn wait_for(&self, index: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        while !state.1 && index >= state.0 + self.size {
+            state = self.moved.wait(state).unwrap();
+        }
+        !state.1
+    }
+
+    fn written(&self, count: usize) {
+        self.state.lock().unwrap().0 = count;
+        self.moved.notify_all();
+    }
+
+    fn stop(&self) {
+        self.state.lock().unwrap().1 = true;
+        self.moved.notify_all();
+    }
+}
+
+pub fn search_files<W: Write>(
+    config: &Config,
+    matcher: &Matcher,
+    files: &[PathBuf],
+    threads: usize,
+    printer: &mut Printer<W>,
+) -> io::Result<Stats> {
+    let next = AtomicUsize::new(0);
+    let window = Window::new(threads * AHEAD_PER_THREAD);
+    let (sender, receiver) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..threads.min(files.len()) {
+            let sender = sender.clone();
+            let (next, window) = (&next, &window);
+            let options = printer.options().clone();
+            scope.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let path = match files.get(index) {
+                    Some(path) => path,
+                    None => break,
+                };
+                if !window.wait_for(index) {
+                    break;
+                }
+                let mut buffer = Printer::new(Vec::new(), options.clone());
+                let result = search_path(config, matcher, path, &mut buffer);
+                if sender.send((index, result.map(|count| (count, buffer)))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        let result = write_in_order(files, receiver, &window, printer);
+        // Don't let the workers carry on through the rest of the files for nothing.
+        if result.is_err() {
+            window.stop();
+        }
+        result
+    })
+}
+
+fn write_in_order<W: Write>(
+    files: &[PathBuf],
+    receiver: Receiver<Searched>,
+    window: &Window,
+    printer: &mut Printer<W>,
+) -> io::Result<Stats> {
+    let mut finished = BTreeMap::new();
+    let mut next = 0;
+    let mut stats = Stats::default();
+
+    for (index, result) in receiver {
+        finished.insert(index, result);
+        while let Some(result) = finished.remove(&next) {
+            match result {
+                Ok((lines, buffer)) => {
+                    stats.add_file(lines);
+                    printer.append(buffer)?;
+                }
+                Err(e) => {
+                    eprintln!("{}: {}", files[next].display(), e);
+                    stats.errors += 1;
+                }
+            }
+            next += 1;
+        }
+        window.written(next);
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::PrintOptions;
+    use std::fs;
+
+    #[test]
+    fn output_matches_the_sequential_search() {
+        let root = std::env::temp_dir().join(format!("minigrep-parallel-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let mut files = Vec::new();
+        for i in 0..20 {
+            let path = root.join(format!("{:02}.txt", i));
+            let lines: Vec<String> = (0..50 * (20 - i)).map(|n| format!("line {} of {}", n, i)).collect();
+            fs::write(&path, lines.join("\n")).unwrap();
+            files.push(path);
+        }
+        files.insert(5, root.join("missing.txt"));
+
+        let args = ["-C1", "line 7 ", root.to_str().unwrap()];
+        let config = crate::cli::parse(args.iter().map(|s| s.to_string())).unwrap();
+        let matcher = Matcher::new(&config).unwrap();
+        let search = |threads| {
+            let options = PrintOptions {
+                with_path: true,
+                before: 1,
+                after: 1,
+                ..PrintOptions::default()
+            };
+            let mut printer = Printer::new(Vec::new(), options);
+            let total = crate::search_files(&config, &matcher, &files, threads, &mut printer).unwrap();
+            (total, String::from_utf8(printer.into_inner()).unwrap())
+        };
+
+        let (stats, sequential) = search(1);
+        assert_eq!(search(4), (stats, sequential.clone()));
+        assert_eq!((stats.files_searched, stats.lines), (20, 20));
+        assert!(sequential.contains("--\n"));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn workers_wait_for_the_window_to_reach_their_file() {
+        let window = Window::new(2);
+        assert!(window.wait_for(1));
+
+        thread::scope(|scope| {
+            let waiting = scope.spawn(|| window.wait_for(3));
+            thread::sleep(std::time::Duration::from_millis(50));
+            assert!(!waiting.is_finished());
+            window.written(1);
+            thread::sleep(std::time::Duration::from_millis(50));
+            assert!(!waiting.is_finished());
+            window.written(2);
+            assert!(waiting.join().unwrap());
+
+            let stopped = scope.spawn(|| window.wait_for(10));
+            window.stop();
+            assert!(!stopped.join().unwrap());
+        });
+    }
+}
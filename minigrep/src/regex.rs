@@ -0,0 +1,950 @@
+//! A small regular expression engine for `--regex` searches.
+//!
+//! Patterns are parsed into a syntax tree and compiled to a program for a Pike VM, which runs all
+//! alternatives in lock step. That keeps matching linear in the length of the line no matter how
+//! the pattern is written, so there is no catastrophic backtracking to worry about.
+//!
+//! Supported syntax: literals, `.`, classes such as `[a-z_]` and `[^0-9]`, the escapes `\d \w \s`
+//! and their negations, `\b \B`, anchors `^ $`, alternation `|`, capturing `( )` and
+//! non-capturing `(?: )` groups, and the repetitions `* + ? {n} {n,} {n,m}` with lazy `?` forms.
+
+use crate::case;
+use std::error;
+use std::fmt;
+
+/// Counted repetitions are expanded into copies of the repeated expression, so they are capped.
+const MAX_REPEAT: u32 = 1000;
+const MAX_PROGRAM_LEN: usize = 100_000;
+/// Parsing and compiling recurse into groups and repetitions, so how deep they go is capped too.
+const MAX_DEPTH: usize = 200;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    pub message: String,
+    /// Character offset in the pattern where the problem was found.
+    pub position: usize,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid regex at position {}: {}", self.position, self.message)
+    }
+}
+
+impl error::Error for Error {}
+
+#[derive(Debug, Clone)]
+struct Class {
+    ranges: Vec<(char, char)>,
+    negated: bool,
+}
+
+impl Class {
+    fn contains(&self, c: char) -> bool {
+        self.ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi)
+    }
+
+    fn matches(&self, c: char, case_insensitive: bool) -> bool {
+        let hit = self.contains(c)
+            || (case_insensitive && case_variants(c).iter().any(|&v| self.contains(v)));
+        hit != self.negated
+    }
+}
+
+/// The other cases of `c`. A mapping to several characters, like `ß` to `SS`, is left out, as the
+/// characters don't match `c` on their own.
+fn case_variants(c: char) -> Vec<char> {
+    let lower: Vec<char> = c.to_lowercase().collect();
+    let upper: Vec<char> = c.to_uppercase().collect();
+    let mut variants = Vec::new();
+    for mapped in &[lower, upper] {
+        if let [v] = mapped[..] {
+            if v != c && !variants.contains(&v) {
+                variants.push(v);
+            }
+        }
+    }
+    variants
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Assertion {
+    Start,
+    End,
+    WordBoundary,
+    NotWordBoundary,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Empty,
+    Literal(char),
+    Any,
+    Class(Class),
+    Assert(Assertion),
+    Group(Box<Node>, Option<usize>),
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: u32,
+        max: Option<u32>,
+        greedy: bool,
+    },
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    groups: usize,
+}
+
+impl Parser {
+    fn error<T>(&self, message: &str) -> Result<T, Error> {
+        Err(Error {
+            message: message.to_string(),
+            position: self.pos,
+        })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse(mut self) -> Result<(Node, usize), Error> {
+        let (node, _) = self.parse_alternation(0)?;
+        if self.pos < self.chars.len() {
+            return self.error("unmatched ')'");
+        }
+        Ok((node, self.groups))
+    }
+
+    // The parse functions return each node with its height, counting the node itself, and take
+    // the number of groups they're inside. Neither may exceed `MAX_DEPTH`.
+
+    fn parse_alternation(&mut self, groups: usize) -> Result<(Node, usize), Error> {
+        let (first, mut height) = self.parse_concat(groups)?;
+        let mut alternatives = vec![first];
+        while self.eat('|') {
+            let (alternative, h) = self.parse_concat(groups)?;
+            alternatives.push(alternative);
+            height = height.max(h);
+        }
+        if alternatives.len() == 1 {
+            return Ok((alternatives.pop().unwrap(), height));
+        }
+        self.check_height(height + 1)?;
+        Ok((Node::Alternate(alternatives), height + 1))
+    }
+
+    fn parse_concat(&mut self, groups: usize) -> Result<(Node, usize), Error> {
+        let mut items = Vec::new();
+        let mut height = 1;
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let (item, h) = self.parse_repeat(groups)?;
+            items.push(item);
+            height = height.max(h);
+        }
+        Ok(match items.len() {
+            0 => (Node::Empty, 1),
+            1 => (items.pop().unwrap(), height),
+            _ => {
+                self.check_height(height + 1)?;
+                (Node::Concat(items), height + 1)
+            }
+        })
+    }
+
+    fn check_height(&self, height: usize) -> Result<(), Error> {
+        if height > MAX_DEPTH {
+            return self.error("pattern nested too deeply");
+        }
+        Ok(())
+    }
+
+    fn parse_repeat(&mut self, groups: usize) -> Result<(Node, usize), Error> {
+        let (mut node, mut height) = self.parse_atom(groups)?;
+
+        loop {
+            let start = self.pos;
+            let (min, max) = match self.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => match self.parse_counts()? {
+                    Some(counts) => counts,
+                    None => break,
+                },
+                _ => break,
+            };
+            if self.pos == start {
+                self.pos += 1;
+            }
+            if let Node::Assert(_) | Node::Empty = node {
+                self.pos = start;
+                return self.error("nothing to repeat");
+            }
+            height += 1;
+            if height > MAX_DEPTH {
+                self.pos = start;
+                return self.error("pattern nested too deeply");
+            }
+            let greedy = !self.eat('?');
+            node = Node::Repeat {
+                node: Box::new(node),
+                min,
+                max,
+                greedy,
+            };
+        }
+        Ok((node, height))
+    }
+
+    /// Parse `{n}`, `{n,}` or `{n,m}`. Anything else leaves the `{` to be read as a literal.
+    fn parse_counts(&mut self) -> Result<Option<(u32, Option<u32>)>, Error> {
+        let rest: String = self.chars[self.pos..].iter().collect();
+        let close = match rest.find('}') {
+            Some(close) => close,
+            None => return Ok(None),
+        };
+        let body = &rest[1..close];
+        let (min, max) = match body.find(',') {
+            Some(i) => (&body[..i], Some(&body[i + 1..])),
+            None => (body, None),
+        };
+        let is_number = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+        if !is_number(min) || !max.is_none_or(|m| m.is_empty() || is_number(m)) {
+            return Ok(None);
+        }
+
+        let parse = |s: &str| s.parse::<u32>().ok().filter(|&n| n <= MAX_REPEAT);
+        let min = match parse(min) {
+            Some(min) => min,
+            None => return self.error("repetition count too large"),
+        };
+        let max = match max {
+            None => Some(min),
+            Some("") => None,
+            Some(max) => match parse(max) {
+                Some(max) if max >= min => Some(max),
+                Some(_) => return self.error("repetition range is backwards"),
+                None => return self.error("repetition count too large"),
+            },
+        };
+
+        self.pos += body.chars().count() + 2;
+        Ok(Some((min, max)))
+    }
+
+    fn parse_atom(&mut self, groups: usize) -> Result<(Node, usize), Error> {
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return self.error("unexpected end of pattern"),
+        };
+        if c == '(' && groups >= MAX_DEPTH {
+            return self.error("pattern nested too deeply");
+        }
+        self.pos += 1;
+
+        let node = match c {
+            '(' => {
+                let index = if self.chars[self.pos..].starts_with(&['?', ':']) {
+                    self.pos += 2;
+                    None
+                } else {
+                    self.groups += 1;
+                    Some(self.groups)
+                };
+                let (inner, height) = self.parse_alternation(groups + 1)?;
+                if !self.eat(')') {
+                    return self.error("unclosed group");
+                }
+                self.check_height(height + 1)?;
+                return Ok((Node::Group(Box::new(inner), index), height + 1));
+            }
+            '[' => self.parse_class()?,
+            '.' => Node::Any,
+            '^' => Node::Assert(Assertion::Start),
+            '$' => Node::Assert(Assertion::End),
+            '*' | '+' | '?' => {
+                self.pos -= 1;
+                return self.error("nothing to repeat");
+            }
+            '\\' => self.parse_escape()?,
+            c => Node::Literal(c),
+        };
+        Ok((node, 1))
+    }
+
+    fn parse_escape(&mut self) -> Result<Node, Error> {
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return self.error("trailing backslash"),
+        };
+        self.pos += 1;
+
+        Ok(match c {
+            'b' => Node::Assert(Assertion::WordBoundary),
+            'B' => Node::Assert(Assertion::NotWordBoundary),
+            _ => match class_escape(c) {
+                Some(class) => Node::Class(class),
+                None => Node::Literal(self.literal_escape(c)?),
+            },
+        })
+    }
+
+    fn literal_escape(&self, c: char) -> Result<char, Error> {
+        match c {
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            'r' => Ok('\r'),
+            c if c.is_ascii_alphanumeric() => self.error("unknown escape sequence"),
+            c => Ok(c),
+        }
+    }
+
+    fn parse_class(&mut self) -> Result<Node, Error> {
+        let negated = self.eat('^');
+        let mut ranges = Vec::new();
+        let mut first = true;
+
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => return self.error("unclosed character class"),
+            };
+            self.pos += 1;
+
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+
+            let lo = if c == '\\' {
+                let e = match self.peek() {
+                    Some(e) => e,
+                    None => return self.error("unclosed character class"),
+                };
+                self.pos += 1;
+                if let Some(class) = class_escape(e) {
+                    ranges.extend(class_ranges(&class));
+                    continue;
+                }
+                self.literal_escape(e)?
+            } else {
+                c
+            };
+
+            // A `-` between two characters makes a range; at either end it is a literal.
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|&n| n != ']')
+            {
+                self.pos += 1;
+                let mut hi = self.chars[self.pos];
+                self.pos += 1;
+                if hi == '\\' {
+                    match self.peek() {
+                        Some(e) => {
+                            self.pos += 1;
+                            hi = self.literal_escape(e)?;
+                        }
+                        None => return self.error("unclosed character class"),
+                    }
+                }
+                if hi < lo {
+                    return self.error("character class range is backwards");
+                }
+                ranges.push((lo, hi));
+            } else {
+                ranges.push((lo, lo));
+            }
+        }
+
+        Ok(Node::Class(Class { ranges, negated }))
+    }
+}
+
+fn class_escape(c: char) -> Option<Class> {
+    let (ranges, negated) = match c {
+        'd' | 'D' => (vec![('0', '9')], c == 'D'),
+        'w' | 'W' => (
+            vec![('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')],
+            c == 'W',
+        ),
+        's' | 'S' => (
+            vec![('\t', '\r'), (' ', ' ')],
+            c == 'S',
+        ),
+        _ => return None,
+    };
+    Some(Class { ranges, negated })
+}
+
+/// The ranges a class matches, with negation folded in so it can be merged into another class.
+fn class_ranges(class: &Class) -> Vec<(char, char)> {
+    if !class.negated {
+        return class.ranges.clone();
+    }
+
+    let mut sorted = class.ranges.clone();
+    sorted.sort();
+    let mut complement = Vec::new();
+    let mut next = 0u32;
+    for (lo, hi) in sorted {
+        if (lo as u32) > next {
+            push_range(&mut complement, next, lo as u32 - 1);
+        }
+        next = next.max(hi as u32 + 1);
+    }
+    push_range(&mut complement, next, char::MAX as u32);
+    complement
+}
+
+// Adds `lo..=hi` as char ranges, stepping around the surrogate gap.
+fn push_range(ranges: &mut Vec<(char, char)>, lo: u32, hi: u32) {
+    for &(a, b) in &[(lo, hi.min(0xD7FF)), (lo.max(0xE000), hi)] {
+        if a <= b {
+            if let (Some(a), Some(b)) = (char::from_u32(a), char::from_u32(b)) {
+                ranges.push((a, b));
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Inst {
+    Char(char),
+    /// One character of the case folding of a literal, in a case-insensitive regex. A character of
+    /// the text matches a run of these that spells out its own folding, so `ß` matches the two
+    /// that `ss` folds to, and `ẞ` matches the two that `ß` folds to.
+    Folded(char),
+    Any,
+    Class(Class),
+    Assert(Assertion),
+    /// Try both targets, preferring the first.
+    Split(usize, usize),
+    Jump(usize),
+    Save(usize),
+    Match,
+}
+
+struct Compiler {
+    prog: Vec<Inst>,
+    case_insensitive: bool,
+}
+
+impl Compiler {
+    fn push(&mut self, inst: Inst) -> Result<usize, Error> {
+        if self.prog.len() >= MAX_PROGRAM_LEN {
+            return Err(Error {
+                message: String::from("pattern is too large"),
+                position: 0,
+            });
+        }
+        self.prog.push(inst);
+        Ok(self.prog.len() - 1)
+    }
+
+    fn compile(&mut self, node: &Node) -> Result<(), Error> {
+        match node {
+            Node::Empty => {}
+            Node::Literal(c) if self.case_insensitive => {
+                let mut folded = String::new();
+                case::fold_char(*c, &mut folded);
+                for f in folded.chars() {
+                    self.push(Inst::Folded(f))?;
+                }
+            }
+            Node::Literal(c) => {
+                self.push(Inst::Char(*c))?;
+            }
+            Node::Any => {
+                self.push(Inst::Any)?;
+            }
+            Node::Class(class) => {
+                self.push(Inst::Class(class.clone()))?;
+            }
+            Node::Assert(assertion) => {
+                self.push(Inst::Assert(*assertion))?;
+            }
+            Node::Group(inner, index) => match index {
+                Some(i) => {
+                    self.push(Inst::Save(2 * i))?;
+                    self.compile(inner)?;
+                    self.push(Inst::Save(2 * i + 1))?;
+                }
+                None => self.compile(inner)?,
+            },
+            Node::Concat(items) => {
+                for item in items {
+                    self.compile(item)?;
+                }
+            }
+            Node::Alternate(alternatives) => {
+                let mut jumps = Vec::new();
+                for (i, alternative) in alternatives.iter().enumerate() {
+                    if i + 1 < alternatives.len() {
+                        let split = self.push(Inst::Split(0, 0))?;
+                        self.compile(alternative)?;
+                        jumps.push(self.push(Inst::Jump(0))?);
+                        self.prog[split] = Inst::Split(split + 1, self.prog.len());
+                    } else {
+                        self.compile(alternative)?;
+                    }
+                }
+                let end = self.prog.len();
+                for jump in jumps {
+                    self.prog[jump] = Inst::Jump(end);
+                }
+            }
+            Node::Repeat {
+                node,
+                min,
+                max,
+                greedy,
+            } => {
+                for _ in 0..*min {
+                    self.compile(node)?;
+                }
+                match max {
+                    None => {
+                        let split = self.push(Inst::Split(0, 0))?;
+                        self.compile(node)?;
+                        self.push(Inst::Jump(split))?;
+                        let end = self.prog.len();
+                        self.prog[split] = self.split(split + 1, end, *greedy);
+                    }
+                    Some(max) => {
+                        let mut splits = Vec::new();
+                        for _ in *min..*max {
+                            splits.push(self.push(Inst::Split(0, 0))?);
+                            self.compile(node)?;
+                        }
+                        let end = self.prog.len();
+                        for split in splits {
+                            self.prog[split] = self.split(split + 1, end, *greedy);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn split(&self, body: usize, exit: usize, greedy: bool) -> Inst {
+        if greedy {
+            Inst::Split(body, exit)
+        } else {
+            Inst::Split(exit, body)
+        }
+    }
+}
+
+/// Where the VM is in the text, with the characters on either side for the assertions.
+struct Position {
+    at: usize,
+    prev: Option<char>,
+    next: Option<char>,
+}
+
+/// The same characters `\w` matches, so `\b` falls where a run of `\w` starts or ends.
+fn is_word_char(c: Option<char>) -> bool {
+    c.is_some_and(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl Assertion {
+    fn holds(self, p: &Position) -> bool {
+        match self {
+            Assertion::Start => p.prev.is_none(),
+            Assertion::End => p.next.is_none(),
+            Assertion::WordBoundary => is_word_char(p.prev) != is_word_char(p.next),
+            Assertion::NotWordBoundary => is_word_char(p.prev) == is_word_char(p.next),
+        }
+    }
+}
+
+type Slots = Vec<Option<usize>>;
+
+/// The threads alive at one position, in priority order, with at most one thread per instruction.
+struct Threads {
+    seen: Vec<bool>,
+    list: Vec<(usize, Slots)>,
+}
+
+impl Threads {
+    fn new(len: usize) -> Threads {
+        Threads {
+            seen: vec![false; len],
+            list: Vec::new(),
+        }
+    }
+
+    fn clear(&mut self) {
+        for seen in self.seen.iter_mut() {
+            *seen = false;
+        }
+        self.list.clear();
+    }
+}
+
+/// A compiled regular expression.
+#[derive(Debug, Clone)]
+pub struct Regex {
+    prog: Vec<Inst>,
+    groups: usize,
+    anchored: bool,
+    case_insensitive: bool,
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex, Error> {
+        Regex::build(pattern, false)
+    }
+
+    /// Like `new`, but letters match regardless of case. Literal characters are compared by their
+    /// full case folding, as literal searches are, so `straße` matches `STRASSE`. A class matches a
+    /// single character, so `stra[ß]e` doesn't.
+    pub fn new_case_insensitive(pattern: &str) -> Result<Regex, Error> {
+        Regex::build(pattern, true)
+    }
+
+    fn build(pattern: &str, case_insensitive: bool) -> Result<Regex, Error> {
+        let parser = Parser {
+            chars: pattern.chars().collect(),
+            pos: 0,
+            groups: 0,
+        };
+        let (node, groups) = parser.parse()?;
+
+        let anchored = match &node {
+            Node::Assert(Assertion::Start) => true,
+            Node::Concat(items) => matches!(items.first(), Some(Node::Assert(Assertion::Start))),
+            _ => false,
+        };
+
+        let mut compiler = Compiler {
+            prog: Vec::new(),
+            case_insensitive,
+        };
+        compiler.push(Inst::Save(0))?;
+        compiler.compile(&node)?;
+        compiler.push(Inst::Save(1))?;
+        compiler.push(Inst::Match)?;
+
+        Ok(Regex {
+            prog: compiler.prog,
+            groups,
+            anchored,
+            case_insensitive,
+        })
+    }
+
+    /// Number of capture groups, not counting the implicit group 0 for the whole match.
+    pub fn captures_len(&self) -> usize {
+        self.groups
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        self.exec(text, 0, true).is_some()
+    }
+
+    /// The leftmost match starting at or after byte offset `start`.
+    pub fn find_at(&self, text: &str, start: usize) -> Option<(usize, usize)> {
+        self.exec(text, start, false)
+            .map(|slots| (slots[0].unwrap(), slots[1].unwrap()))
+    }
+
+    /// All non-overlapping matches, left to right.
+    pub fn find_iter(&self, text: &str) -> Vec<(usize, usize)> {
+        let mut matches = Vec::new();
+        let mut start = 0;
+        while start <= text.len() {
+            let (s, e) = match self.find_at(text, start) {
+                Some(m) => m,
+                None => break,
+            };
+            matches.push((s, e));
+            start = if e > s {
+                e
+            } else {
+                match text[e..].chars().next() {
+                    Some(c) => e + c.len_utf8(),
+                    None => break,
+                }
+            };
+        }
+        matches
+    }
+
+    /// The spans of the whole match and of every group for the leftmost match at or after `start`.
+    /// Groups that didn't take part in the match are `None`.
+    pub fn captures_at(&self, text: &str, start: usize) -> Option<Vec<Option<(usize, usize)>>> {
+        let slots = self.exec(text, start, false)?;
+        Some(
+            slots
+                .chunks(2)
+                .map(|pair| match (pair[0], pair[1]) {
+                    (Some(s), Some(e)) => Some((s, e)),
+                    _ => None,
+                })
+                .collect(),
+        )
+    }
+
+    fn add_thread(&self, threads: &mut Threads, pc: usize, slots: Slots, p: &Position) {
+        let mut stack = vec![(pc, slots)];
+        while let Some((pc, mut slots)) = stack.pop() {
+            if threads.seen[pc] {
+                continue;
+            }
+            threads.seen[pc] = true;
+
+            match &self.prog[pc] {
+                Inst::Jump(to) => stack.push((*to, slots)),
+                Inst::Split(first, second) => {
+                    // Pushed in reverse so the preferred branch is explored first.
+                    stack.push((*second, slots.clone()));
+                    stack.push((*first, slots));
+                }
+                Inst::Save(slot) => {
+                    slots[*slot] = Some(p.at);
+                    stack.push((pc + 1, slots));
+                }
+                Inst::Assert(assertion) => {
+                    if assertion.holds(p) {
+                        stack.push((pc + 1, slots));
+                    }
+                }
+                _ => threads.list.push((pc, slots)),
+            }
+        }
+    }
+
+    /// Where a thread at the `Inst::Folded` at `pc` goes once the text character that folds to
+    /// `folded` is matched against it, if it matches.
+    fn match_folded(&self, pc: usize, folded: &str) -> Option<usize> {
+        let mut to = pc;
+        for f in folded.chars() {
+            match self.prog.get(to) {
+                Some(Inst::Folded(expected)) if *expected == f => to += 1,
+                _ => return None,
+            }
+        }
+        Some(to)
+    }
+
+    fn exec(&self, text: &str, start: usize, earliest: bool) -> Option<Slots> {
+        let slot_count = 2 * (self.groups + 1);
+        let mut current = Threads::new(self.prog.len());
+        let mut next = Threads::new(self.prog.len());
+        let mut matched: Option<Slots> = None;
+
+        let mut at = start;
+        let mut prev = text[..start].chars().next_back();
+
+        loop {
+            let c = text[at..].chars().next();
+            let position = Position { at, prev, next: c };
+
+            if matched.is_none() && (!self.anchored || at == 0) {
+                self.add_thread(&mut current, 0, vec![None; slot_count], &position);
+            }
+            if current.list.is_empty() && (matched.is_some() || self.anchored) {
+                break;
+            }
+
+            let after = Position {
+                at: at + c.map_or(0, |c| c.len_utf8()),
+                prev: c,
+                next: c.and_then(|c| text[at + c.len_utf8()..].chars().next()),
+            };
+
+            let mut folded = String::new();
+            if let (true, Some(c)) = (self.case_insensitive, c) {
+                case::fold_char(c, &mut folded);
+            }
+
+            let list = std::mem::take(&mut current.list);
+            for (pc, slots) in list {
+                let advance = match (&self.prog[pc], c) {
+                    (Inst::Match, _) => {
+                        matched = Some(slots);
+                        if earliest {
+                            return matched;
+                        }
+                        // Every thread after this one has lower priority.
+                        break;
+                    }
+                    (Inst::Char(expected), Some(c)) if *expected == c => Some(pc + 1),
+                    (Inst::Folded(_), Some(_)) => self.match_folded(pc, &folded),
+                    (Inst::Any, Some(c)) if c != '\n' => Some(pc + 1),
+                    (Inst::Class(class), Some(c)) if class.matches(c, self.case_insensitive) => {
+                        Some(pc + 1)
+                    }
+                    _ => None,
+                };
+                if let Some(to) = advance {
+                    self.add_thread(&mut next, to, slots, &after);
+                }
+            }
+
+            if c.is_none() {
+                break;
+            }
+            at = after.at;
+            prev = c;
+            current.clear();
+            std::mem::swap(&mut current, &mut next);
+        }
+        matched
+    }
+}
+
+/// Strings that every match of `pattern` contains, for ruling out text without them before
+/// running the regex. These are the runs of literal characters in the pattern's top-level
+/// sequence, looking into groups and into repetitions that must happen at least once. A pattern
+/// that doesn't parse has none.
+pub fn required_literals(pattern: &str) -> Vec<String> {
+    let parser = Parser {
+        chars: pattern.chars().collect(),
+        pos: 0,
+        groups: 0,
+    };
+    let mut literals = Vec::new();
+    if let Ok((node, _)) = parser.parse() {
+        let mut run = String::new();
+        collect_literals(&node, &mut run, &mut literals);
+        end_run(&mut run, &mut literals);
+    }
+    literals
+}
+
+fn collect_literals(node: &Node, run: &mut String, literals: &mut Vec<String>) {
+    match node {
+        Node::Literal(c) => run.push(*c),
+        Node::Concat(items) => {
+            for item in items {
+                collect_literals(item, run, literals);
+            }
+        }
+        Node::Group(inner, _) => collect_literals(inner, run, literals),
+        // Assertions match nothing, so the literals either side of one are still adjacent.
+        Node::Assert(_) | Node::Empty => {}
+        Node::Repeat { node, min, .. } if *min > 0 => {
+            // One copy is certain, but not what's next to it.
+            end_run(run, literals);
+            collect_literals(node, run, literals);
+            end_run(run, literals);
+        }
+        _ => end_run(run, literals),
+    }
+}
+
+fn end_run(run: &mut String, literals: &mut Vec<String>) {
+    if !run.is_empty() {
+        literals.push(std::mem::take(run));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(pattern: &str, text: &str) -> Option<(usize, usize)> {
+        Regex::new(pattern).unwrap().find_at(text, 0)
+    }
+
+    #[test]
+    fn literals_classes_and_anchors() {
+        assert_eq!(find("duct", "safe, fast, productive."), Some((15, 19)));
+        assert_eq!(find("[0-9]+", "abc 123 def"), Some((4, 7)));
+        assert_eq!(find("[^a-z ]", "abc 123"), Some((4, 5)));
+        assert_eq!(find(r"\d{2,3}", "a 12345"), Some((2, 5)));
+        assert_eq!(find("^Rust", "Trust me"), None);
+        assert_eq!(find("me$", "Trust me"), Some((6, 8)));
+        assert_eq!(find(r"\bfast\b", "fastest, fast"), Some((9, 13)));
+    }
+
+    #[test]
+    fn alternation_groups_and_repetition() {
+        assert_eq!(find("cat|dog", "hotdog"), Some((3, 6)));
+        assert_eq!(find("(?:ab)+", "xxababa"), Some((2, 6)));
+        assert_eq!(find("a.*?b", "aXbYb"), Some((0, 3)));
+        assert_eq!(find("a.*b", "aXbYb"), Some((0, 5)));
+        assert_eq!(find("colou?r", "color"), Some((0, 5)));
+        assert_eq!(find("(a*)*b", "aaab"), Some((0, 4)));
+    }
+
+    #[test]
+    fn captures_and_iteration() {
+        let re = Regex::new(r"(\w+)@(\w+)").unwrap();
+        assert_eq!(
+            re.captures_at("mail ann@example now", 0),
+            Some(vec![Some((5, 16)), Some((5, 8)), Some((9, 16))])
+        );
+
+        let words = Regex::new(r"\w+").unwrap();
+        assert_eq!(words.find_iter("one, two"), vec![(0, 3), (5, 8)]);
+    }
+
+    #[test]
+    fn case_insensitive_and_unicode() {
+        let re = Regex::new_case_insensitive("rust").unwrap();
+        assert!(re.is_match("Trust me."));
+        assert_eq!(find("é.", "café!"), Some((3, 6)));
+        assert_eq!(find(r"\w+\b", "café"), Some((0, 3)));
+        assert_eq!(find(r"\bé", "café"), Some((3, 5)));
+
+        let sharp_s = Regex::new_case_insensitive("ß").unwrap();
+        assert!(sharp_s.is_match("STRAẞE"));
+        assert!(!sharp_s.is_match("Str"));
+        assert!(!Regex::new_case_insensitive("s").unwrap().is_match("ß"));
+        assert!(!Regex::new_case_insensitive("i").unwrap().is_match("İ"));
+
+        let strasse = Regex::new_case_insensitive("straße").unwrap();
+        assert_eq!(strasse.find_at("the STRASSE", 0), Some((4, 11)));
+        assert_eq!(strasse.find_at("strasse", 0), Some((0, 7)));
+        let strasse = Regex::new_case_insensitive(r"\bstrasse\b").unwrap();
+        assert_eq!(strasse.find_at("Straße", 0), Some((0, 7)));
+        assert!(Regex::new_case_insensitive("(?:ss)+").unwrap().is_match("ßSS"));
+        assert!(Regex::new_case_insensitive("ΣΑΣ").unwrap().is_match("σας"));
+    }
+
+    #[test]
+    fn literals_every_match_contains() {
+        assert_eq!(required_literals(r"^fn (\w+)_test\b"), vec!["fn ", "_test"]);
+        assert_eq!(required_literals("(?:ab)+c|d"), Vec::<String>::new());
+        assert_eq!(required_literals("x(ab)+y?z"), vec!["x", "ab", "z"]);
+        assert_eq!(required_literals("colou?r"), vec!["colo", "r"]);
+        assert_eq!(required_literals("(abc"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn reports_syntax_errors() {
+        for pattern in &["(abc", "abc)", "[a-", "*a", "a{5,2}", r"\q", "a{2000}"] {
+            assert!(Regex::new(pattern).is_err(), "{} should not compile", pattern);
+        }
+
+        let nested = format!("{}a{}", "(".repeat(3000), ")".repeat(3000));
+        let starred = format!("a{}", "*".repeat(3000));
+        let both = format!("{}a*{}", "(".repeat(150), ")*".repeat(150));
+        for pattern in &[nested, starred, both] {
+            let error = Regex::new(pattern).unwrap_err();
+            assert_eq!(error.message, "pattern nested too deeply");
+            assert!(required_literals(pattern).is_empty());
+        }
+        assert!(Regex::new(&format!("{}a{}", "(".repeat(50), ")*".repeat(50))).is_ok());
+        assert_eq!(find("a{b", "a{b"), Some((0, 3)));
+    }
+}
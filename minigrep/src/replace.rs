@@ -0,0 +1,418 @@
+//! Rewriting matches for `--replace`.
+//!
+//! A replacement is a template in which `$N` or `${N}` stands for capture group N of the match
+//! (`$0` being the whole match) and `$$` for a literal `$`. Any other `$` is kept as it is, and a
+//! group that didn't take part in the match is replaced by nothing.
+//!
+//! Besides printing lines with their matches replaced, whole files can be rewritten: `--dry-run`
+//! prints a unified diff of the changes, and `--in-place` writes each changed file to a temporary
+//! file next to it and renames that over the original, so a file is never left half written.
+
+use crate::matcher::Matcher;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Unchanged lines shown around each change in a diff.
+const DIFF_CONTEXT: usize = 3;
+
+#[derive(Debug, PartialEq)]
+enum Part {
+    Literal(String),
+    Group(usize),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Template {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = template;
+
+        while let Some(i) = rest.find('$') {
+            literal.push_str(&rest[..i]);
+            let after = &rest[i + 1..];
+            let (group, consumed) = if let Some(braced) = after.strip_prefix('{') {
+                match braced.find('}') {
+                    Some(end) => (braced[..end].parse().ok(), end + 2),
+                    None => (None, 0),
+                }
+            } else {
+                let digits = after.len() - after.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+                (after[..digits].parse().ok(), digits)
+            };
+
+            match group {
+                Some(group) => {
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Group(group));
+                    rest = &after[consumed..];
+                }
+                None if after.starts_with('$') => {
+                    literal.push('$');
+                    rest = &after[1..];
+                }
+                None => {
+                    literal.push('$');
+                    rest = after;
+                }
+            }
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Template { parts }
+    }
+
+    fn expand(&self, line: &str, groups: &[Option<(usize, usize)>], into: &mut String) {
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => into.push_str(text),
+                Part::Group(n) => {
+                    if let Some(Some((s, e))) = groups.get(*n) {
+                        into.push_str(&line[*s..*e]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// `line` with every match replaced, or `None` if nothing matched. Also returns where each
+/// replacement ended up in the new line, for highlighting.
+pub fn replace_line(
+    matcher: &Matcher,
+    template: &Template,
+    line: &str,
+) -> Option<(String, Vec<(usize, usize)>)> {
+    let matches = matcher.captures_iter(line);
+    if matches.is_empty() {
+        return None;
+    }
+
+    let mut replaced = String::with_capacity(line.len());
+    let mut spans = Vec::with_capacity(matches.len());
+    let mut last = 0;
+    for groups in &matches {
+        let (s, e) = groups[0].expect("group 0 is the whole match");
+        replaced.push_str(&line[last..s]);
+        let start = replaced.len();
+        template.expand(line, groups, &mut replaced);
+        spans.push((start, replaced.len()));
+        last = e;
+    }
+    replaced.push_str(&line[last..]);
+    Some((replaced, spans))
+}
+
+/// Go through `reader` line by line, calling `each` with the line as read (terminator included) and
+/// its replacement, if anything in it matched. Returns how many lines were changed.
+///
+/// Lines that aren't valid UTF-8 are never changed: replacing in the lossy text would write U+FFFD
+/// over the bytes that couldn't be decoded. Binary input isn't changed at all.
+fn rewrite_lines<R, F>(matcher: &Matcher, template: &Template, mut reader: R, mut each: F) -> io::Result<usize>
+where
+    R: BufRead,
+    F: FnMut(&[u8], Option<&[u8]>) -> io::Result<()>,
+{
+    if crate::is_binary(reader.fill_buf()?) {
+        return Ok(0);
+    }
+
+    let mut buffer = Vec::new();
+    let mut replaced = Vec::new();
+    let mut changed = 0;
+    loop {
+        buffer.clear();
+        if reader.read_until(b'\n', &mut buffer)? == 0 {
+            return Ok(changed);
+        }
+        let mut end = buffer.len();
+        if buffer[..end].ends_with(b"\n") {
+            end -= 1;
+        }
+        if buffer[..end].ends_with(b"\r") {
+            end -= 1;
+        }
+
+        let line = match std::str::from_utf8(&buffer[..end]) {
+            Ok(line) => line,
+            Err(_) => {
+                each(&buffer, None)?;
+                continue;
+            }
+        };
+        match replace_line(matcher, template, line) {
+            Some((text, _)) if text != line => {
+                replaced.clear();
+                replaced.extend_from_slice(text.as_bytes());
+                replaced.extend_from_slice(&buffer[end..]);
+                changed += 1;
+                each(&buffer, Some(&replaced))?;
+            }
+            _ => each(&buffer, None)?,
+        }
+    }
+}
+
+/// Replace matches in the file at `path` and return how many lines changed.
+///
+/// The new contents go to a temporary file in the same directory, which is given the original's
+/// permissions, synced and then renamed over it, so anything reading the file sees either all of
+/// the old contents or all of the new. If nothing changed the file isn't touched.
+pub fn in_place(matcher: &Matcher, template: &Template, path: &Path) -> io::Result<usize> {
+    // Rewrite what a symlink points to, rather than replacing the link with a regular file.
+    let path = fs::canonicalize(path)?;
+    let permissions = fs::metadata(&path)?.permissions();
+    let temp = temp_path(&path);
+    let file = OpenOptions::new().write(true).create_new(true).open(&temp)?;
+
+    let result = (|| -> io::Result<usize> {
+        let reader = BufReader::new(File::open(&path)?);
+        let mut out = BufWriter::new(file);
+        let changed = rewrite_lines(matcher, template, reader, |line, replaced| {
+            out.write_all(replaced.unwrap_or(line))
+        })?;
+        let file = out.into_inner().map_err(|e| e.into_error())?;
+        file.set_permissions(permissions)?;
+        file.sync_all()?;
+        Ok(changed)
+    })();
+
+    let result = match result {
+        Ok(changed) if changed > 0 => fs::rename(&temp, &path).map(|_| changed),
+        other => other,
+    };
+    if result.as_ref().map_or(true, |&changed| changed == 0) {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.minigrep-{}.tmp", name, process::id()))
+}
+
+/// Print a unified diff of the replacements in `reader` to `out`, headed with `path`. Returns how
+/// many lines would change.
+pub fn diff<R: BufRead, W: Write>(
+    matcher: &Matcher,
+    template: &Template,
+    path: &str,
+    reader: R,
+    out: &mut W,
+) -> io::Result<usize> {
+    let mut diff = Diff {
+        out,
+        path,
+        number: 0,
+        before: VecDeque::new(),
+        skipped: false,
+        hunk: None,
+        header_written: false,
+        after_left: 0,
+        added_so_far: 0,
+    };
+    let changed = rewrite_lines(matcher, template, reader, |line, replaced| diff.line(line, replaced))?;
+    diff.close_hunk()?;
+    Ok(changed)
+}
+
+struct Hunk {
+    old_start: usize,
+    old_len: usize,
+    new_start: usize,
+    new_len: usize,
+    lines: Vec<u8>,
+}
+
+/// Builds hunks as lines stream past, keeping only the unchanged lines that might still become
+/// context for a change further on.
+struct Diff<'a, W: Write> {
+    out: &'a mut W,
+    path: &'a str,
+    number: usize,
+    before: VecDeque<Vec<u8>>,
+    /// Whether unchanged lines have been dropped from `before` since the current hunk's last line.
+    skipped: bool,
+    hunk: Option<Hunk>,
+    header_written: bool,
+    after_left: usize,
+    /// How many more lines the new file has than the old one so far, when replacements add lines.
+    added_so_far: isize,
+}
+
+impl<'a, W: Write> Diff<'a, W> {
+    fn line(&mut self, line: &[u8], replaced: Option<&[u8]>) -> io::Result<()> {
+        self.number += 1;
+        match replaced {
+            Some(replaced) => self.changed(line, replaced),
+            None => {
+                self.unchanged(line);
+                Ok(())
+            }
+        }
+    }
+
+    fn unchanged(&mut self, line: &[u8]) {
+        let after_left = self.after_left;
+        if let Some(hunk) = self.hunk.as_mut().filter(|_| after_left > 0) {
+            self.after_left -= 1;
+            push_diff_line(&mut hunk.lines, b' ', line);
+            hunk.old_len += 1;
+            hunk.new_len += 1;
+            return;
+        }
+        if self.before.len() == DIFF_CONTEXT {
+            self.before.pop_front();
+            self.skipped = true;
+        }
+        self.before.push_back(line.to_vec());
+    }
+
+    fn changed(&mut self, line: &[u8], replaced: &[u8]) -> io::Result<()> {
+        if self.hunk.is_some() && self.skipped {
+            self.close_hunk()?;
+        }
+        let first = self.number - self.before.len();
+        let added_so_far = self.added_so_far;
+        let hunk = self.hunk.get_or_insert_with(|| Hunk {
+            old_start: first,
+            old_len: 0,
+            new_start: (first as isize + added_so_far) as usize,
+            new_len: 0,
+            lines: Vec::new(),
+        });
+        for context in self.before.drain(..) {
+            push_diff_line(&mut hunk.lines, b' ', &context);
+            hunk.old_len += 1;
+            hunk.new_len += 1;
+        }
+        push_diff_line(&mut hunk.lines, b'-', line);
+        hunk.old_len += 1;
+        // A replacement containing newlines turns one line into several.
+        let new_lines = replaced.split_inclusive(|&b| b == b'\n').count();
+        for new_line in replaced.split_inclusive(|&b| b == b'\n') {
+            push_diff_line(&mut hunk.lines, b'+', new_line);
+        }
+        hunk.new_len += new_lines;
+        self.added_so_far += new_lines as isize - 1;
+        self.skipped = false;
+        self.after_left = DIFF_CONTEXT;
+        Ok(())
+    }
+
+    fn close_hunk(&mut self) -> io::Result<()> {
+        let hunk = match self.hunk.take() {
+            Some(hunk) => hunk,
+            None => return Ok(()),
+        };
+        if !self.header_written {
+            writeln!(self.out, "--- {}", self.path)?;
+            writeln!(self.out, "+++ {}", self.path)?;
+            self.header_written = true;
+        }
+        writeln!(
+            self.out,
+            "@@ -{},{} +{},{} @@",
+            hunk.old_start, hunk.old_len, hunk.new_start, hunk.new_len
+        )?;
+        self.out.write_all(&hunk.lines)
+    }
+}
+
+fn push_diff_line(lines: &mut Vec<u8>, marker: u8, line: &[u8]) {
+    lines.push(marker);
+    lines.extend_from_slice(line);
+    if !line.ends_with(b"\n") {
+        lines.extend_from_slice(b"\n\\ No newline at end of file\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli;
+
+    fn matcher(args: &[&str]) -> Matcher {
+        let config = cli::parse(args.iter().map(|s| s.to_string()).chain(Some(String::from("-"))))
+            .unwrap();
+        Matcher::new(&config).unwrap()
+    }
+
+    #[test]
+    fn parses_templates() {
+        assert_eq!(
+            Template::parse("$1-${22}$$x$y$"),
+            Template {
+                parts: vec![
+                    Part::Group(1),
+                    Part::Literal(String::from("-")),
+                    Part::Group(22),
+                    Part::Literal(String::from("$x$y$")),
+                ]
+            }
+        );
+    }
+
+    #[test]
+    fn replaces_with_capture_groups() {
+        let dates = matcher(&["--regex", r"(\d+)/(\d+)"]);
+        let template = Template::parse("$2.$1");
+
+        assert_eq!(
+            replace_line(&dates, &template, "from 12/3 to 4/56"),
+            Some((String::from("from 3.12 to 56.4"), vec![(5, 9), (13, 17)]))
+        );
+        assert_eq!(replace_line(&dates, &template, "no dates"), None);
+    }
+
+    #[test]
+    fn diffs_with_context_and_merged_hunks() {
+        let frog = matcher(&["frog"]);
+        let template = Template::parse("toad");
+        let text = "frog\n1\n2\n3\n4\n5\n6\nfrog\n7\n8\n9\n10\n11\n12\n13\nfrog";
+
+        let mut out = Vec::new();
+        let changed = diff(&frog, &template, "pond.txt", text.as_bytes(), &mut out).unwrap();
+
+        assert_eq!(changed, 3);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "--- pond.txt\n+++ pond.txt\n\
+             @@ -1,11 +1,11 @@\n-frog\n+toad\n 1\n 2\n 3\n 4\n 5\n 6\n-frog\n+toad\n 7\n 8\n 9\n\
+             @@ -13,4 +13,4 @@\n 11\n 12\n 13\n-frog\n\\ No newline at end of file\n\
+             +toad\n\\ No newline at end of file\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rewrites_in_place_keeping_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("minigrep-replace-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("script.sh");
+        fs::write(&path, b"echo frog\r\necho \xff frog\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o750)).unwrap();
+
+        let changed = in_place(&matcher(&["frog"]), &Template::parse("toad"), &path).unwrap();
+
+        assert_eq!(changed, 1);
+        assert_eq!(fs::read(&path).unwrap(), b"echo toad\r\necho \xff frog\n");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o750);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
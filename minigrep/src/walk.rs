@@ -0,0 +1,285 @@
+//! Expanding the paths given on the command line into the files to search.
+//!
+//! Directories are walked recursively in sorted order. Each directory may hold `.gitignore` or
+//! `.ignore` files whose rules apply to everything below it, using the usual gitignore syntax:
+//! `#` comments, `!` to re-include, a trailing `/` for directories only, a `/` anywhere else to
+//! anchor the pattern to the ignore file's directory, and the globs `*`, `?`, `[...]` and `**`.
+//! The last matching rule wins, and rules in deeper directories override those above them.
+
+use crate::regex::Regex;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".ignore"];
+
+struct Rule {
+    regex: Regex,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+impl Rule {
+    fn parse(line: &str) -> Option<Rule> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let (negated, pattern) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (dir_only, pattern) = match pattern.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+        let anchored = pattern.contains('/');
+        let pattern = pattern.trim_start_matches('/');
+        if pattern.is_empty() {
+            return None;
+        }
+
+        Some(Rule {
+            regex: Regex::new(&glob_to_regex(pattern)).ok()?,
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+
+    /// `relative` is the path below the ignore file's directory, with `/` separators.
+    fn matches(&self, relative: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            self.regex.is_match(relative)
+        } else {
+            let name = relative.rsplit('/').next().unwrap_or(relative);
+            self.regex.is_match(name)
+        }
+    }
+}
+
+/// Translate a gitignore glob into an anchored regex over a `/`-separated path.
+fn glob_to_regex(glob: &str) -> String {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut regex = String::from("^");
+    let mut i = 0;
+
+    while i < chars.len() {
+        match chars[i] {
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                let at_start = i == 0 || chars[i - 1] == '/';
+                let slash_follows = chars.get(i + 2) == Some(&'/');
+                if at_start && slash_follows {
+                    // `**/` matches zero or more leading directories.
+                    regex.push_str("(?:.*/)?");
+                    i += 3;
+                } else {
+                    regex.push_str(".*");
+                    i += 2;
+                }
+                continue;
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => match chars[i + 1..].iter().position(|&c| c == ']') {
+                Some(len) if len > 0 => {
+                    let body: String = chars[i + 1..i + 1 + len].iter().collect();
+                    let body = match body.strip_prefix('!') {
+                        Some(rest) => format!("^{}", rest),
+                        None => body,
+                    };
+                    regex.push('[');
+                    regex.push_str(&body.replace('\\', "\\\\"));
+                    regex.push(']');
+                    i += len + 2;
+                    continue;
+                }
+                _ => regex.push_str("\\["),
+            },
+            '\\' if i + 1 < chars.len() => {
+                i += 1;
+                push_literal(&mut regex, chars[i]);
+            }
+            c => push_literal(&mut regex, c),
+        }
+        i += 1;
+    }
+
+    regex.push('$');
+    regex
+}
+
+fn push_literal(regex: &mut String, c: char) {
+    if !c.is_alphanumeric() && c != '_' && c != ' ' {
+        regex.push('\\');
+    }
+    regex.push(c);
+}
+
+struct IgnoreFile {
+    base: PathBuf,
+    rules: Vec<Rule>,
+}
+
+impl IgnoreFile {
+    fn load(dir: &Path) -> Vec<IgnoreFile> {
+        IGNORE_FILES
+            .iter()
+            .filter_map(|name| fs::read_to_string(dir.join(name)).ok())
+            .map(|contents| IgnoreFile {
+                base: dir.to_path_buf(),
+                rules: contents.lines().filter_map(Rule::parse).collect(),
+            })
+            .collect()
+    }
+}
+
+fn is_ignored(ignores: &[IgnoreFile], path: &Path, is_dir: bool) -> bool {
+    let mut ignored = false;
+    for file in ignores {
+        let relative = match path.strip_prefix(&file.base) {
+            Ok(relative) => relative,
+            Err(_) => continue,
+        };
+        let relative = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        for rule in &file.rules {
+            if rule.matches(&relative, is_dir) {
+                ignored = !rule.negated;
+            }
+        }
+    }
+    ignored
+}
+
+/// Every file to search for the given paths, in order. Files named explicitly, and `-` for standard
+/// input, are always included; directories contribute the files below them that aren't ignored.
+///
+/// A path that doesn't exist, or a directory that can't be listed, is included as it is, so that
+/// reading it reports the problem in its turn and the other paths are still searched.
+pub fn files(paths: &[String]) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for path in paths {
+        let path = PathBuf::from(path);
+        if path == Path::new(crate::STDIN_PATH) {
+            files.push(path);
+        } else if fs::metadata(&path).is_ok_and(|m| m.is_dir()) {
+            let mut ignores = Vec::new();
+            walk_dir(&path, &mut ignores, &mut files);
+        } else {
+            files.push(path);
+        }
+    }
+    files
+}
+
+fn walk_dir(dir: &Path, ignores: &mut Vec<IgnoreFile>, files: &mut Vec<PathBuf>) {
+    let loaded = IgnoreFile::load(dir);
+    let loaded_count = loaded.len();
+    ignores.extend(loaded);
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => {
+            files.push(dir.to_path_buf());
+            ignores.truncate(ignores.len() - loaded_count);
+            return;
+        }
+    };
+    let mut entries: Vec<PathBuf> = entries.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+    entries.sort();
+
+    for path in entries {
+        // Symlinked directories aren't followed, which rules out loops.
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        let is_dir = metadata.is_dir();
+        if path.file_name().is_some_and(|name| name == ".git" || name == crate::index::INDEX_FILE) {
+            continue;
+        }
+        if is_ignored(ignores, &path, is_dir) {
+            continue;
+        }
+
+        if is_dir {
+            walk_dir(&path, ignores, files);
+        } else if metadata.is_file() || fs::metadata(&path).is_ok_and(|m| m.is_file()) {
+            files.push(path);
+        }
+    }
+
+    ignores.truncate(ignores.len() - loaded_count);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ignored(rules: &str, path: &str, is_dir: bool) -> bool {
+        let file = IgnoreFile {
+            base: PathBuf::from("/repo"),
+            rules: rules.lines().filter_map(Rule::parse).collect(),
+        };
+        is_ignored(&[file], &Path::new("/repo").join(path), is_dir)
+    }
+
+    #[test]
+    fn globs_match_names_at_any_depth() {
+        assert!(ignored("*.log", "debug.log", false));
+        assert!(ignored("*.log", "a/b/debug.log", false));
+        assert!(!ignored("*.log", "a/log", false));
+        assert!(ignored("debug?.txt", "debug1.txt", false));
+        assert!(ignored("[abc].rs", "src/b.rs", false));
+        assert!(!ignored("[!abc].rs", "src/b.rs", false));
+    }
+
+    #[test]
+    fn slashes_anchor_and_mark_directories() {
+        assert!(ignored("/target", "target", true));
+        assert!(!ignored("/target", "sub/target", true));
+        assert!(ignored("build/", "sub/build", true));
+        assert!(!ignored("build/", "sub/build", false));
+        assert!(ignored("docs/**/*.md", "docs/a/b/c.md", false));
+        assert!(ignored("docs/**/*.md", "docs/c.md", false));
+        assert!(ignored("**/cache", "a/b/cache", true));
+    }
+
+    #[test]
+    fn negation_re_includes_and_last_rule_wins() {
+        let rules = "*.log\n!keep.log\n# comment\n";
+        assert!(ignored(rules, "drop.log", false));
+        assert!(!ignored(rules, "keep.log", false));
+        assert!(ignored("!keep.log\n*.log", "keep.log", false));
+    }
+
+    #[test]
+    fn walks_directories_honouring_ignore_files() {
+        let root = std::env::temp_dir().join(format!("minigrep-walk-{}", std::process::id()));
+        fs::create_dir_all(root.join("src/generated")).unwrap();
+        fs::create_dir_all(root.join("target")).unwrap();
+        fs::write(root.join(".gitignore"), "target/\n*.tmp\n").unwrap();
+        fs::write(root.join("src/.ignore"), "generated\n").unwrap();
+        for file in &["a.txt", "b.tmp", "src/lib.rs", "src/generated/x.rs", "target/out.txt"] {
+            fs::write(root.join(file), "text").unwrap();
+        }
+
+        let found = files(&[root.to_string_lossy().to_string()]);
+        let found: Vec<_> = found
+            .iter()
+            .map(|p| p.strip_prefix(&root).unwrap().to_string_lossy().replace('\\', "/"))
+            .collect();
+
+        assert_eq!(found, vec![".gitignore", "a.txt", "src/.ignore", "src/lib.rs"]);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
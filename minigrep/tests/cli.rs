@@ -0,0 +1,121 @@
+//! Runs the built binary to check what scripts see: output, and grep's exit statuses.
+
+use std::process::{Command, Output};
+
+fn minigrep(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_minigrep"))
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> &str {
+    std::str::from_utf8(&output.stdout).unwrap()
+}
+
+#[test]
+fn exits_0_when_a_line_is_selected() {
+    let output = minigrep(&["frog", "poem.txt"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "How public, like a frog\n");
+}
+
+#[test]
+fn exits_1_when_nothing_is_selected() {
+    let output = minigrep(&["toad", "poem.txt"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+
+    assert_eq!(minigrep(&["-c", "toad", "poem.txt"]).status.code(), Some(1));
+    assert_eq!(minigrep(&["-v", "", "poem.txt"]).status.code(), Some(1));
+}
+
+#[test]
+fn exits_2_for_usage_errors() {
+    assert_eq!(minigrep(&[]).status.code(), Some(2));
+    assert_eq!(
+        minigrep(&["--no-such-option", "frog", "poem.txt"])
+            .status
+            .code(),
+        Some(2)
+    );
+    assert_eq!(
+        minigrep(&["-m", "many", "frog", "poem.txt"]).status.code(),
+        Some(2)
+    );
+    assert_eq!(minigrep(&["--help"]).status.code(), Some(0));
+}
+
+#[test]
+fn an_unreadable_file_is_an_error_even_if_others_match() {
+    let output = minigrep(&["frog", "poem.txt", "no-such-file.txt"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(stdout(&output), "poem.txt:How public, like a frog\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no-such-file.txt"));
+
+    assert_eq!(
+        minigrep(&["toad", "no-such-file.txt"]).status.code(),
+        Some(2)
+    );
+}
+
+#[test]
+fn quiet_prints_nothing_and_stops_at_the_first_match() {
+    let output = minigrep(&["-q", "frog", "poem.txt", "no-such-file.txt"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "");
+    assert!(output.stderr.is_empty());
+
+    assert_eq!(
+        minigrep(&["--quiet", "toad", "poem.txt"]).status.code(),
+        Some(1)
+    );
+    assert_eq!(
+        minigrep(&["--silent", "toad", "no-such-file.txt"])
+            .status
+            .code(),
+        Some(2)
+    );
+}
+
+#[test]
+fn max_count_stops_after_that_many_lines() {
+    let output = minigrep(&["-m", "2", "you", "poem.txt"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        stdout(&output),
+        "I'm nobody! Who are you?\nAre you nobody, too?\n"
+    );
+
+    assert_eq!(
+        stdout(&minigrep(&["-c", "--max-count=2", "o", "poem.txt"])),
+        "2\n"
+    );
+    assert_eq!(
+        minigrep(&["-m0", "frog", "poem.txt"]).status.code(),
+        Some(1)
+    );
+}
+
+#[test]
+fn max_count_still_prints_trailing_context() {
+    let output = minigrep(&["-m1", "-A1", "you", "poem.txt"]);
+    assert_eq!(
+        stdout(&output),
+        "I'm nobody! Who are you?\nAre you nobody, too?\n"
+    );
+
+    let output = minigrep(&["-m1", "-A2", "public", "poem.txt"]);
+    assert_eq!(
+        stdout(&output),
+        "How public, like a frog\nTo tell your name the livelong day\nTo an admiring bog!\n"
+    );
+}
+
+#[test]
+fn searches_compressed_fixtures() {
+    let output = minigrep(&["-c", "frog", "tests/fixtures/poem.txt.gz"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "1\n");
+}
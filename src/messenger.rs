@@ -1,38 +1,157 @@
+mod backends;
+mod background;
+mod policy;
+mod quota;
+mod store;
+
+pub use self::backends::{
+    FanoutMessenger, FileMessenger, SpoolMessenger, StderrMessenger, WebhookMessenger,
+};
+pub use self::background::{AsyncMessenger, Delivery};
+pub use self::policy::{Direction, Notification, Policy, Severity, Threshold};
+pub use self::quota::{QuotaError, QuotaRegistry, Usage, Window};
+pub use self::store::{Key, Saved, Store};
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
+
+/// Something a `Messenger` is asked to deliver.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outgoing {
+    Text(String),
+    Notification(Notification),
+}
+
+/// Why a batch wasn't delivered in full.
+#[derive(Debug)]
+pub struct BatchError {
+    /// How many messages at the start of the batch were delivered before the failure.
+    pub delivered: usize,
+    pub error: io::Error,
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} delivered, then: {}", self.delivered, self.error)
+    }
+}
+
+impl Error for BatchError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Somewhere to send notifications.
+///
+/// `send` and `notify` can't fail, so a messenger that can has to deal with failure itself. The
+/// `try_` methods report it instead, for callers such as `AsyncMessenger` that retry; messengers
+/// that can't fail can leave them be.
 pub trait Messenger {
     fn send(&self, msg: &str);
+
+    /// Delivers a threshold notification. Messengers that only deal in text get its message.
+    fn notify(&self, notification: &Notification) {
+        self.send(&notification.message);
+    }
+
+    fn try_send(&self, msg: &str) -> io::Result<()> {
+        self.send(msg);
+        Ok(())
+    }
+
+    fn try_notify(&self, notification: &Notification) -> io::Result<()> {
+        self.notify(notification);
+        Ok(())
+    }
+
+    /// Delivers several messages, by default one at a time, stopping at the first failure.
+    fn try_send_batch(&self, batch: &[Outgoing]) -> Result<(), BatchError> {
+        for (delivered, outgoing) in batch.iter().enumerate() {
+            match outgoing {
+                Outgoing::Text(msg) => self.try_send(msg),
+                Outgoing::Notification(notification) => self.try_notify(notification),
+            }
+            .map_err(|error| BatchError { delivered, error })?;
+        }
+        Ok(())
+    }
 }
 
+/// Tracks a value against a maximum, notifying a `Messenger` as the value crosses the thresholds
+/// of a `Policy`.
 pub struct LimitTracker<'a, T: Messenger> {
     messenger: &'a T,
     value: usize,
     max: usize,
+    policy: Policy,
+    store: Option<(&'a Mutex<Store>, Key)>,
 }
 
 impl<'a, T> LimitTracker<'a, T>
 where
     T: Messenger,
 {
-    pub fn new(messenger: &T, max: usize) -> LimitTracker<T> {
+    pub fn new(messenger: &'a T, max: usize) -> LimitTracker<'a, T> {
+        LimitTracker::with_policy(messenger, max, Policy::default())
+    }
+
+    pub fn with_policy(messenger: &'a T, max: usize, policy: Policy) -> LimitTracker<'a, T> {
         LimitTracker {
             messenger,
             value: 0,
             max,
+            policy,
+            store: None,
         }
     }
 
+    /// Keeps the value and which thresholds have fired in `store` under `name`, picking up where
+    /// a tracker of that name left off if there is one. A tracker that can't save says so on
+    /// stderr and carries on.
+    pub fn persist(mut self, store: &'a Mutex<Store>, name: &str) -> LimitTracker<'a, T> {
+        let key = Key::Tracker(String::from(name));
+        if let Some(saved) = store.lock().unwrap().get(&key) {
+            self.value = saved.value;
+            self.policy.restore(&saved.fired, self.value, self.max);
+        }
+        self.store = Some((store, key));
+        self
+    }
+
+    pub fn value(&self) -> usize {
+        self.value
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    /// Sets the value, notifying the messenger if that crossed a threshold.
     pub fn set_value(&mut self, value: usize) {
         self.value = value;
 
-        let percentage_of_max = self.value as f64 / self.max as f64;
-
-        if percentage_of_max >= 1.0 {
-            self.messenger.send("Error: You are over your quota!");
-        } else if percentage_of_max >= 0.9 {
-            self.messenger
-                .send("Urgent warning: You've used up over 90% of your quota!");
-        } else if percentage_of_max >= 0.75 {
-            self.messenger
-                .send("Warning: You've used up over 75% of your quota!");
+        let notification = self.policy.check(self.value, self.max);
+        // Save before notifying, so that a crash in between can't lead to a second notification.
+        if let Some((store, key)) = &self.store {
+            let saved = Saved {
+                value: self.value,
+                max: self.max,
+                period: 0,
+                fired: self.policy.fired().to_vec(),
+            };
+            if let Err(e) = store.lock().unwrap().save(key.clone(), saved) {
+                eprintln!("could not save limit tracker state: {}", e);
+            }
+        }
+        if let Some(notification) = notification {
+            self.messenger.notify(&notification);
         }
     }
 }
@@ -76,6 +195,54 @@ mod tests {
 
         assert_eq!(mock_messenger.sent_messages.borrow().len(), 1);
     }
+
+    #[test]
+    fn it_does_not_repeat_a_warning_until_the_value_recovers() {
+        let mock_messenger = MockMessenger::new();
+        let mut limit_tracker = LimitTracker::new(&mock_messenger, 100);
+
+        for &value in &[80, 82, 79, 101, 50, 76] {
+            limit_tracker.set_value(value);
+        }
+
+        assert_eq!(
+            *mock_messenger.sent_messages.borrow(),
+            vec![
+                "Warning: You've used up over 75% of your quota!",
+                "Error: You are over your quota!",
+                "Resolved: You are back under 75% of your quota.",
+                "Warning: You've used up over 75% of your quota!",
+            ]
+        );
+    }
+
+    #[test]
+    fn it_remembers_its_value_and_warnings_across_restarts() {
+        let dir = std::env::temp_dir().join(format!("limit-tracker-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mock_messenger = MockMessenger::new();
+
+        let store = Mutex::new(Store::open(&dir).unwrap());
+        let mut limit_tracker = LimitTracker::new(&mock_messenger, 100).persist(&store, "api");
+        limit_tracker.set_value(80);
+        drop(limit_tracker);
+        drop(store);
+
+        let store = Mutex::new(Store::open(&dir).unwrap());
+        let mut limit_tracker = LimitTracker::new(&mock_messenger, 100).persist(&store, "api");
+        assert_eq!(limit_tracker.value(), 80);
+        limit_tracker.set_value(85);
+        limit_tracker.set_value(95);
+
+        assert_eq!(
+            *mock_messenger.sent_messages.borrow(),
+            vec![
+                "Warning: You've used up over 75% of your quota!",
+                "Urgent warning: You've used up over 90% of your quota!",
+            ]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
 
 // The borrow method returns the smart pointer type Ref<T>, and borrow_mut returns the smart pointer
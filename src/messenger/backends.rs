@@ -0,0 +1,760 @@
+//! Messengers that deliver somewhere real.
+//!
+//! The `try_` methods report failure to deliver. `send` and `notify` can't, so they say so on
+//! stderr and carry on, as a quota check shouldn't fail because a notification couldn't be sent.
+
+use super::quota::civil_date;
+use super::{BatchError, Direction, Messenger, Notification, Outgoing};
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*, BufReader};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn report(backend: &str, result: io::Result<()>) {
+    if let Err(e) = result {
+        eprintln!("{}: could not deliver notification: {}", backend, e);
+    }
+}
+
+/// Seconds since the epoch and the UTC date and time they fall on.
+fn utc(time: SystemTime) -> (u64, (u64, u64, u64), (u64, u64, u64)) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let rem = secs % 86_400;
+    (
+        secs,
+        civil_date(secs / 86_400),
+        (rem / 3600, rem % 3600 / 60, rem % 60),
+    )
+}
+
+/// Format a time as in RFC 3339, e.g. `2024-02-01T08:49:37Z`.
+fn rfc3339(time: SystemTime) -> String {
+    let (_, (year, month, day), (hour, minute, second)) = utc(time);
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, hour, minute, second
+    )
+}
+
+/// Format a time for a mail `Date` header, e.g. `Thu, 01 Feb 2024 08:49:37 +0000`.
+fn rfc5322(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let (secs, (year, month, day), (hour, minute, second)) = utc(time);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} +0000",
+        DAYS[(secs / 86_400 % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        hour,
+        minute,
+        second
+    )
+}
+
+/// Appends notifications to a log file, one per line, starting a new file when it gets too big.
+///
+/// When writing a line would take the file past `max_bytes`, `alerts.log` is renamed to
+/// `alerts.log.1`, `alerts.log.1` to `alerts.log.2` and so on, and the oldest of the `keep`
+/// rotated files is removed.
+pub struct FileMessenger {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    /// The open file and its size, opened on first use.
+    file: Mutex<Option<(File, u64)>>,
+}
+
+impl FileMessenger {
+    pub fn new<P: AsRef<Path>>(path: P) -> FileMessenger {
+        FileMessenger {
+            path: path.as_ref().to_path_buf(),
+            max_bytes: 10 * 1024 * 1024,
+            keep: 5,
+            file: Mutex::new(None),
+        }
+    }
+
+    /// Rotate once the file would grow past `max_bytes`, keeping `keep` old files.
+    pub fn rotate(mut self, max_bytes: u64, keep: usize) -> FileMessenger {
+        self.max_bytes = max_bytes;
+        self.keep = keep;
+        self
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = OsString::from(self.path.as_os_str());
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate_files(&self) -> io::Result<()> {
+        if self.keep == 0 {
+            return fs::remove_file(&self.path);
+        }
+        for n in (1..self.keep).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                fs::rename(from, self.rotated(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))
+    }
+
+    /// Appends `line`, which may be several lines, rotating first if it wouldn't fit.
+    fn write_line(&self, line: &str) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+
+        let len = line.len() as u64;
+        if let Some((_, size)) = *file {
+            if size > 0 && size + len > self.max_bytes {
+                *file = None;
+                self.rotate_files()?;
+            }
+        }
+        if file.is_none() {
+            let opened = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            let size = opened.metadata()?.len();
+            *file = Some((opened, size));
+        }
+
+        let (opened, size) = file.as_mut().unwrap();
+        opened.write_all(line.as_bytes())?;
+        *size += len;
+        Ok(())
+    }
+
+    fn line(outgoing: &Outgoing) -> String {
+        let now = rfc3339(SystemTime::now());
+        match outgoing {
+            Outgoing::Text(msg) => format!("{} {}\n", now, escape_line_breaks(msg)),
+            Outgoing::Notification(notification) => format!(
+                "{} {}: {}\n",
+                now,
+                notification.severity,
+                escape_line_breaks(&notification.message)
+            ),
+        }
+    }
+}
+
+/// Keeps a message on its one line of the log, so every line starts with a timestamp.
+fn escape_line_breaks(msg: &str) -> String {
+    msg.replace('\r', "\\r").replace('\n', "\\n")
+}
+
+impl Messenger for FileMessenger {
+    fn send(&self, msg: &str) {
+        report(&self.path.display().to_string(), self.try_send(msg));
+    }
+
+    fn notify(&self, notification: &Notification) {
+        report(
+            &self.path.display().to_string(),
+            self.try_notify(notification),
+        );
+    }
+
+    fn try_send(&self, msg: &str) -> io::Result<()> {
+        self.write_line(&FileMessenger::line(&Outgoing::Text(String::from(msg))))
+    }
+
+    fn try_notify(&self, notification: &Notification) -> io::Result<()> {
+        self.write_line(&FileMessenger::line(&Outgoing::Notification(
+            notification.clone(),
+        )))
+    }
+
+    /// Writes the whole batch at once, so it's either all there or none of it is.
+    fn try_send_batch(&self, batch: &[Outgoing]) -> Result<(), BatchError> {
+        let lines: String = batch.iter().map(FileMessenger::line).collect();
+        self.write_line(&lines).map_err(|error| BatchError {
+            delivered: 0,
+            error,
+        })
+    }
+}
+
+/// Writes notifications to standard error.
+pub struct StderrMessenger;
+
+impl Messenger for StderrMessenger {
+    fn send(&self, msg: &str) {
+        eprintln!("{}", msg);
+    }
+
+    fn notify(&self, notification: &Notification) {
+        eprintln!("{}: {}", notification.severity, notification.message);
+    }
+
+    fn try_send(&self, msg: &str) -> io::Result<()> {
+        writeln!(io::stderr(), "{}", msg)
+    }
+
+    fn try_notify(&self, notification: &Notification) -> io::Result<()> {
+        writeln!(
+            io::stderr(),
+            "{}: {}",
+            notification.severity,
+            notification.message
+        )
+    }
+}
+
+/// Posts notifications as JSON to an `http://` URL, as chat services' incoming webhooks expect.
+///
+/// The body has a `text` field with the message and, for threshold notifications, `severity`,
+/// `direction`, `level`, `value` and `max`. Any 2xx response counts as delivered.
+pub struct WebhookMessenger {
+    host: String,
+    port: u16,
+    path: String,
+    timeout: Duration,
+}
+
+impl WebhookMessenger {
+    /// Fails if `url` isn't a plain `http://` URL; there's no TLS support.
+    pub fn new(url: &str) -> io::Result<WebhookMessenger> {
+        let invalid = |reason: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("webhook URL {}: {}", url, reason),
+            )
+        };
+
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| invalid("only http:// URLs are supported"))?;
+        // The path goes into the request line as it is, where a space or line break would end it.
+        if rest.chars().any(|c| c == ' ' || c.is_control()) {
+            return Err(invalid("spaces and control characters aren't allowed"));
+        }
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.strip_prefix('[') {
+            // An IPv6 address, whose colons aren't the port's.
+            Some(bracketed) => {
+                let end = bracketed
+                    .find(']')
+                    .ok_or_else(|| invalid("unclosed [ in host"))?;
+                match &bracketed[end + 1..] {
+                    "" => (&bracketed[..end], None),
+                    port => (
+                        &bracketed[..end],
+                        Some(port.strip_prefix(':').ok_or_else(|| invalid("bad port"))?),
+                    ),
+                }
+            }
+            None => match authority.rfind(':') {
+                Some(i) => (&authority[..i], Some(&authority[i + 1..])),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| invalid("bad port"))?,
+            None => 80,
+        };
+        if host.is_empty() {
+            return Err(invalid("no host"));
+        }
+
+        Ok(WebhookMessenger {
+            host: String::from(host),
+            port,
+            path: String::from(path),
+            timeout: Duration::from_secs(10),
+        })
+    }
+
+    /// How long to wait to connect, and then for each read or write. The default is 10 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> WebhookMessenger {
+        self.timeout = timeout;
+        self
+    }
+
+    fn post(&self, body: &str) -> io::Result<()> {
+        // Like `TcpStream::connect`, try each address the host resolves to until one answers.
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "host has no address");
+        let mut connected = None;
+        for addr in (self.host.as_str(), self.port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.timeout) {
+                Ok(stream) => {
+                    connected = Some(stream);
+                    break;
+                }
+                Err(error) => last_error = error,
+            }
+        }
+        let mut stream = connected.ok_or(last_error)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        let host = if self.port == 80 {
+            host
+        } else {
+            format!("{}:{}", host, self.port)
+        };
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            host,
+            body.len(),
+            body
+        )?;
+
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line)?;
+        let status = status_line.split(' ').nth(1).unwrap_or("");
+        if status.len() == 3 && status.starts_with('2') {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!(
+                "webhook answered {:?}",
+                status_line.trim_end()
+            )))
+        }
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl Messenger for WebhookMessenger {
+    fn send(&self, msg: &str) {
+        report(&format!("webhook {}", self.host), self.try_send(msg));
+    }
+
+    fn notify(&self, notification: &Notification) {
+        report(
+            &format!("webhook {}", self.host),
+            self.try_notify(notification),
+        );
+    }
+
+    fn try_send(&self, msg: &str) -> io::Result<()> {
+        self.post(&format!("{{\"text\":{}}}", json_string(msg)))
+    }
+
+    fn try_notify(&self, notification: &Notification) -> io::Result<()> {
+        let body = format!(
+            "{{\"text\":{},\"severity\":\"{}\",\"direction\":\"{}\",\"level\":{},\"value\":{},\"max\":{}}}",
+            json_string(&notification.message),
+            notification.severity,
+            match notification.direction {
+                Direction::Up => "up",
+                Direction::Down => "down",
+            },
+            notification.level,
+            notification.value,
+            notification.max
+        );
+        self.post(&body)
+    }
+}
+
+/// Writes each notification as an email message into a spool directory, for a mail transfer agent
+/// or a cron job to pick up and send.
+///
+/// Messages are written under a temporary name starting with `.` and renamed to `<id>.eml` once
+/// complete, so whatever reads the directory never sees half a message.
+pub struct SpoolMessenger {
+    dir: PathBuf,
+    from: String,
+    to: Vec<String>,
+}
+
+static SPOOLED: AtomicUsize = AtomicUsize::new(0);
+
+impl SpoolMessenger {
+    pub fn new<P: AsRef<Path>>(dir: P, from: &str, to: &[&str]) -> SpoolMessenger {
+        SpoolMessenger {
+            dir: dir.as_ref().to_path_buf(),
+            from: String::from(from),
+            to: to.iter().map(|&to| String::from(to)).collect(),
+        }
+    }
+
+    fn spool(&self, subject: &str, body: &str) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        let now = SystemTime::now();
+        let id = format!(
+            "{}.{}.{}",
+            now.duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos())
+                .unwrap_or(0),
+            process::id(),
+            SPOOLED.fetch_add(1, Ordering::SeqCst)
+        );
+        let domain = self.from.rsplit('@').next().unwrap_or("localhost");
+
+        // Headers can't span lines, and the body's line endings are CRLF as SMTP wants.
+        let subject: String = subject
+            .chars()
+            .map(|c| if c.is_control() { ' ' } else { c })
+            .collect();
+        let mut message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMessage-ID: <{}@{}>\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\r\n",
+            self.from,
+            self.to.join(", "),
+            subject,
+            rfc5322(now),
+            id,
+            domain
+        );
+        for line in body.lines() {
+            message.push_str(line);
+            message.push_str("\r\n");
+        }
+
+        let temp = self.dir.join(format!(".{}.tmp", id));
+        fs::write(&temp, message)?;
+        fs::rename(temp, self.dir.join(format!("{}.eml", id)))
+    }
+}
+
+fn subject_and_body(outgoing: &Outgoing) -> (String, String) {
+    match outgoing {
+        Outgoing::Text(msg) => (String::from(msg.lines().next().unwrap_or("")), msg.clone()),
+        Outgoing::Notification(notification) => (
+            format!(
+                "[{}] {}",
+                notification.severity,
+                notification.message.lines().next().unwrap_or("")
+            ),
+            format!(
+                "{}\n\nUsage is {} of {}.",
+                notification.message, notification.value, notification.max
+            ),
+        ),
+    }
+}
+
+impl Messenger for SpoolMessenger {
+    fn send(&self, msg: &str) {
+        report(&self.dir.display().to_string(), self.try_send(msg));
+    }
+
+    fn notify(&self, notification: &Notification) {
+        report(
+            &self.dir.display().to_string(),
+            self.try_notify(notification),
+        );
+    }
+
+    fn try_send(&self, msg: &str) -> io::Result<()> {
+        let (subject, body) = subject_and_body(&Outgoing::Text(String::from(msg)));
+        self.spool(&subject, &body)
+    }
+
+    fn try_notify(&self, notification: &Notification) -> io::Result<()> {
+        let (subject, body) = subject_and_body(&Outgoing::Notification(notification.clone()));
+        self.spool(&subject, &body)
+    }
+
+    /// Spools a batch of more than one as a single digest, to spare people's inboxes.
+    fn try_send_batch(&self, batch: &[Outgoing]) -> Result<(), BatchError> {
+        let result = if let [outgoing] = batch {
+            let (subject, body) = subject_and_body(outgoing);
+            self.spool(&subject, &body)
+        } else {
+            let severity = batch
+                .iter()
+                .filter_map(|outgoing| match outgoing {
+                    Outgoing::Notification(notification) => Some(notification.severity),
+                    Outgoing::Text(_) => None,
+                })
+                .max();
+            let subject = match severity {
+                Some(severity) => format!("[{}] {} notifications", severity, batch.len()),
+                None => format!("{} notifications", batch.len()),
+            };
+            let bodies: Vec<String> = batch
+                .iter()
+                .map(|outgoing| subject_and_body(outgoing).1)
+                .collect();
+            self.spool(&subject, &bodies.join("\n\n"))
+        };
+        result.map_err(|error| BatchError {
+            delivered: 0,
+            error,
+        })
+    }
+}
+
+/// Delivers every notification to each of several messengers in turn.
+///
+/// The `try_` methods try every messenger and return the first error, so retrying after a failure
+/// delivers again to those that succeeded. To retry each separately, wrap each in its own
+/// `AsyncMessenger` instead.
+#[derive(Default)]
+pub struct FanoutMessenger {
+    messengers: Vec<Box<dyn Messenger + Send + Sync>>,
+}
+
+impl FanoutMessenger {
+    pub fn new() -> FanoutMessenger {
+        FanoutMessenger::default()
+    }
+
+    pub fn with<M: Messenger + Send + Sync + 'static>(mut self, messenger: M) -> FanoutMessenger {
+        self.messengers.push(Box::new(messenger));
+        self
+    }
+}
+
+impl Messenger for FanoutMessenger {
+    fn send(&self, msg: &str) {
+        for messenger in &self.messengers {
+            messenger.send(msg);
+        }
+    }
+
+    fn notify(&self, notification: &Notification) {
+        for messenger in &self.messengers {
+            messenger.notify(notification);
+        }
+    }
+
+    fn try_send(&self, msg: &str) -> io::Result<()> {
+        self.messengers
+            .iter()
+            .map(|messenger| messenger.try_send(msg))
+            .fold(Ok(()), Result::and)
+    }
+
+    fn try_notify(&self, notification: &Notification) -> io::Result<()> {
+        self.messengers
+            .iter()
+            .map(|messenger| messenger.try_notify(notification))
+            .fold(Ok(()), Result::and)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Policy;
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("messenger-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn over_90() -> Notification {
+        Policy::default().check(95, 100).unwrap()
+    }
+
+    #[test]
+    fn formats_dates() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_706_777_377);
+        assert_eq!(rfc3339(time), "2024-02-01T08:49:37Z");
+        assert_eq!(rfc5322(time), "Thu, 01 Feb 2024 08:49:37 +0000");
+    }
+
+    #[test]
+    fn file_messenger_rotates() {
+        let dir = scratch("file");
+        let path = dir.join("alerts.log");
+        let messenger = FileMessenger::new(&path).rotate(60, 2);
+
+        for i in 0..4 {
+            messenger.send(&format!("message {}", i));
+        }
+        messenger.notify(&over_90());
+
+        let read = |path: PathBuf| fs::read_to_string(path).unwrap();
+        assert!(read(path.clone())
+            .ends_with(" urgent: Urgent warning: You've used up over 90% of your quota!\n"));
+        assert!(read(dir.join("alerts.log.1")).ends_with(" message 3\n"));
+        assert!(read(dir.join("alerts.log.2")).ends_with(" message 2\n"));
+        assert!(!dir.join("alerts.log.3").exists());
+
+        let batch = vec![
+            Outgoing::Text(String::from("one")),
+            Outgoing::Text(String::from("two")),
+        ];
+        messenger.try_send_batch(&batch).unwrap();
+        let log = read(path.clone());
+        assert!(log.contains(" one\n") && log.ends_with(" two\n"));
+        assert!(read(dir.join("alerts.log.1")).contains("quota!\n"));
+
+        messenger.send("first\r\nsecond");
+        assert!(read(path.clone()).ends_with(" first\\r\\nsecond\n"));
+
+        let missing = FileMessenger::new(dir.join("missing").join("alerts.log"));
+        assert!(missing.try_send("lost").is_err());
+        let failed = missing.try_send_batch(&batch).unwrap_err();
+        assert_eq!(failed.delivered, 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn spool_messenger_writes_whole_messages() {
+        let dir = scratch("spool");
+        let messenger = SpoolMessenger::new(&dir, "quota@example.com", &["ops@example.com"]);
+
+        messenger.notify(&over_90());
+
+        let entries: Vec<PathBuf> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].extension().unwrap(), "eml");
+
+        let message = fs::read_to_string(&entries[0]).unwrap();
+        assert!(message.starts_with("From: quota@example.com\r\nTo: ops@example.com\r\n"));
+        assert!(message.contains(
+            "\r\nSubject: [urgent] Urgent warning: You've used up over 90% of your quota!\r\n"
+        ));
+        assert!(message.contains("@example.com>\r\n"));
+        assert!(message.ends_with("\r\n\r\nUsage is 95 of 100.\r\n"));
+        fs::remove_file(&entries[0]).unwrap();
+
+        let batch = vec![
+            Outgoing::Notification(over_90()),
+            Outgoing::Text(String::from("hello")),
+        ];
+        messenger.try_send_batch(&batch).unwrap();
+        let digest = fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+        let message = fs::read_to_string(digest).unwrap();
+        assert!(message.contains("\r\nSubject: [urgent] 2 notifications\r\n"));
+        assert!(message.ends_with("Usage is 95 of 100.\r\n\r\nhello\r\n"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn webhook_messenger_posts_json() {
+        // Wherever localhost resolves to ::1 first, nothing is listening there.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let url = format!("http://localhost:{}/hooks/quota", port);
+
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for status in &["204 No Content", "500 Internal Server Error"] {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut head = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(value) = line.strip_prefix("Content-Length: ") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                    head.push_str(&line);
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                write!(reader.get_mut(), "HTTP/1.1 {}\r\n\r\n", status).unwrap();
+                requests.push((head, String::from_utf8(body).unwrap()));
+            }
+            requests
+        });
+
+        let messenger = WebhookMessenger::new(&url).unwrap();
+        messenger.notify(&over_90());
+        assert_eq!(
+            messenger.post("{}").unwrap_err().to_string(),
+            "webhook answered \"HTTP/1.1 500 Internal Server Error\""
+        );
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].0.starts_with("POST /hooks/quota HTTP/1.1\r\n"));
+        assert_eq!(
+            requests[0].1,
+            "{\"text\":\"Urgent warning: You've used up over 90% of your quota!\",\
+             \"severity\":\"urgent\",\"direction\":\"up\",\"level\":0.9,\"value\":95,\"max\":100}"
+        );
+
+        assert!(WebhookMessenger::new("https://example.com/").is_err());
+        assert!(WebhookMessenger::new("http://example.com:x/").is_err());
+        assert!(WebhookMessenger::new("http://example.com/a b").is_err());
+        assert!(WebhookMessenger::new("http://example.com/a\r\nX-Evil: 1").is_err());
+
+        let ipv6 = WebhookMessenger::new("http://[::1]:8080/hooks").unwrap();
+        assert_eq!((ipv6.host.as_str(), ipv6.port), ("::1", 8080));
+        assert_eq!(WebhookMessenger::new("http://[::1]/").unwrap().port, 80);
+        assert!(WebhookMessenger::new("http://[::1/").is_err());
+        assert!(WebhookMessenger::new("http://[::1]x/").is_err());
+    }
+
+    #[derive(Default, Clone)]
+    struct Collect(Arc<Mutex<Vec<String>>>);
+
+    impl Messenger for Collect {
+        fn send(&self, msg: &str) {
+            self.0.lock().unwrap().push(String::from(msg));
+        }
+
+        fn notify(&self, notification: &Notification) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{}: {}", notification.severity, notification.level));
+        }
+    }
+
+    #[test]
+    fn fanout_delivers_to_each() {
+        let (a, b) = (Collect::default(), Collect::default());
+        let fanout = FanoutMessenger::new().with(a.clone()).with(b.clone());
+
+        fanout.send("hello");
+        fanout.notify(&over_90());
+
+        for collected in &[a, b] {
+            assert_eq!(*collected.0.lock().unwrap(), vec!["hello", "urgent: 0.9"]);
+        }
+    }
+}
@@ -0,0 +1,496 @@
+use super::{Messenger, Notification, Outgoing};
+use std::cmp;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How an `AsyncMessenger` delivers: batch sizes, retries and where undeliverable messages go.
+pub struct Delivery {
+    capacity: usize,
+    batch_size: usize,
+    linger: Duration,
+    attempts: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+    dead_letter: Option<Box<dyn Messenger + Send>>,
+}
+
+impl Delivery {
+    /// Room for 1024 messages waiting, batches of up to 32, and five attempts at delivering each
+    /// starting 100ms apart.
+    pub fn new() -> Delivery {
+        Delivery {
+            capacity: 1024,
+            batch_size: 32,
+            linger: Duration::from_millis(0),
+            attempts: 5,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            dead_letter: None,
+        }
+    }
+
+    /// How many messages may wait to be delivered. Once that many are waiting, sending more fails
+    /// until the inner messenger catches up.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn capacity(mut self, capacity: usize) -> Delivery {
+        assert!(capacity > 0);
+        self.capacity = capacity;
+        self
+    }
+
+    /// Deliver up to `size` messages at a time, waiting up to `linger` after the first for others
+    /// to join it. Without a linger, a batch is whatever had queued up by the time the last one
+    /// was delivered.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is zero.
+    pub fn batch(mut self, size: usize, linger: Duration) -> Delivery {
+        assert!(size > 0);
+        self.batch_size = size;
+        self.linger = linger;
+        self
+    }
+
+    /// Try delivering a message `attempts` times before giving up on it, waiting `backoff` after
+    /// the first failure and twice as long after each one after that, up to `max_backoff`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `attempts` is zero.
+    pub fn retry(mut self, attempts: u32, backoff: Duration, max_backoff: Duration) -> Delivery {
+        assert!(attempts > 0);
+        self.attempts = attempts;
+        self.backoff = backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Where messages go that couldn't be delivered; by default they're written to stderr.
+    pub fn dead_letter<M: Messenger + Send + 'static>(mut self, messenger: M) -> Delivery {
+        self.dead_letter = Some(Box::new(messenger));
+        self
+    }
+
+    pub fn start<M: Messenger + Send + 'static>(self, inner: M) -> AsyncMessenger {
+        AsyncMessenger::with_delivery(inner, self)
+    }
+}
+
+impl Default for Delivery {
+    fn default() -> Delivery {
+        Delivery::new()
+    }
+}
+
+/// Delivers messages on a background thread, so that a slow or failing messenger doesn't hold up
+/// whoever is sending.
+///
+/// Messages are queued and handed to the inner messenger's `try_send_batch` in batches. A batch
+/// that fails is retried from the first message that wasn't delivered, with exponential backoff.
+/// Once that message has failed as many times as `Delivery` allows, it goes to the dead letter
+/// messenger instead, and delivery carries on with the rest of the batch.
+///
+/// The queue is bounded, so a messenger that stalls can't make it grow without limit. `try_send` and
+/// `try_notify` fail with `WouldBlock` while it's full, and `send` and `notify` write the message to
+/// stderr instead.
+///
+/// Dropping an `AsyncMessenger` waits for the messages already queued to be delivered or given up
+/// on.
+pub struct AsyncMessenger {
+    sender: Option<SyncSender<Outgoing>>,
+    worker: Option<JoinHandle<()>>,
+    dead_lettered: Arc<AtomicUsize>,
+}
+
+impl AsyncMessenger {
+    pub fn new<M: Messenger + Send + 'static>(inner: M) -> AsyncMessenger {
+        AsyncMessenger::with_delivery(inner, Delivery::new())
+    }
+
+    pub fn with_delivery<M: Messenger + Send + 'static>(
+        inner: M,
+        delivery: Delivery,
+    ) -> AsyncMessenger {
+        let (sender, receiver) = mpsc::sync_channel(delivery.capacity);
+        let dead_lettered = Arc::new(AtomicUsize::new(0));
+
+        let worker = Worker {
+            inner,
+            delivery,
+            dead_lettered: Arc::clone(&dead_lettered),
+        };
+        let worker = thread::spawn(move || worker.run(receiver));
+
+        AsyncMessenger {
+            sender: Some(sender),
+            worker: Some(worker),
+            dead_lettered,
+        }
+    }
+
+    /// How many messages have been given up on so far.
+    pub fn dead_lettered(&self) -> usize {
+        self.dead_lettered.load(Ordering::SeqCst)
+    }
+
+    fn queue(&self, outgoing: Outgoing) -> io::Result<()> {
+        self.sender
+            .as_ref()
+            .unwrap()
+            .try_send(outgoing)
+            .map_err(|e| match e {
+                TrySendError::Full(_) => {
+                    io::Error::new(io::ErrorKind::WouldBlock, "delivery queue is full")
+                }
+                TrySendError::Disconnected(_) => {
+                    io::Error::new(io::ErrorKind::BrokenPipe, "delivery thread has stopped")
+                }
+            })
+    }
+}
+
+impl Messenger for AsyncMessenger {
+    fn send(&self, msg: &str) {
+        if let Err(e) = self.try_send(msg) {
+            eprintln!("undelivered: {}: {}", msg, e);
+        }
+    }
+
+    fn notify(&self, notification: &Notification) {
+        if let Err(e) = self.try_notify(notification) {
+            eprintln!(
+                "undelivered: {}: {}: {}",
+                notification.severity, notification.message, e
+            );
+        }
+    }
+
+    /// Queues the message, failing if the queue is full or the delivery thread has died.
+    fn try_send(&self, msg: &str) -> io::Result<()> {
+        self.queue(Outgoing::Text(String::from(msg)))
+    }
+
+    fn try_notify(&self, notification: &Notification) -> io::Result<()> {
+        self.queue(Outgoing::Notification(notification.clone()))
+    }
+}
+
+impl Drop for AsyncMessenger {
+    fn drop(&mut self) {
+        // Closing the channel lets the worker finish once it has dealt with what's queued.
+        drop(self.sender.take());
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+struct Worker<M> {
+    inner: M,
+    delivery: Delivery,
+    dead_lettered: Arc<AtomicUsize>,
+}
+
+impl<M: Messenger> Worker<M> {
+    fn run(self, receiver: Receiver<Outgoing>) {
+        while let Ok(first) = receiver.recv() {
+            let mut batch = vec![first];
+            let deadline = Instant::now() + self.delivery.linger;
+            while batch.len() < self.delivery.batch_size {
+                let wait = deadline.saturating_duration_since(Instant::now());
+                match receiver.recv_timeout(wait) {
+                    Ok(outgoing) => batch.push(outgoing),
+                    Err(_) => break,
+                }
+            }
+            self.deliver(&batch);
+        }
+    }
+
+    fn deliver(&self, batch: &[Outgoing]) {
+        let mut pending = batch;
+        let mut failures = 0;
+        let mut backoff = self.delivery.backoff;
+
+        while let Err(e) = self.inner.try_send_batch(pending) {
+            // Don't trust the inner messenger to count within the batch.
+            let delivered = cmp::min(e.delivered, pending.len());
+            if delivered > 0 {
+                pending = &pending[delivered..];
+                failures = 0;
+                backoff = self.delivery.backoff;
+            }
+            if pending.is_empty() {
+                return;
+            }
+            failures += 1;
+            if failures >= self.delivery.attempts {
+                eprintln!(
+                    "giving up on a notification after {} attempts: {}",
+                    failures, e.error
+                );
+                // Only this one has failed; the others haven't been tried yet.
+                self.dead_letter(&pending[..1]);
+                pending = &pending[1..];
+                failures = 0;
+                backoff = self.delivery.backoff;
+                if pending.is_empty() {
+                    return;
+                }
+                continue;
+            }
+            thread::sleep(backoff);
+            backoff = cmp::min(backoff * 2, self.delivery.max_backoff);
+        }
+    }
+
+    fn dead_letter(&self, undelivered: &[Outgoing]) {
+        self.dead_lettered
+            .fetch_add(undelivered.len(), Ordering::SeqCst);
+
+        if let Some(messenger) = &self.delivery.dead_letter {
+            match messenger.try_send_batch(undelivered) {
+                Ok(()) => return,
+                Err(e) => eprintln!("could not dead-letter notifications: {}", e),
+            }
+        }
+        for outgoing in undelivered {
+            match outgoing {
+                Outgoing::Text(msg) => eprintln!("undelivered: {}", msg),
+                Outgoing::Notification(notification) => eprintln!(
+                    "undelivered: {}: {}",
+                    notification.severity, notification.message
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::BatchError;
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Records what it's asked to deliver, failing on messages equal to `fail_on` while it has
+    /// failures left.
+    #[derive(Clone, Default)]
+    struct Flaky {
+        fail_on: Option<&'static str>,
+        failures: Arc<Mutex<usize>>,
+        delivered: Arc<Mutex<Vec<String>>>,
+        batches: Arc<Mutex<Vec<(usize, Instant)>>>,
+    }
+
+    impl Flaky {
+        fn failing(fail_on: &'static str, failures: usize) -> Flaky {
+            Flaky {
+                fail_on: Some(fail_on),
+                failures: Arc::new(Mutex::new(failures)),
+                ..Flaky::default()
+            }
+        }
+
+        fn delivered(&self) -> Vec<String> {
+            self.delivered.lock().unwrap().clone()
+        }
+    }
+
+    impl Messenger for Flaky {
+        fn send(&self, _: &str) {
+            unreachable!()
+        }
+
+        fn try_send(&self, msg: &str) -> io::Result<()> {
+            let mut failures = self.failures.lock().unwrap();
+            if self.fail_on == Some(msg) && *failures > 0 {
+                *failures -= 1;
+                return Err(io::Error::other("flaky"));
+            }
+            self.delivered.lock().unwrap().push(String::from(msg));
+            Ok(())
+        }
+
+        fn try_send_batch(&self, batch: &[Outgoing]) -> Result<(), BatchError> {
+            self.batches
+                .lock()
+                .unwrap()
+                .push((batch.len(), Instant::now()));
+            for (delivered, outgoing) in batch.iter().enumerate() {
+                if let Outgoing::Text(msg) = outgoing {
+                    self.try_send(msg)
+                        .map_err(|error| BatchError { delivered, error })?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Holds up every batch until `gate` is free.
+    struct Gated {
+        gate: Arc<Mutex<()>>,
+        inner: Flaky,
+    }
+
+    impl Messenger for Gated {
+        fn send(&self, _: &str) {
+            unreachable!()
+        }
+
+        fn try_send_batch(&self, batch: &[Outgoing]) -> Result<(), BatchError> {
+            let _open = self.gate.lock().unwrap();
+            self.inner.try_send_batch(batch)
+        }
+    }
+
+    /// Delivers everything but reports a failure anyway, claiming more deliveries than it was given.
+    struct Overcounting(Flaky);
+
+    impl Messenger for Overcounting {
+        fn send(&self, _: &str) {
+            unreachable!()
+        }
+
+        fn try_send_batch(&self, batch: &[Outgoing]) -> Result<(), BatchError> {
+            self.0.try_send_batch(batch).unwrap();
+            Err(BatchError {
+                delivered: batch.len() + 1,
+                error: io::Error::other("lost the acknowledgement"),
+            })
+        }
+    }
+
+    fn quick_retries(attempts: u32) -> Delivery {
+        Delivery::new().retry(
+            attempts,
+            Duration::from_millis(20),
+            Duration::from_millis(30),
+        )
+    }
+
+    #[test]
+    fn delivers_in_batches_on_another_thread() {
+        let flaky = Flaky::default();
+        let messenger = Delivery::new()
+            .batch(4, Duration::from_millis(200))
+            .start(flaky.clone());
+
+        for i in 0..10 {
+            messenger.send(&i.to_string());
+        }
+        drop(messenger);
+
+        assert_eq!(
+            flaky.delivered(),
+            (0..10).map(|i| i.to_string()).collect::<Vec<_>>()
+        );
+        let sizes: Vec<usize> = flaky.batches.lock().unwrap().iter().map(|b| b.0).collect();
+        assert_eq!(sizes, vec![4, 4, 2]);
+    }
+
+    #[test]
+    fn refuses_messages_while_the_queue_is_full() {
+        let flaky = Flaky::default();
+        let gate = Arc::new(Mutex::new(()));
+        let closed = gate.lock().unwrap();
+        let messenger = Delivery::new()
+            .capacity(2)
+            .batch(1, Duration::from_millis(0))
+            .start(Gated {
+                gate: Arc::clone(&gate),
+                inner: flaky.clone(),
+            });
+
+        // The worker may or may not have taken the first message off the queue yet.
+        let mut queued = 0;
+        let error = loop {
+            match messenger.try_send(&queued.to_string()) {
+                Ok(()) => queued += 1,
+                Err(e) => break e,
+            }
+            assert!(queued <= 3);
+        };
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+        assert!(queued >= 2);
+
+        drop(closed);
+        drop(messenger);
+        assert_eq!(
+            flaky.delivered(),
+            (0..queued).map(|i| i.to_string()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn retries_from_the_first_undelivered_message() {
+        let flaky = Flaky::failing("b", 2);
+        let messenger = quick_retries(3)
+            .batch(3, Duration::from_millis(200))
+            .start(flaky.clone());
+
+        for msg in &["a", "b", "c"] {
+            messenger.send(msg);
+        }
+        drop(messenger);
+
+        assert_eq!(flaky.delivered(), vec!["a", "b", "c"]);
+        let batches = flaky.batches.lock().unwrap();
+        let sizes: Vec<usize> = batches.iter().map(|b| b.0).collect();
+        assert_eq!(sizes, vec![3, 2, 2]);
+        assert!(batches[1].1 - batches[0].1 >= Duration::from_millis(20));
+        assert!(batches[2].1 - batches[1].1 >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn dead_letters_after_too_many_failures() {
+        let flaky = Flaky::failing("b", 100);
+        let dead = Flaky::default();
+        let messenger = quick_retries(3)
+            .batch(3, Duration::from_millis(200))
+            .dead_letter(dead.clone())
+            .start(flaky.clone());
+
+        for msg in &["a", "b", "c"] {
+            messenger.send(msg);
+        }
+        messenger.send("d");
+        let started = Instant::now();
+        while messenger.dead_lettered() < 1 {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(5));
+        }
+        drop(messenger);
+
+        assert_eq!(flaky.delivered(), vec!["a", "c", "d"]);
+        assert_eq!(dead.delivered(), vec!["b"]);
+        let sizes: Vec<usize> = flaky.batches.lock().unwrap().iter().map(|b| b.0).collect();
+        assert_eq!(sizes, vec![3, 2, 2, 1, 1]);
+    }
+
+    #[test]
+    fn stops_once_the_inner_messenger_has_delivered_everything() {
+        let flaky = Flaky::default();
+        let dead = Flaky::default();
+        let messenger = quick_retries(1)
+            .batch(2, Duration::from_millis(200))
+            .dead_letter(dead.clone())
+            .start(Overcounting(flaky.clone()));
+
+        for msg in &["a", "b", "c", "d"] {
+            messenger.send(msg);
+        }
+        messenger.try_send("e").unwrap();
+        drop(messenger);
+
+        assert_eq!(flaky.delivered(), vec!["a", "b", "c", "d", "e"]);
+        assert!(dead.delivered().is_empty());
+    }
+}
@@ -0,0 +1,306 @@
+use std::fmt;
+
+/// How serious a notification is, from least to most.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Info,
+    Warning,
+    Urgent,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Urgent => "urgent",
+            Severity::Error => "error",
+        })
+    }
+}
+
+/// A level of usage worth telling someone about.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Threshold {
+    /// Fraction of the maximum at which the threshold is crossed, so 0.9 for 90%.
+    pub level: f64,
+    pub severity: Severity,
+    /// Sent when usage rises to `level`.
+    pub message: String,
+    /// Sent when usage falls back below `level`; a generic message is used if this is `None`.
+    pub cleared: Option<String>,
+}
+
+impl Threshold {
+    pub fn new(level: f64, severity: Severity, message: &str) -> Threshold {
+        Threshold {
+            level,
+            severity,
+            message: String::from(message),
+            cleared: None,
+        }
+    }
+
+    pub fn cleared(mut self, message: &str) -> Threshold {
+        self.cleared = Some(String::from(message));
+        self
+    }
+
+    fn cleared_message(&self) -> String {
+        match &self.cleared {
+            Some(message) => message.clone(),
+            // Rounded, as 0.07 * 100.0 is 7.000000000000001.
+            None => format!(
+                "Resolved: You are back under {}% of your quota.",
+                (self.level * 100.0 * 1e6).round() / 1e6
+            ),
+        }
+    }
+}
+
+/// Which way usage crossed a threshold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+/// What a `Messenger` is asked to deliver when usage crosses a threshold.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notification {
+    /// The threshold's severity going up; always `Info` coming back down.
+    pub severity: Severity,
+    pub direction: Direction,
+    /// The level of the threshold that was crossed.
+    pub level: f64,
+    pub message: String,
+    pub value: usize,
+    pub max: usize,
+}
+
+fn usage(value: usize, max: usize) -> f64 {
+    if max > 0 {
+        value as f64 / max as f64
+    } else if value > 0 {
+        f64::INFINITY
+    } else {
+        0.0
+    }
+}
+
+/// Decides when changes in usage are worth a notification.
+///
+/// A threshold fires once when usage rises to its level, and is only armed again once usage
+/// falls `hysteresis` below the level, at which point a notification says so. A value that
+/// wobbles around a threshold therefore produces one notification rather than one per change.
+///
+/// When a single change crosses several thresholds, only one notification is sent: for the
+/// highest one crossed going up, or the lowest one cleared going down, as that describes where
+/// usage now is.
+#[derive(Debug, Clone)]
+pub struct Policy {
+    thresholds: Vec<Threshold>,
+    fired: Vec<bool>,
+    hysteresis: f64,
+}
+
+impl Policy {
+    /// # Panics
+    ///
+    /// Panics if a threshold's level isn't a positive number.
+    pub fn new(mut thresholds: Vec<Threshold>) -> Policy {
+        for threshold in &thresholds {
+            assert!(
+                threshold.level > 0.0 && threshold.level.is_finite(),
+                "threshold level must be positive"
+            );
+        }
+        thresholds.sort_by(|a, b| a.level.partial_cmp(&b.level).unwrap());
+
+        Policy {
+            fired: vec![false; thresholds.len()],
+            thresholds,
+            hysteresis: 0.05,
+        }
+    }
+
+    /// How far below a threshold, as a fraction of the maximum, usage has to fall before the
+    /// threshold clears. The default is 0.05, five percentage points.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `hysteresis` is negative.
+    pub fn hysteresis(mut self, hysteresis: f64) -> Policy {
+        assert!(hysteresis >= 0.0, "hysteresis can't be negative");
+        self.hysteresis = hysteresis;
+        self
+    }
+
+    /// The thresholds, lowest first.
+    pub fn thresholds(&self) -> &[Threshold] {
+        &self.thresholds
+    }
+
+    /// Whether each threshold, lowest first, has fired and not yet cleared.
+    pub fn fired(&self) -> &[bool] {
+        &self.fired
+    }
+
+    /// Marks thresholds as fired or not without notifying, as saved from `fired` for usage of
+    /// `value` out of `max`. If the thresholds have changed since, so that `fired` doesn't have
+    /// one entry per threshold, those that usage is at or over are taken to have fired.
+    pub fn restore(&mut self, fired: &[bool], value: usize, max: usize) {
+        if fired.len() == self.thresholds.len() {
+            self.fired.copy_from_slice(fired);
+        } else {
+            let usage = usage(value, max);
+            for (fired, threshold) in self.fired.iter_mut().zip(&self.thresholds) {
+                *fired = usage >= threshold.level;
+            }
+        }
+    }
+
+    /// Updates which thresholds have fired for usage of `value` out of `max`, returning the
+    /// notification to send, if any.
+    pub fn check(&mut self, value: usize, max: usize) -> Option<Notification> {
+        let usage = usage(value, max);
+
+        let mut up = None;
+        let mut down = None;
+        for (i, threshold) in self.thresholds.iter().enumerate() {
+            if !self.fired[i] && usage >= threshold.level {
+                self.fired[i] = true;
+                up = Some(i);
+            } else if self.fired[i] && usage < threshold.level - self.hysteresis {
+                self.fired[i] = false;
+                down = down.or(Some(i));
+            }
+        }
+
+        let (i, direction) = match (up, down) {
+            (Some(i), _) => (i, Direction::Up),
+            (None, Some(i)) => (i, Direction::Down),
+            (None, None) => return None,
+        };
+        let threshold = &self.thresholds[i];
+        Some(Notification {
+            severity: match direction {
+                Direction::Up => threshold.severity,
+                Direction::Down => Severity::Info,
+            },
+            direction,
+            level: threshold.level,
+            message: match direction {
+                Direction::Up => threshold.message.clone(),
+                Direction::Down => threshold.cleared_message(),
+            },
+            value,
+            max,
+        })
+    }
+}
+
+/// The thresholds `LimitTracker` has always had: warnings at 75% and 90%, and an error at 100%.
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy::new(vec![
+            Threshold::new(
+                0.75,
+                Severity::Warning,
+                "Warning: You've used up over 75% of your quota!",
+            ),
+            Threshold::new(
+                0.9,
+                Severity::Urgent,
+                "Urgent warning: You've used up over 90% of your quota!",
+            ),
+            Threshold::new(1.0, Severity::Error, "Error: You are over your quota!"),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(policy: &mut Policy, values: &[usize]) -> Vec<(Direction, f64)> {
+        values
+            .iter()
+            .filter_map(|&value| policy.check(value, 100))
+            .map(|n| (n.direction, n.level))
+            .collect()
+    }
+
+    #[test]
+    fn notifies_only_on_crossing() {
+        let mut policy = Policy::default();
+
+        assert_eq!(
+            messages(&mut policy, &[10, 80, 85, 95, 96, 120, 80, 10]),
+            vec![
+                (Direction::Up, 0.75),
+                (Direction::Up, 0.9),
+                (Direction::Up, 1.0),
+                (Direction::Down, 0.9),
+                (Direction::Down, 0.75),
+            ]
+        );
+        assert_eq!(policy.fired(), &[false, false, false]);
+
+        policy.restore(&[true, false, false], 80, 100);
+        assert_eq!(messages(&mut policy, &[85, 95]), vec![(Direction::Up, 0.9)]);
+        policy.restore(&[true], 80, 100);
+        assert_eq!(policy.fired(), &[true, false, false]);
+    }
+
+    #[test]
+    fn hysteresis_stops_a_wobbling_value_repeating_itself() {
+        let mut policy = Policy::default().hysteresis(0.05);
+        assert_eq!(
+            messages(&mut policy, &[90, 89, 91, 86, 90, 84, 90]),
+            vec![
+                (Direction::Up, 0.9),
+                (Direction::Down, 0.9),
+                (Direction::Up, 0.9),
+            ]
+        );
+
+        let mut policy = Policy::default().hysteresis(0.0);
+        assert_eq!(messages(&mut policy, &[90, 89, 90]).len(), 3);
+    }
+
+    #[test]
+    fn custom_thresholds_and_messages() {
+        let mut policy = Policy::new(vec![
+            Threshold::new(1.0, Severity::Error, "full").cleared("room again"),
+            Threshold::new(0.5, Severity::Info, "half"),
+        ]);
+        assert_eq!(policy.thresholds()[0].message, "half");
+
+        let half = policy.check(5, 10).unwrap();
+        assert_eq!(
+            (half.severity, half.message.as_str()),
+            (Severity::Info, "half")
+        );
+        assert_eq!(policy.check(10, 10).unwrap().severity, Severity::Error);
+
+        let cleared = policy.check(6, 10).unwrap();
+        assert_eq!(cleared.message, "room again");
+        assert_eq!(cleared.severity, Severity::Info);
+        assert_eq!(
+            policy.check(0, 10).unwrap().message,
+            "Resolved: You are back under 50% of your quota."
+        );
+
+        assert_eq!(policy.check(1, 0).unwrap().message, "full");
+
+        for &(level, percent) in &[(0.07, "7"), (0.29, "29"), (0.57, "57"), (0.125, "12.5")] {
+            let threshold = Threshold::new(level, Severity::Warning, "over");
+            assert_eq!(
+                threshold.cleared_message(),
+                format!("Resolved: You are back under {}% of your quota.", percent)
+            );
+        }
+    }
+}
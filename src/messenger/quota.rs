@@ -0,0 +1,547 @@
+use super::{Key, Messenger, Policy, Saved, Store};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How often a quota starts again from zero. Windows follow the UTC calendar, so a daily quota
+/// resets at midnight UTC and a monthly one on the first of the month.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Window {
+    Hourly,
+    Daily,
+    Monthly,
+    /// For quotas such as storage that are a level rather than a rate.
+    Never,
+}
+
+impl Window {
+    /// A number identifying the window that `time` falls in; later windows have larger numbers.
+    pub fn period(self, time: SystemTime) -> u64 {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        match self {
+            Window::Hourly => secs / 3600,
+            Window::Daily => secs / 86_400,
+            Window::Monthly => {
+                let (year, month, _) = civil_date(secs / 86_400);
+                year * 12 + month - 1
+            }
+            Window::Never => 0,
+        }
+    }
+}
+
+/// The year, month and day of a day counted from 1970-01-01 (Howard Hinnant's algorithm).
+pub(super) fn civil_date(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum QuotaError {
+    /// The dimension wasn't declared when the registry was built.
+    UnknownDimension(String),
+}
+
+impl fmt::Display for QuotaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QuotaError::UnknownDimension(name) => write!(f, "unknown quota dimension `{}`", name),
+        }
+    }
+}
+
+impl Error for QuotaError {}
+
+/// A tenant's use of one dimension in the current window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    pub used: usize,
+    pub max: usize,
+}
+
+impl Usage {
+    pub fn remaining(&self) -> usize {
+        self.max.saturating_sub(self.used)
+    }
+
+    pub fn exceeded(&self) -> bool {
+        self.used > self.max
+    }
+}
+
+struct Dimension {
+    max: usize,
+    window: Window,
+    policy: Policy,
+}
+
+struct Quota {
+    window: Window,
+    max: AtomicUsize,
+    used: AtomicUsize,
+    /// The window `used` counts for. Changes to `used` hold the read lock, so moving to a new
+    /// window, which takes the write lock, can't lose an increment made as it happens.
+    period: RwLock<u64>,
+    policy: Mutex<Policy>,
+}
+
+impl Quota {
+    /// Moves the quota on to the window `time` is in, if it isn't there already, and returns the
+    /// read lock to hold while changing `used`.
+    fn current(&self, time: SystemTime) -> RwLockReadGuard<'_, u64> {
+        let period = self.window.period(time);
+        {
+            let current = self.period.read().unwrap();
+            // If the clock goes backwards, carry on counting in the later window.
+            if *current >= period {
+                return current;
+            }
+        }
+        {
+            let mut current = self.period.write().unwrap();
+            if *current < period {
+                *current = period;
+                self.used.store(0, Ordering::SeqCst);
+            }
+        }
+        self.period.read().unwrap()
+    }
+
+    fn usage(&self) -> Usage {
+        Usage {
+            used: self.used.load(Ordering::SeqCst),
+            max: self.max.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// Per-tenant quotas over several dimensions, such as requests, storage and bandwidth.
+///
+/// Each dimension is declared up front with a default maximum, a reset `Window` and a `Policy`;
+/// a tenant gets its own quota for a dimension the first time it's used. Every method takes
+/// `&self` and can be called from many threads at once: increments are atomic, and only the
+/// first use by a tenant and the start of a new window take an exclusive lock.
+///
+/// When a change crosses one of the policy's thresholds, the messenger is notified, with the
+/// tenant and dimension in front of the message.
+///
+/// With a `Store`, every change is saved too, which means taking the store's lock for each one.
+pub struct QuotaRegistry<M> {
+    messenger: M,
+    dimensions: HashMap<String, Dimension>,
+    quotas: RwLock<HashMap<(String, String), Arc<Quota>>>,
+    store: Option<Mutex<Store>>,
+}
+
+impl<M: Messenger> QuotaRegistry<M> {
+    pub fn new(messenger: M) -> QuotaRegistry<M> {
+        QuotaRegistry {
+            messenger,
+            dimensions: HashMap::new(),
+            quotas: RwLock::new(HashMap::new()),
+            store: None,
+        }
+    }
+
+    /// Declares a dimension with the default policy.
+    pub fn dimension(self, name: &str, max: usize, window: Window) -> QuotaRegistry<M> {
+        self.dimension_with_policy(name, max, window, Policy::default())
+    }
+
+    pub fn dimension_with_policy(
+        mut self,
+        name: &str,
+        max: usize,
+        window: Window,
+        policy: Policy,
+    ) -> QuotaRegistry<M> {
+        self.dimensions.insert(
+            String::from(name),
+            Dimension {
+                max,
+                window,
+                policy,
+            },
+        );
+        self
+    }
+
+    /// Keeps usage, limits and which thresholds have fired in `store`, starting from what it
+    /// already holds. Dimensions have to be declared first, and saved quotas for dimensions that
+    /// aren't are left alone. A registry that can't save says so on stderr and carries on.
+    pub fn persist(mut self, store: Store) -> QuotaRegistry<M> {
+        let quotas = self.quotas.get_mut().unwrap();
+        for (key, saved) in store.entries() {
+            let (tenant, dimension) = match key {
+                Key::Quota { tenant, dimension } => (tenant, dimension),
+                Key::Tracker(_) => continue,
+            };
+            let declared = match self.dimensions.get(dimension) {
+                Some(declared) => declared,
+                None => continue,
+            };
+
+            let mut policy = declared.policy.clone();
+            policy.restore(&saved.fired, saved.value, saved.max);
+            quotas.insert(
+                (tenant.clone(), dimension.clone()),
+                Arc::new(Quota {
+                    window: declared.window,
+                    max: AtomicUsize::new(saved.max),
+                    used: AtomicUsize::new(saved.value),
+                    period: RwLock::new(saved.period),
+                    policy: Mutex::new(policy),
+                }),
+            );
+        }
+        self.store = Some(Mutex::new(store));
+        self
+    }
+
+    /// Gives `tenant` a maximum other than the dimension's default.
+    pub fn set_limit(&self, tenant: &str, dimension: &str, max: usize) -> Result<(), QuotaError> {
+        let quota = self.quota(tenant, dimension)?;
+        let policy = quota.policy.lock().unwrap();
+        quota.max.store(max, Ordering::SeqCst);
+        // A new limit can put usage over or back under a threshold just as a change in usage can.
+        self.check(tenant, dimension, &quota, policy);
+        Ok(())
+    }
+
+    /// Adds `amount` to the tenant's use of `dimension`, returning the usage afterwards.
+    pub fn increment(
+        &self,
+        tenant: &str,
+        dimension: &str,
+        amount: usize,
+    ) -> Result<Usage, QuotaError> {
+        self.increment_at(tenant, dimension, amount, SystemTime::now())
+    }
+
+    pub fn increment_at(
+        &self,
+        tenant: &str,
+        dimension: &str,
+        amount: usize,
+        now: SystemTime,
+    ) -> Result<Usage, QuotaError> {
+        self.update(tenant, dimension, now, |used| {
+            used.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |u| {
+                Some(u.saturating_add(amount))
+            })
+            .unwrap();
+        })
+    }
+
+    /// Replaces the tenant's use of `dimension`, for levels like storage that go down as well as
+    /// up.
+    pub fn set(&self, tenant: &str, dimension: &str, value: usize) -> Result<Usage, QuotaError> {
+        self.set_at(tenant, dimension, value, SystemTime::now())
+    }
+
+    pub fn set_at(
+        &self,
+        tenant: &str,
+        dimension: &str,
+        value: usize,
+        now: SystemTime,
+    ) -> Result<Usage, QuotaError> {
+        self.update(tenant, dimension, now, |used| {
+            used.store(value, Ordering::SeqCst)
+        })
+    }
+
+    /// The tenant's use of `dimension` in the current window.
+    pub fn usage(&self, tenant: &str, dimension: &str) -> Result<Usage, QuotaError> {
+        self.usage_at(tenant, dimension, SystemTime::now())
+    }
+
+    pub fn usage_at(
+        &self,
+        tenant: &str,
+        dimension: &str,
+        now: SystemTime,
+    ) -> Result<Usage, QuotaError> {
+        let quota = self.quota(tenant, dimension)?;
+        let _period = quota.current(now);
+        Ok(quota.usage())
+    }
+
+    fn update<F>(
+        &self,
+        tenant: &str,
+        dimension: &str,
+        now: SystemTime,
+        change: F,
+    ) -> Result<Usage, QuotaError>
+    where
+        F: FnOnce(&AtomicUsize),
+    {
+        let quota = self.quota(tenant, dimension)?;
+        let usage = {
+            let _period = quota.current(now);
+            change(&quota.used);
+            quota.usage()
+        };
+
+        // Usage is read again under the policy's lock so that the policy sees changes in the
+        // order they were made, whichever thread gets here first.
+        let policy = quota.policy.lock().unwrap();
+        self.check(tenant, dimension, &quota, policy);
+        Ok(usage)
+    }
+
+    /// Runs the quota's policy over its latest usage, saves the outcome and sends any
+    /// notification, releasing the policy's lock before the messenger is called.
+    fn check(
+        &self,
+        tenant: &str,
+        dimension: &str,
+        quota: &Quota,
+        mut policy: MutexGuard<'_, Policy>,
+    ) {
+        let latest = quota.usage();
+        let notification = policy.check(latest.used, latest.max);
+        // Save before notifying, so that a crash in between can't lead to a second notification.
+        self.save(tenant, dimension, quota, &policy);
+        // A messenger can be slow, and shouldn't hold up other updates to this quota meanwhile.
+        drop(policy);
+        if let Some(mut notification) = notification {
+            notification.message = format!("{} {}: {}", tenant, dimension, notification.message);
+            self.messenger.notify(&notification);
+        }
+    }
+
+    /// Saves the quota's state, if there's a store. The caller holds the policy's lock, so saves
+    /// of the same quota happen in the order of the changes they record.
+    fn save(&self, tenant: &str, dimension: &str, quota: &Quota, policy: &Policy) {
+        let store = match &self.store {
+            Some(store) => store,
+            None => return,
+        };
+
+        let saved = {
+            // Holding the window's lock keeps usage from being reset while it's read.
+            let period = quota.period.read().unwrap();
+            let usage = quota.usage();
+            Saved {
+                value: usage.used,
+                max: usage.max,
+                period: *period,
+                fired: policy.fired().to_vec(),
+            }
+        };
+        let key = Key::Quota {
+            tenant: String::from(tenant),
+            dimension: String::from(dimension),
+        };
+        if let Err(e) = store.lock().unwrap().save(key, saved) {
+            eprintln!("could not save quota for {} {}: {}", tenant, dimension, e);
+        }
+    }
+
+    fn quota(&self, tenant: &str, dimension: &str) -> Result<Arc<Quota>, QuotaError> {
+        let key = (String::from(tenant), String::from(dimension));
+        if let Some(quota) = self.quotas.read().unwrap().get(&key) {
+            return Ok(Arc::clone(quota));
+        }
+
+        let declared = self
+            .dimensions
+            .get(dimension)
+            .ok_or_else(|| QuotaError::UnknownDimension(String::from(dimension)))?;
+        let mut quotas = self.quotas.write().unwrap();
+        let quota = quotas.entry(key).or_insert_with(|| {
+            Arc::new(Quota {
+                window: declared.window,
+                max: AtomicUsize::new(declared.max),
+                used: AtomicUsize::new(0),
+                period: RwLock::new(0),
+                policy: Mutex::new(declared.policy.clone()),
+            })
+        });
+        Ok(Arc::clone(quota))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    struct Collect(Mutex<Vec<String>>);
+
+    impl Messenger for Collect {
+        fn send(&self, msg: &str) {
+            self.0.lock().unwrap().push(String::from(msg));
+        }
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn windows_follow_the_calendar() {
+        // 2024-01-31 23:59:59 and 2024-02-01 00:00:00 UTC.
+        let before = at(1_706_745_599);
+        let after = at(1_706_745_600);
+
+        for &window in &[Window::Hourly, Window::Daily, Window::Monthly] {
+            assert_eq!(window.period(after), window.period(before) + 1);
+        }
+        assert_eq!(Window::Monthly.period(before), 2024 * 12);
+        assert_eq!(
+            Window::Monthly.period(at(1_706_745_600 + 29 * 86_400)),
+            2024 * 12 + 2
+        );
+        assert_eq!(Window::Never.period(after), Window::Never.period(at(0)));
+    }
+
+    #[test]
+    fn tracks_tenants_and_dimensions_separately_and_resets() {
+        let registry = QuotaRegistry::new(Collect(Mutex::new(vec![])))
+            .dimension("requests", 100, Window::Hourly)
+            .dimension("storage", 1000, Window::Never);
+        let start = at(3600 * 10);
+
+        registry
+            .increment_at("acme", "requests", 80, start)
+            .unwrap();
+        registry
+            .increment_at("globex", "requests", 5, start)
+            .unwrap();
+        registry.set_at("acme", "storage", 500, start).unwrap();
+        registry.set_limit("globex", "storage", 10).unwrap();
+
+        let acme = registry.usage_at("acme", "requests", start).unwrap();
+        assert_eq!((acme.used, acme.remaining()), (80, 20));
+        assert_eq!(
+            registry.usage_at("globex", "storage", start).unwrap().max,
+            10
+        );
+
+        let later = start + Duration::from_secs(3600);
+        assert_eq!(
+            registry.usage_at("acme", "requests", later).unwrap().used,
+            0
+        );
+        assert_eq!(
+            registry.usage_at("acme", "storage", later).unwrap().used,
+            500
+        );
+        assert_eq!(
+            registry
+                .increment_at("acme", "requests", 1, later)
+                .unwrap()
+                .used,
+            1
+        );
+
+        assert_eq!(
+            registry.increment("acme", "cpu", 1),
+            Err(QuotaError::UnknownDimension(String::from("cpu")))
+        );
+        assert_eq!(
+            *registry.messenger.0.lock().unwrap(),
+            vec![
+                "acme requests: Warning: You've used up over 75% of your quota!",
+                "acme requests: Resolved: You are back under 75% of your quota.",
+            ]
+        );
+    }
+
+    #[test]
+    fn persists_usage_limits_and_fired_thresholds() {
+        let dir = std::env::temp_dir().join(format!("quota-store-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let registry = |dir: &std::path::Path| {
+            QuotaRegistry::new(Collect(Mutex::new(vec![])))
+                .dimension("requests", 100, Window::Hourly)
+                .persist(Store::open(dir).unwrap())
+        };
+        let start = at(3600 * 10);
+
+        let before = registry(&dir);
+        before.increment_at("acme", "requests", 80, start).unwrap();
+        before.set_limit("globex", "requests", 10).unwrap();
+        assert_eq!(before.messenger.0.lock().unwrap().len(), 1);
+        drop(before);
+
+        let after = registry(&dir);
+        let usage = after.increment_at("acme", "requests", 5, start).unwrap();
+        assert_eq!(usage.used, 85);
+        assert_eq!(after.usage_at("globex", "requests", start).unwrap().max, 10);
+        assert!(after.messenger.0.lock().unwrap().is_empty());
+
+        let later = start + Duration::from_secs(3600);
+        assert_eq!(after.usage_at("acme", "requests", later).unwrap().used, 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn notifies_when_a_new_limit_crosses_a_threshold() {
+        let registry = QuotaRegistry::new(Collect(Mutex::new(vec![]))).dimension(
+            "storage",
+            1000,
+            Window::Never,
+        );
+        registry.set("acme", "storage", 500).unwrap();
+        assert!(registry.messenger.0.lock().unwrap().is_empty());
+
+        registry.set_limit("acme", "storage", 600).unwrap();
+        registry.set_limit("acme", "storage", 1000).unwrap();
+        assert_eq!(
+            *registry.messenger.0.lock().unwrap(),
+            vec![
+                "acme storage: Warning: You've used up over 75% of your quota!",
+                "acme storage: Resolved: You are back under 75% of your quota.",
+            ]
+        );
+    }
+
+    #[test]
+    fn increments_from_many_threads() {
+        let registry = Arc::new(QuotaRegistry::new(Collect(Mutex::new(vec![]))).dimension(
+            "requests",
+            8000,
+            Window::Daily,
+        ));
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let registry = Arc::clone(&registry);
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        registry.increment_at("acme", "requests", 1, at(0)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(
+            registry.usage_at("acme", "requests", at(0)).unwrap().used,
+            8000
+        );
+        assert_eq!(registry.messenger.0.lock().unwrap().len(), 3);
+    }
+}
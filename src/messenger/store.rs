@@ -0,0 +1,571 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+
+const SNAPSHOT_MAGIC: &[u8] = b"limits snapshot 2\n";
+const LOG_MAGIC: &[u8] = b"limits log 2\n";
+/// A record's length, the CRC-32 of the length, and the CRC-32 of the payload.
+const HEADER_LEN: usize = 12;
+/// The longest record payload there can be. Real ones are tiny, so a longer length means damage.
+const MAX_RECORD_LEN: usize = 1 << 20;
+
+/// What saved state belongs to.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Key {
+    /// A `LimitTracker`, by the name it was given.
+    Tracker(String),
+    /// One of a `QuotaRegistry`'s quotas.
+    Quota { tenant: String, dimension: String },
+}
+
+/// The state of a tracker or quota.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Saved {
+    pub value: usize,
+    pub max: usize,
+    /// The reset window the value counts for; see `Window::period`.
+    pub period: u64,
+    /// Which of the policy's thresholds have fired, lowest first.
+    pub fired: Vec<bool>,
+}
+
+/// The CRC-32 of `bytes`, as used by gzip and PNG.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// A record as stored: its length, a CRC-32 of that and one of the payload, then the payload with
+/// the key and state. The length has a checksum of its own so that a record cut short can be told
+/// from one whose length has been damaged.
+fn encode(key: &Key, saved: &Saved) -> Vec<u8> {
+    fn string(out: &mut Vec<u8>, s: &str) {
+        out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+        out.extend_from_slice(s.as_bytes());
+    }
+
+    let mut payload = Vec::new();
+    match key {
+        Key::Tracker(name) => {
+            payload.push(0);
+            string(&mut payload, name);
+        }
+        Key::Quota { tenant, dimension } => {
+            payload.push(1);
+            string(&mut payload, tenant);
+            string(&mut payload, dimension);
+        }
+    }
+    payload.extend_from_slice(&(saved.value as u64).to_le_bytes());
+    payload.extend_from_slice(&(saved.max as u64).to_le_bytes());
+    payload.extend_from_slice(&saved.period.to_le_bytes());
+    payload.extend_from_slice(&(saved.fired.len() as u32).to_le_bytes());
+    payload.extend(saved.fired.iter().map(|&fired| fired as u8));
+
+    let len = (payload.len() as u32).to_le_bytes();
+    let mut record = Vec::with_capacity(payload.len() + HEADER_LEN);
+    record.extend_from_slice(&len);
+    record.extend_from_slice(&crc32(&len).to_le_bytes());
+    record.extend_from_slice(&crc32(&payload).to_le_bytes());
+    record.extend_from_slice(&payload);
+    record
+}
+
+/// Reads a record's payload back, or `None` if it doesn't make sense.
+fn decode(payload: &[u8]) -> Option<(Key, Saved)> {
+    struct Reader<'a>(&'a [u8]);
+
+    impl<'a> Reader<'a> {
+        fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+            if self.0.len() < n {
+                return None;
+            }
+            let (taken, rest) = self.0.split_at(n);
+            self.0 = rest;
+            Some(taken)
+        }
+
+        fn u32(&mut self) -> Option<u32> {
+            Some(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+        }
+
+        fn u64(&mut self) -> Option<u64> {
+            Some(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+        }
+
+        fn usize(&mut self) -> Option<usize> {
+            self.u64()?.try_into().ok()
+        }
+
+        fn string(&mut self) -> Option<String> {
+            let len = self.u32()? as usize;
+            String::from_utf8(self.take(len)?.to_vec()).ok()
+        }
+    }
+
+    let mut reader = Reader(payload);
+    let key = match reader.take(1)?[0] {
+        0 => Key::Tracker(reader.string()?),
+        1 => Key::Quota {
+            tenant: reader.string()?,
+            dimension: reader.string()?,
+        },
+        _ => return None,
+    };
+    let value = reader.usize()?;
+    let max = reader.usize()?;
+    let period = reader.u64()?;
+    let count = reader.u32()? as usize;
+    let mut fired = Vec::new();
+    for &byte in reader.take(count)? {
+        match byte {
+            0 => fired.push(false),
+            1 => fired.push(true),
+            _ => return None,
+        }
+    }
+    if !reader.0.is_empty() {
+        return None;
+    }
+
+    Some((
+        key,
+        Saved {
+            value,
+            max,
+            period,
+            fired,
+        },
+    ))
+}
+
+/// How reading a file of records went.
+struct Read {
+    records: usize,
+    /// Where the file ends part way through a record, or with a last record that fails its
+    /// checksum, which is what a crash while appending leaves behind. The records before this
+    /// offset are good.
+    torn: Option<u64>,
+}
+
+fn corrupt(path: &Path, what: &str, offset: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "{}: {} at byte {}; the limit store is corrupt",
+            path.display(),
+            what,
+            offset
+        ),
+    )
+}
+
+/// Reads the records in `bytes`, which came from `path`, into `state`.
+fn read_records(
+    path: &Path,
+    bytes: &[u8],
+    magic: &[u8],
+    state: &mut BTreeMap<Key, Saved>,
+) -> io::Result<Read> {
+    if !bytes.starts_with(magic) {
+        return Err(corrupt(path, "no header", 0));
+    }
+
+    let mut offset = magic.len();
+    let mut records = 0;
+    while offset < bytes.len() {
+        let rest = &bytes[offset..];
+        let header = match rest.get(..HEADER_LEN) {
+            Some(header) => header,
+            None => break,
+        };
+        let word = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+        let (len, crc) = (word(0) as usize, word(8));
+        // Taking a damaged length for a torn tail would throw away every record after it.
+        if crc32(&header[..4]) != word(4) || len > MAX_RECORD_LEN {
+            return Err(corrupt(path, "bad record length", offset));
+        }
+        let end = HEADER_LEN + len;
+        let payload = match rest.get(HEADER_LEN..end) {
+            Some(payload) => payload,
+            None => break,
+        };
+        if crc32(payload) != crc {
+            // A bad last record may have been left by a crash. Anything after it, though, means
+            // the damage is in the middle.
+            if end == rest.len() {
+                break;
+            }
+            return Err(corrupt(path, "checksum mismatch", offset));
+        }
+        let (key, saved) = decode(payload).ok_or_else(|| corrupt(path, "bad record", offset))?;
+        state.insert(key, saved);
+        records += 1;
+        offset += end;
+    }
+
+    Ok(Read {
+        records,
+        torn: if offset < bytes.len() {
+            Some(offset as u64)
+        } else {
+            None
+        },
+    })
+}
+
+/// Writes `bytes` to `path` by way of a temporary file, so that `path` is never half written.
+fn replace(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let temp = path.with_extension("tmp");
+    let mut file = File::create(&temp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(temp, path)
+}
+
+/// Saved tracker and quota state in a directory, so that it survives restarts.
+///
+/// Every change is appended to a log, and once the log has grown long enough everything is written
+/// out afresh as a snapshot and the log is started again. Records carry a CRC-32, and opening a
+/// store that has been damaged fails rather than quietly forgetting usage. The exception is a
+/// last record in the log that is cut short or fails its checksum, as a crash while saving can
+/// leave, which is dropped.
+///
+/// By default records are written but not synced, which survives the process dying but not the
+/// machine; see `sync`.
+pub struct Store {
+    dir: PathBuf,
+    log: File,
+    /// How long the log is up to the end of the last record saved whole.
+    len: u64,
+    /// Whether a failed save may have left part of a record after `len`.
+    torn: bool,
+    state: BTreeMap<Key, Saved>,
+    logged: usize,
+    compact_after: usize,
+    sync: bool,
+}
+
+impl Store {
+    /// Opens the store in `dir`, creating it if need be.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Store> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut state = BTreeMap::new();
+        let snapshot = dir.join("snapshot");
+        match fs::read(&snapshot) {
+            Ok(bytes) => {
+                let read = read_records(&snapshot, &bytes, SNAPSHOT_MAGIC, &mut state)?;
+                if let Some(offset) = read.torn {
+                    // Snapshots are renamed into place complete, so this isn't a crash.
+                    return Err(corrupt(&snapshot, "truncated record", offset as usize));
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let path = dir.join("log");
+        let mut logged = 0;
+        match fs::read(&path) {
+            Ok(bytes) => {
+                let read = read_records(&path, &bytes, LOG_MAGIC, &mut state)?;
+                logged = read.records;
+                if let Some(offset) = read.torn {
+                    OpenOptions::new()
+                        .write(true)
+                        .open(&path)?
+                        .set_len(offset)?;
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => replace(&path, LOG_MAGIC)?,
+            Err(e) => return Err(e),
+        }
+        let log = OpenOptions::new().append(true).open(&path)?;
+        let len = log.metadata()?.len();
+
+        Ok(Store {
+            dir,
+            log,
+            len,
+            torn: false,
+            state,
+            logged,
+            compact_after: 10_000,
+            sync: false,
+        })
+    }
+
+    /// Write a fresh snapshot once the log holds `records` records. The default is 10,000.
+    pub fn compact_after(mut self, records: usize) -> Store {
+        self.compact_after = records;
+        self
+    }
+
+    /// Whether to sync each record to disk as it's saved, so that it survives a power cut too.
+    pub fn sync(mut self, sync: bool) -> Store {
+        self.sync = sync;
+        self
+    }
+
+    pub fn get(&self, key: &Key) -> Option<&Saved> {
+        self.state.get(key)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&Key, &Saved)> {
+        self.state.iter()
+    }
+
+    /// Records the state for `key`, unless that's what is saved already.
+    pub fn save(&mut self, key: Key, saved: Saved) -> io::Result<()> {
+        if self.state.get(&key) == Some(&saved) {
+            return Ok(());
+        }
+
+        let record = encode(&key, &saved);
+        if record.len() - HEADER_LEN > MAX_RECORD_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "key too long to save",
+            ));
+        }
+        // Part of a record left by a failed save would read as damage once more were appended
+        // after it, so it goes first.
+        if self.torn {
+            self.log.set_len(self.len)?;
+            self.torn = false;
+        }
+        let written = self.log.write_all(&record).and_then(|()| {
+            if self.sync {
+                self.log.sync_data()
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(e) = written {
+            self.torn = self.log.set_len(self.len).is_err();
+            return Err(e);
+        }
+        self.len += record.len() as u64;
+        self.state.insert(key, saved);
+        self.logged += 1;
+
+        if self.logged >= self.compact_after {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Writes everything to a new snapshot and empties the log.
+    pub fn compact(&mut self) -> io::Result<()> {
+        let mut snapshot = SNAPSHOT_MAGIC.to_vec();
+        for (key, saved) in &self.state {
+            snapshot.extend_from_slice(&encode(key, saved));
+        }
+        // If this is interrupted between the two, the old log is replayed over the new snapshot,
+        // which does no harm as both hold whole states rather than changes.
+        replace(&self.dir.join("snapshot"), &snapshot)?;
+        replace(&self.dir.join("log"), LOG_MAGIC)?;
+
+        self.log = OpenOptions::new().append(true).open(self.dir.join("log"))?;
+        self.len = LOG_MAGIC.len() as u64;
+        self.torn = false;
+        self.logged = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("limit-store-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn quota(tenant: &str) -> Key {
+        Key::Quota {
+            tenant: String::from(tenant),
+            dimension: String::from("requests"),
+        }
+    }
+
+    fn saved(value: usize) -> Saved {
+        Saved {
+            value,
+            max: 100,
+            period: 7,
+            fired: vec![value >= 75, value >= 90, false],
+        }
+    }
+
+    #[test]
+    fn state_survives_reopening_and_compaction() {
+        let dir = scratch("reopen");
+        let mut store = Store::open(&dir).unwrap().compact_after(4);
+        store
+            .save(Key::Tracker(String::from("api")), saved(80))
+            .unwrap();
+        for value in 0..10 {
+            store.save(quota("acme"), saved(value * 10)).unwrap();
+        }
+        store.save(quota("globex"), saved(5)).unwrap();
+        drop(store);
+
+        let store = Store::open(&dir).unwrap();
+        assert_eq!(store.get(&quota("acme")), Some(&saved(90)));
+        assert_eq!(
+            store.get(&Key::Tracker(String::from("api"))),
+            Some(&saved(80))
+        );
+        assert_eq!(store.entries().count(), 3);
+        assert!(fs::metadata(dir.join("log")).unwrap().len() < 200);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn drops_a_record_cut_short_at_the_end() {
+        let dir = scratch("torn");
+        let mut store = Store::open(&dir).unwrap();
+        store.save(quota("acme"), saved(10)).unwrap();
+        store.save(quota("acme"), saved(20)).unwrap();
+        drop(store);
+
+        let log = dir.join("log");
+        let bytes = fs::read(&log).unwrap();
+        fs::write(&log, &bytes[..bytes.len() - 3]).unwrap();
+        let mut store = Store::open(&dir).unwrap();
+        assert_eq!(store.get(&quota("acme")), Some(&saved(10)));
+        store.save(quota("acme"), saved(20)).unwrap();
+        drop(store);
+
+        // A crash can also leave the end of the last record garbled, or part of a header after it.
+        let mut bytes = fs::read(&log).unwrap();
+        let len = bytes.len();
+        for byte in &mut bytes[len - 3..] {
+            *byte ^= 0xff;
+        }
+        fs::write(&log, &bytes).unwrap();
+        let mut store = Store::open(&dir).unwrap();
+        assert_eq!(store.get(&quota("acme")), Some(&saved(10)));
+        store.save(quota("acme"), saved(20)).unwrap();
+        drop(store);
+
+        let mut file = OpenOptions::new().append(true).open(&log).unwrap();
+        file.write_all(&[1, 2, 3]).unwrap();
+        drop(file);
+        let mut store = Store::open(&dir).unwrap();
+        assert_eq!(store.get(&quota("acme")), Some(&saved(20)));
+        store.save(quota("acme"), saved(10)).unwrap();
+        drop(store);
+
+        let mut store = Store::open(&dir).unwrap();
+        assert_eq!(store.get(&quota("acme")), Some(&saved(10)));
+        store.save(quota("acme"), saved(30)).unwrap();
+        drop(store);
+        assert_eq!(
+            Store::open(&dir).unwrap().get(&quota("acme")),
+            Some(&saved(30))
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn drops_what_a_failed_save_wrote_before_saving_again() {
+        let dir = scratch("failed");
+        let mut store = Store::open(&dir).unwrap();
+        store.save(quota("acme"), saved(10)).unwrap();
+
+        // As if a write had run out of space part way through a record and couldn't be undone.
+        let record = encode(&quota("acme"), &saved(20));
+        let log = dir.join("log");
+        let mut file = OpenOptions::new().append(true).open(&log).unwrap();
+        file.write_all(&record[..record.len() / 2]).unwrap();
+        store.torn = true;
+
+        store.save(quota("acme"), saved(30)).unwrap();
+        store.save(quota("other"), saved(40)).unwrap();
+        drop(store);
+        let store = Store::open(&dir).unwrap();
+        assert_eq!(store.get(&quota("acme")), Some(&saved(30)));
+        assert_eq!(store.get(&quota("other")), Some(&saved(40)));
+        assert_eq!(store.len, fs::metadata(&log).unwrap().len());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_a_damaged_record_length_rather_than_dropping_what_follows() {
+        let dir = scratch("length");
+        let mut store = Store::open(&dir).unwrap();
+        for tenant in &["a", "b", "c", "d", "e"] {
+            store.save(quota(tenant), saved(10)).unwrap();
+        }
+        drop(store);
+
+        let log = dir.join("log");
+        let mut bytes = fs::read(&log).unwrap();
+        let record = encode(&quota("a"), &saved(10)).len();
+        // The length itself, and its checksum.
+        for &byte in &[0, 3, 4] {
+            let mut damaged = bytes.clone();
+            damaged[LOG_MAGIC.len() + record + byte] = 0xff;
+            fs::write(&log, &damaged).unwrap();
+            let error = Store::open(&dir).err().unwrap();
+            assert!(error.to_string().contains("bad record length"));
+            assert_eq!(fs::read(&log).unwrap(), damaged);
+        }
+
+        bytes[LOG_MAGIC.len() + record] -= 1;
+        fs::write(&log, &bytes).unwrap();
+        let error = Store::open(&dir).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("bad record length"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn detects_corruption() {
+        let dir = scratch("corrupt");
+        let mut store = Store::open(&dir).unwrap();
+        store.save(quota("acme"), saved(10)).unwrap();
+        store.save(quota("globex"), saved(20)).unwrap();
+        drop(store);
+
+        let log = dir.join("log");
+        let mut bytes = fs::read(&log).unwrap();
+        bytes[LOG_MAGIC.len() + HEADER_LEN] ^= 1;
+        fs::write(&log, &bytes).unwrap();
+        let error = Store::open(&dir).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("checksum mismatch at byte 13"));
+
+        bytes[LOG_MAGIC.len() + HEADER_LEN] ^= 1;
+        fs::write(&log, &bytes).unwrap();
+        Store::open(&dir).unwrap().compact().unwrap();
+        let snapshot = dir.join("snapshot");
+        let mut bytes = fs::read(&snapshot).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        fs::write(&snapshot, &bytes).unwrap();
+        assert_eq!(
+            Store::open(&dir).err().unwrap().kind(),
+            io::ErrorKind::InvalidData
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
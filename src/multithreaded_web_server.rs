@@ -8,6 +8,24 @@ use std::sync::Mutex;
 use std::thread;
 use std::time::Duration;
 
+mod cgi;
+pub mod cookie;
+mod http;
+mod range;
+mod rate_limit;
+mod router;
+pub mod session;
+mod sha256;
+mod static_files;
+mod virtual_hosts;
+
+pub use self::cgi::Cgi;
+pub use self::http::{http_date, reason_phrase, Handler, Request, Response};
+pub use self::rate_limit::{ClientKey, Decision, RateLimiter};
+pub use self::router::Router;
+pub use self::static_files::StaticFiles;
+pub use self::virtual_hosts::VirtualHosts;
+
 pub fn webserver_main() {
     let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
     let pool = ThreadPool::new(4);
@@ -61,6 +79,44 @@ fn handle_connection(mut stream: TcpStream) {
     // println!("Request: {}", String::from_utf8_lossy(&buffer[..]));
 }
 
+/// Accept connections on `listener` and answer each one on the pool with `handler`.
+///
+/// Every connection carries a single request and is closed once the response has been written.
+pub fn serve<H>(listener: TcpListener, pool: &ThreadPool, handler: H)
+where
+    H: Handler + 'static,
+{
+    let handler = Arc::new(handler);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        let handler = Arc::clone(&handler);
+        pool.execute(move || {
+            serve_connection(stream, &*handler);
+        });
+    }
+}
+
+fn serve_connection(mut stream: TcpStream, handler: &dyn Handler) {
+    let mut response = match Request::read_from(&mut stream) {
+        Ok(mut request) => {
+            request.remote_addr = stream.peer_addr().ok();
+            handler.handle(&mut request)
+        }
+        Err(_) => Response::new(400),
+    };
+    // A handler's bad header is our fault, not the client's, and it still deserves an answer.
+    if response.check_headers().is_err() {
+        response = Response::new(500);
+    }
+
+    // The client may already have gone away; there is nobody left to report that to.
+    response.write_to(&mut stream).ok();
+}
+
 // Uniform Resource Identifier (URI)
 // TcpStream contains an internal buffer to minimize calls to the underlying operating system.
 
@@ -226,3 +282,27 @@ impl Worker {
 // The F type parameter also has the trait bound Send and the lifetime bound 'static, which are useful
 // in our situation: we need Send to transfer the closure from one thread to another and 'static
 // because we don’t know how long the thread will take to execute.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bad_header(_: &mut Request) -> Response {
+        Response::new(302).with_header("Location", "/\r\nSet-Cookie: admin=1")
+    }
+
+    #[test]
+    fn answers_a_bad_header_from_the_handler_with_an_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        client.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        serve_connection(stream, &bad_header);
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 500 "));
+        assert!(!response.contains("admin"));
+    }
+}
@@ -0,0 +1,412 @@
+use super::http::{Handler, Request, Response};
+use std::io::prelude::*;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Scripts that print more than this get `502 Bad Gateway` instead of being buffered forever.
+const MAX_OUTPUT_LEN: usize = 16 * 1024 * 1024;
+
+/// Runs executables from a directory as CGI/1.1 scripts, one process per request.
+///
+/// Mount it under a prefix, e.g. `Router::new().mount("/cgi-bin", Cgi::new("scripts"))`. The
+/// first path segment after the prefix names the script and the rest becomes `PATH_INFO`. The
+/// request body is piped to the script's stdin and its stdout is parsed as CGI headers followed by
+/// the response body. A script that hasn't finished within the timeout is killed and the client
+/// gets `504 Gateway Timeout`.
+pub struct Cgi {
+    dir: PathBuf,
+    timeout: Duration,
+}
+
+impl Cgi {
+    /// Scripts get 30 seconds by default. A relative `dir` is taken from the current directory.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Cgi {
+        let dir = dir.into();
+        // Scripts run from inside `dir`, so spawning them by a relative path would resolve it a
+        // second time from there.
+        let dir = match std::env::current_dir() {
+            Ok(cwd) => cwd.join(dir),
+            Err(_) => dir,
+        };
+        Cgi {
+            dir,
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Cgi {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Split the request path into the script file and `PATH_INFO`.
+    fn resolve(&self, path: &str) -> Option<(String, PathBuf, String)> {
+        let path = path.trim_start_matches('/');
+        let (name, path_info) = match path.find('/') {
+            Some(i) => (&path[..i], &path[i..]),
+            None => (path, ""),
+        };
+
+        let mut components = Path::new(name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => {}
+            _ => return None,
+        }
+
+        let script = self.dir.join(name);
+        if script.is_file() {
+            Some((name.to_string(), script, path_info.to_string()))
+        } else {
+            None
+        }
+    }
+
+    fn environment(&self, request: &Request, name: &str, path_info: &str) -> Vec<(String, String)> {
+        let host = request.header("Host").unwrap_or("localhost");
+        let (server_name, server_port) = match host.rfind(':') {
+            Some(i) if !host.ends_with(']') => (&host[..i], &host[i + 1..]),
+            _ => (host, "80"),
+        };
+
+        let mut env = vec![
+            ("GATEWAY_INTERFACE", String::from("CGI/1.1")),
+            ("SERVER_SOFTWARE", String::from("SkRustLearn")),
+            ("SERVER_PROTOCOL", request.version.clone()),
+            ("SERVER_NAME", server_name.to_string()),
+            ("SERVER_PORT", server_port.to_string()),
+            ("REQUEST_METHOD", request.method.clone()),
+            ("SCRIPT_NAME", format!("{}/{}", request.base_path, name)),
+            ("PATH_INFO", path_info.to_string()),
+            ("QUERY_STRING", request.query.clone().unwrap_or_default()),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect::<Vec<_>>();
+
+        if let Some(addr) = request.remote_addr {
+            env.push((String::from("REMOTE_ADDR"), addr.ip().to_string()));
+            env.push((String::from("REMOTE_PORT"), addr.port().to_string()));
+        }
+        if !request.body.is_empty() {
+            env.push((String::from("CONTENT_LENGTH"), request.body.len().to_string()));
+        }
+        if let Some(content_type) = request.header("Content-Type") {
+            env.push((String::from("CONTENT_TYPE"), content_type.to_string()));
+        }
+        if let Ok(path) = std::env::var("PATH") {
+            env.push((String::from("PATH"), path));
+        }
+
+        for (header, value) in &request.headers {
+            // A name spelt with `_` would come out the same as the one with `-`, letting a client
+            // pass off its own value as one a proxy in front of us added.
+            if header.contains('_') {
+                continue;
+            }
+            let header = header.to_ascii_uppercase().replace('-', "_");
+            // These are already covered above, passing Authorization on would leak credentials to
+            // every script, and scripts take HTTP_PROXY for the proxy to make requests through.
+            if header == "CONTENT_LENGTH"
+                || header == "CONTENT_TYPE"
+                || header == "AUTHORIZATION"
+                || header == "PROXY"
+            {
+                continue;
+            }
+            env.push((format!("HTTP_{}", header), value.clone()));
+        }
+        env
+    }
+
+    fn run(&self, request: &Request, name: &str, script: &Path, path_info: &str) -> Response {
+        let mut command = Command::new(script);
+        command
+            .current_dir(&self.dir)
+            .env_clear()
+            .envs(self.environment(request, name, path_info))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+        // In a process group of its own, so whatever the script starts can be killed with it.
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(_) => return Response::new(500),
+        };
+        let deadline = Instant::now() + self.timeout;
+
+        // Feed stdin and drain stdout on their own threads: a script that writes a lot before
+        // reading its input would otherwise deadlock against us.
+        let mut stdin = child.stdin.take().unwrap();
+        let body = request.body.clone();
+        thread::spawn(move || {
+            stdin.write_all(&body).ok();
+        });
+
+        let stdout = child.stdout.take().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut output = Vec::new();
+            let result = stdout
+                .take(MAX_OUTPUT_LEN as u64 + 1)
+                .read_to_end(&mut output)
+                .map(|_| output);
+            sender.send(result).ok();
+        });
+
+        let output = receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()));
+        let response = match output {
+            Ok(Ok(output)) if output.len() > MAX_OUTPUT_LEN => Response::new(502),
+            Ok(Ok(output)) if wait_until(&mut child, deadline) => return parse_output(&output),
+            Ok(Err(_)) => Response::new(502),
+            _ => Response::new(504).with_body("Gateway Timeout"),
+        };
+        kill(&mut child);
+        response
+    }
+}
+
+/// Wait for the child to exit, giving up once the deadline has passed.
+fn wait_until(child: &mut Child, deadline: Instant) -> bool {
+    loop {
+        match child.try_wait() {
+            Ok(Some(_)) => return true,
+            Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+            _ => return false,
+        }
+    }
+}
+
+/// Kill a script that hasn't been waited for, along with everything in its process group: `sh`
+/// runs a script's last command as a child instead of replacing itself with it.
+fn kill(child: &mut Child) {
+    #[cfg(unix)]
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    #[cfg(not(unix))]
+    child.kill().ok();
+    child.wait().ok();
+}
+
+/// Turn a script's output into a response (RFC 3875, section 6).
+///
+/// The `Status` header sets the status code, a bare `Location` means a redirect and every other
+/// header is passed through. Output without a header block is a broken script: `502 Bad Gateway`.
+fn parse_output(output: &[u8]) -> Response {
+    let (head_len, body_start) = match find_blank_line(output) {
+        Some(split) => split,
+        None => return Response::new(502),
+    };
+    let head = String::from_utf8_lossy(&output[..head_len]);
+
+    let mut status = None;
+    let mut response = Response::new(200);
+    for line in head.lines().filter(|l| !l.is_empty()) {
+        let i = match line.find(':') {
+            Some(i) => i,
+            None => return Response::new(502),
+        };
+        let (name, value) = (line[..i].trim(), line[i + 1..].trim());
+
+        if name.eq_ignore_ascii_case("Status") {
+            status = value.split_whitespace().next().and_then(|s| s.parse().ok());
+            if status.is_none() {
+                return Response::new(502);
+            }
+        } else {
+            response = response.with_header(name, value);
+        }
+    }
+
+    response.status = match status {
+        Some(status) => status,
+        None if response.header("Location").is_some() => 302,
+        None if response.header("Content-Type").is_some() => 200,
+        None => return Response::new(502),
+    };
+    response.with_body(&output[body_start..])
+}
+
+// Scripts may end lines with either LF or CRLF. Returns where the head ends and the body starts.
+fn find_blank_line(output: &[u8]) -> Option<(usize, usize)> {
+    (0..output.len()).find_map(|i| {
+        if output[i..].starts_with(b"\r\n\r\n") {
+            Some((i, i + 4))
+        } else if output[i..].starts_with(b"\n\n") {
+            Some((i, i + 2))
+        } else if output[i..].starts_with(b"\n\r\n") {
+            Some((i, i + 3))
+        } else {
+            None
+        }
+    })
+}
+
+impl Handler for Cgi {
+    fn handle(&self, request: &mut Request) -> Response {
+        match self.resolve(&request.path) {
+            Some((name, script, path_info)) => self.run(request, &name, &script, &path_info),
+            None => Response::new(404).with_body("Not Found"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_status_location_and_plain_output() {
+        let response = parse_output(b"Status: 404 Not Found\nContent-Type: text/plain\n\nnope");
+        assert_eq!(response.status, 404);
+        assert_eq!(response.body, b"nope");
+
+        assert_eq!(parse_output(b"Location: /elsewhere\r\n\r\n").status, 302);
+        assert_eq!(parse_output(b"just some text").status, 502);
+    }
+
+    #[test]
+    fn passes_headers_on_except_those_a_script_could_be_misled_by() {
+        let request = Request::new("GET", "/cgi-bin/env.sh")
+            .with_header("X-Forwarded-For", "10.0.0.1")
+            .with_header("X_Forwarded_For", "127.0.0.1")
+            .with_header("Proxy", "http://evil.example:8080")
+            .with_header("Authorization", "Basic c2VjcmV0")
+            .with_header("Accept", "text/html");
+        let env = Cgi::new(".").environment(&request, "env.sh", "");
+        let headers: Vec<_> = env.iter().filter(|(k, _)| k.starts_with("HTTP_")).collect();
+
+        assert_eq!(
+            headers,
+            vec![
+                &(String::from("HTTP_X_FORWARDED_FOR"), String::from("10.0.0.1")),
+                &(String::from("HTTP_ACCEPT"), String::from("text/html")),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    mod scripts {
+        use super::super::*;
+        use crate::multithreaded_web_server::Router;
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        fn script_dir(name: &str, source: &str) -> PathBuf {
+            let dir = std::env::temp_dir().join(format!("cgi-{}-{}", name, std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join(name);
+            fs::write(&path, source).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            dir
+        }
+
+        #[test]
+        fn passes_environment_and_body() {
+            let dir = script_dir(
+                "echo.sh",
+                "#!/bin/sh\nprintf 'Content-Type: text/plain\\n\\n'\n\
+                 printf '%s %s %s %s|' \"$REQUEST_METHOD\" \"$SCRIPT_NAME\" \"$PATH_INFO\" \"$QUERY_STRING\"\n\
+                 printf '%s|' \"$HTTP_X_TOKEN\"\ncat\n",
+            );
+            let router = Router::new().mount("/cgi-bin", Cgi::new(&dir));
+
+            let mut request = Request::new("POST", "/cgi-bin/echo.sh/extra?q=1")
+                .with_header("X-Token", "t");
+            request.body = b"payload".to_vec();
+            let response = router.handle(&mut request);
+
+            assert_eq!(response.status, 200);
+            assert_eq!(
+                String::from_utf8(response.body).unwrap(),
+                "POST /cgi-bin/echo.sh /extra q=1|t|payload"
+            );
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn kills_scripts_that_run_too_long() {
+            let dir = script_dir("slow.sh", "#!/bin/sh\nsleep 5\n");
+            let cgi = Cgi::new(&dir).timeout(Duration::from_millis(200));
+
+            let started = Instant::now();
+            let response = cgi.handle(&mut Request::new("GET", "/slow.sh"));
+
+            assert_eq!(response.status, 504);
+            assert!(started.elapsed() < Duration::from_secs(2));
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn kills_what_a_script_started_when_it_times_out() {
+            let dir = script_dir(
+                "spawns.sh",
+                "#!/bin/sh\nsleep 5 &\necho $! > \"$0.pid\"\nwait\n",
+            );
+            let cgi = Cgi::new(&dir).timeout(Duration::from_millis(200));
+
+            let response = cgi.handle(&mut Request::new("GET", "/spawns.sh"));
+            assert_eq!(response.status, 504);
+
+            let pid = fs::read_to_string(dir.join("spawns.sh.pid")).unwrap();
+            let alive = || {
+                std::process::Command::new("kill")
+                    .args(&["-0", pid.trim()])
+                    .stderr(Stdio::null())
+                    .status()
+                    .unwrap()
+                    .success()
+            };
+            let started = Instant::now();
+            while alive() && started.elapsed() < Duration::from_secs(2) {
+                thread::sleep(Duration::from_millis(10));
+            }
+            assert!(!alive());
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn refuses_output_that_is_too_long() {
+            let dir = script_dir(
+                "yes.sh",
+                "#!/bin/sh\nprintf 'Content-Type: text/plain\\n\\n'\nyes\n",
+            );
+            let cgi = Cgi::new(&dir);
+
+            let started = Instant::now();
+            assert_eq!(cgi.handle(&mut Request::new("GET", "/yes.sh")).status, 502);
+            assert!(started.elapsed() < Duration::from_secs(10));
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn runs_scripts_from_a_relative_directory() {
+            let dir = PathBuf::from(format!("cgi-relative-{}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("hello.sh");
+            fs::write(&path, "#!/bin/sh\nprintf 'Content-Type: text/plain\\n\\nhi'\n").unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+            let response = Cgi::new(&dir).handle(&mut Request::new("GET", "/hello.sh"));
+            fs::remove_dir_all(dir).unwrap();
+
+            assert_eq!(response.status, 200);
+            assert_eq!(response.body, b"hi");
+        }
+
+        #[test]
+        fn refuses_paths_outside_the_directory() {
+            let dir = script_dir("ok.sh", "#!/bin/sh\n");
+            let cgi = Cgi::new(&dir);
+
+            assert_eq!(cgi.handle(&mut Request::new("GET", "/../ok.sh")).status, 404);
+            assert_eq!(cgi.handle(&mut Request::new("GET", "/missing.sh")).status, 404);
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl fmt::Display for SameSite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SameSite::Strict => write!(f, "Strict"),
+            SameSite::Lax => write!(f, "Lax"),
+            SameSite::None => write!(f, "None"),
+        }
+    }
+}
+
+/// A cookie as sent to the client in a `Set-Cookie` header.
+///
+/// The attribute setters consume and return the cookie so they can be chained:
+/// `Cookie::new("sid", id).path("/").http_only(true)`.
+///
+/// # Panics
+///
+/// `new`, `path` and `domain` panic if given a line break or `;`, which would let whatever
+/// follows pass for another attribute or header.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    pub path: Option<String>,
+    pub domain: Option<String>,
+    pub max_age: Option<Duration>,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Cookie {
+        check("name", name);
+        check("value", value);
+        Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            domain: None,
+            max_age: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    /// A cookie that tells the client to forget `name` straight away.
+    pub fn removal(name: &str) -> Cookie {
+        Cookie::new(name, "").max_age(Duration::from_secs(0))
+    }
+
+    pub fn path(mut self, path: &str) -> Cookie {
+        check("path", path);
+        self.path = Some(path.to_string());
+        self
+    }
+
+    pub fn domain(mut self, domain: &str) -> Cookie {
+        check("domain", domain);
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Cookie {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn secure(mut self, secure: bool) -> Cookie {
+        self.secure = secure;
+        self
+    }
+
+    pub fn http_only(mut self, http_only: bool) -> Cookie {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Cookie {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    /// Parse the value of a `Set-Cookie` header. Unknown attributes are ignored, as browsers do.
+    pub fn parse(header: &str) -> Option<Cookie> {
+        let mut parts = header.split(';');
+        let (name, value) = split_pair(parts.next()?)?;
+        let mut cookie = Cookie::new(name, value);
+
+        for attribute in parts {
+            let (key, val) = match attribute.find('=') {
+                Some(i) => (attribute[..i].trim(), attribute[i + 1..].trim()),
+                None => (attribute.trim(), ""),
+            };
+
+            match key.to_ascii_lowercase().as_str() {
+                "path" => cookie.path = Some(val.to_string()),
+                "domain" => cookie.domain = Some(val.to_string()),
+                "max-age" => cookie.max_age = val.parse().ok().map(Duration::from_secs),
+                "secure" => cookie.secure = true,
+                "httponly" => cookie.http_only = true,
+                "samesite" => {
+                    cookie.same_site = match val.to_ascii_lowercase().as_str() {
+                        "strict" => Some(SameSite::Strict),
+                        "lax" => Some(SameSite::Lax),
+                        "none" => Some(SameSite::None),
+                        _ => None,
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Some(cookie)
+    }
+}
+
+fn check(what: &str, text: &str) {
+    assert!(
+        !text.contains(&['\r', '\n', ';'][..]),
+        "cookie {} can't contain a line break or ';'",
+        what
+    );
+}
+
+// Serializes to the `Set-Cookie` header value.
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if self.secure {
+            write!(f, "; Secure")?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={}", same_site)?;
+        }
+        Ok(())
+    }
+}
+
+/// Split a request `Cookie` header such as `a=1; b=2` into its name/value pairs. Pairs without a
+/// name are dropped; surrounding double quotes on a value are removed.
+pub fn parse_cookie_header(header: &str) -> Vec<(String, String)> {
+    header
+        .split(';')
+        .filter_map(split_pair)
+        .map(|(n, v)| (n.to_string(), v.to_string()))
+        .collect()
+}
+
+fn split_pair(pair: &str) -> Option<(&str, &str)> {
+    let i = pair.find('=')?;
+    let name = pair[..i].trim();
+    let value = pair[i + 1..].trim();
+    let value = if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        &value[1..value.len() - 1]
+    } else {
+        value
+    };
+
+    if name.is_empty() {
+        None
+    } else {
+        Some((name, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serializes_attributes() {
+        let cookie = Cookie::new("sid", "abc")
+            .path("/")
+            .max_age(Duration::from_secs(3600))
+            .http_only(true)
+            .same_site(SameSite::Lax);
+
+        assert_eq!(
+            cookie.to_string(),
+            "sid=abc; Path=/; Max-Age=3600; HttpOnly; SameSite=Lax"
+        );
+        assert_eq!(Cookie::parse(&cookie.to_string()), Some(cookie));
+    }
+
+    #[test]
+    #[should_panic(expected = "cookie value can't contain a line break")]
+    fn refuses_values_that_would_split_the_header() {
+        Cookie::new("next", "/\r\nSet-Cookie: admin=1");
+    }
+
+    #[test]
+    fn parses_request_header() {
+        assert_eq!(
+            parse_cookie_header("a=1; b=\"two\";=skipped; c="),
+            vec![
+                (String::from("a"), String::from("1")),
+                (String::from("b"), String::from("two")),
+                (String::from("c"), String::from("")),
+            ]
+        );
+    }
+}
@@ -0,0 +1,409 @@
+use super::cookie::{self, Cookie};
+use super::session::Session;
+use std::io;
+use std::io::prelude::*;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Requests whose head is larger than this are rejected instead of being buffered forever.
+const MAX_HEAD_LEN: usize = 16 * 1024;
+/// Likewise for bodies, which are read into memory whole before the handler sees them.
+const MAX_BODY_LEN: usize = 1024 * 1024;
+
+/// A parsed HTTP/1.x request.
+///
+/// Only what the handlers in this module need is kept: the request line, the headers in the order
+/// they were received and the body as raw bytes.
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// The prefix a `Router` mount stripped from `path`, empty at the top level.
+    pub base_path: String,
+    pub query: Option<String>,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub remote_addr: Option<SocketAddr>,
+    /// Filled in by `SessionMiddleware` before the wrapped handler runs.
+    pub session: Option<Session>,
+}
+
+impl Request {
+    /// Build a request by hand, which is mostly useful in tests.
+    pub fn new(method: &str, target: &str) -> Request {
+        let (path, query) = split_target(target);
+        Request {
+            method: method.to_string(),
+            path,
+            base_path: String::new(),
+            query,
+            version: String::from("HTTP/1.1"),
+            headers: Vec::new(),
+            body: Vec::new(),
+            remote_addr: None,
+            session: None,
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Request {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Read one request from the stream.
+    ///
+    /// The head is read byte by byte up to the blank line so that nothing past it is consumed, and
+    /// the body is then read according to `Content-Length`.
+    pub fn read_from<R: Read>(stream: &mut R) -> io::Result<Request> {
+        let mut head = Vec::new();
+        let mut byte = [0; 1];
+
+        while !head.ends_with(b"\r\n\r\n") {
+            if stream.read(&mut byte)? == 0 {
+                return Err(invalid("connection closed before end of request head"));
+            }
+            head.push(byte[0]);
+            if head.len() > MAX_HEAD_LEN {
+                return Err(invalid("request head too large"));
+            }
+        }
+
+        let head = String::from_utf8_lossy(&head);
+        let mut lines = head.split("\r\n");
+
+        let request_line = lines.next().unwrap_or("");
+        let mut parts = request_line.split_whitespace();
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(m), Some(t), Some(v)) if v.starts_with("HTTP/") => (m, t, v),
+            _ => return Err(invalid("malformed request line")),
+        };
+
+        let mut request = Request::new(method, target);
+        request.version = version.to_string();
+
+        for line in lines.filter(|l| !l.is_empty()) {
+            match line.find(':') {
+                Some(i) => request
+                    .headers
+                    .push((line[..i].trim().to_string(), line[i + 1..].trim().to_string())),
+                None => return Err(invalid("malformed header line")),
+            }
+        }
+
+        let length = match request.header("Content-Length") {
+            Some(value) => value
+                .parse::<usize>()
+                .map_err(|_| invalid("invalid Content-Length"))?,
+            None => 0,
+        };
+        if length > MAX_BODY_LEN {
+            return Err(invalid("request body too large"));
+        }
+        request.body = vec![0; length];
+        stream.read_exact(&mut request.body)?;
+
+        Ok(request)
+    }
+
+    /// Look up a header by name, ignoring case as HTTP requires.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// All `name=value` pairs sent in `Cookie` headers.
+    pub fn cookies(&self) -> Vec<(String, String)> {
+        self.headers
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case("Cookie"))
+            .flat_map(|(_, v)| cookie::parse_cookie_header(v))
+            .collect()
+    }
+
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.cookies()
+            .into_iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v)
+    }
+}
+
+fn split_target(target: &str) -> (String, Option<String>) {
+    match target.find('?') {
+        Some(i) => (target[..i].to_string(), Some(target[i + 1..].to_string())),
+        None => (target.to_string(), None),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// An HTTP response, written back to the client in one go.
+///
+/// The body is usually held in `body`, but one that's too large for that, such as a file being
+/// downloaded, can be read from a stream as it's written out instead.
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Sent instead of `body` if set, with its length.
+    stream: Option<(Box<dyn Read + Send>, u64)>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+            stream: None,
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_body<B: Into<Vec<u8>>>(mut self, body: B) -> Response {
+        self.body = body.into();
+        self.stream = None;
+        self
+    }
+
+    /// Send the first `len` bytes of `body` as the body, reading them only as they're written.
+    pub fn with_stream<R: Read + Send + 'static>(mut self, body: R, len: u64) -> Response {
+        self.body = Vec::new();
+        self.stream = Some((Box::new(body), len));
+        self
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Add a `Set-Cookie` header. Unlike other headers several of these may be present.
+    pub fn set_cookie(&mut self, cookie: &Cookie) {
+        self.headers
+            .push((String::from("Set-Cookie"), cookie.to_string()));
+    }
+
+    /// Fails if a header has a line break in it, which would let whoever put it there add headers
+    /// or a body of their own.
+    pub fn check_headers(&self) -> io::Result<()> {
+        for (name, value) in &self.headers {
+            if name.contains(&['\r', '\n'][..]) || value.contains(&['\r', '\n'][..]) {
+                return Err(invalid("line break in a response header"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Serialize the status line, headers and body. `Content-Length` is always derived from the
+    /// body so handlers don't have to keep it in sync themselves. A streamed body is used up.
+    ///
+    /// Nothing is written if `check_headers` fails.
+    pub fn write_to<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        self.check_headers()?;
+        let len = match &self.stream {
+            Some((_, len)) => *len,
+            None => self.body.len() as u64,
+        };
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason_phrase(self.status));
+        for (name, value) in &self.headers {
+            if !name.eq_ignore_ascii_case("Content-Length") {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+        head.push_str(&format!("Content-Length: {}\r\n\r\n", len));
+
+        out.write_all(head.as_bytes())?;
+        match self.stream.take() {
+            // The length has been sent already, so a body that ends early can only be cut off.
+            Some((body, len)) => {
+                if io::copy(&mut body.take(len), out)? < len {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "response body ended early",
+                    ));
+                }
+            }
+            None => out.write_all(&self.body)?,
+        }
+        out.flush()
+    }
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        412 => "Precondition Failed",
+        416 => "Range Not Satisfiable",
+        421 => "Misdirected Request",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    }
+}
+
+/// Format a time as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let days = secs / 86_400;
+    let rem = secs % 86_400;
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Anything that can turn a request into a response.
+///
+/// Handlers are shared between all worker threads, hence the `Send + Sync` bound. The request is
+/// passed mutably so that middleware can attach state such as the session for the handlers it wraps.
+pub trait Handler: Send + Sync {
+    fn handle(&self, request: &mut Request) -> Response;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&mut Request) -> Response + Send + Sync,
+{
+    fn handle(&self, request: &mut Request) -> Response {
+        self(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_request_line_headers_and_body() {
+        let raw = b"POST /login?next=/ HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\nCookie: a=1; b=2\r\n\r\nhello";
+        let request = Request::read_from(&mut &raw[..]).unwrap();
+
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/login");
+        assert_eq!(request.query.as_deref(), Some("next=/"));
+        assert_eq!(request.header("host"), Some("example.com"));
+        assert_eq!(request.body, b"hello");
+        assert_eq!(request.cookie("b"), Some(String::from("2")));
+    }
+
+    #[test]
+    fn rejects_a_body_too_large_before_reading_it() {
+        let raw = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\nhello",
+            usize::MAX
+        );
+        let error = Request::read_from(&mut raw.as_bytes()).err().unwrap();
+        assert_eq!(error.to_string(), "request body too large");
+    }
+
+    #[test]
+    fn formats_http_dates() {
+        let time = UNIX_EPOCH + std::time::Duration::from_secs(784_111_777);
+        assert_eq!(http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+    }
+
+    #[test]
+    fn rejects_malformed_request_line() {
+        let raw = b"nonsense\r\n\r\n";
+        assert!(Request::read_from(&mut &raw[..]).is_err());
+    }
+
+    #[test]
+    fn writes_content_length_from_body() {
+        let mut out = Vec::new();
+        Response::new(200)
+            .with_header("Content-Type", "text/plain")
+            .with_body("hi")
+            .write_to(&mut out)
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 2\r\n\r\nhi"
+        );
+    }
+
+    #[test]
+    fn refuses_to_write_headers_with_line_breaks() {
+        for (name, value) in &[
+            ("Location", "/\r\nSet-Cookie: admin=1"),
+            ("Location", "/\nX: y"),
+            ("X\r\nY", "z"),
+        ] {
+            let mut out = Vec::new();
+            let result = Response::new(302)
+                .with_header(name, value)
+                .write_to(&mut out);
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+            assert!(out.is_empty());
+        }
+    }
+
+    #[test]
+    fn streams_bodies_and_reports_ones_that_end_early() {
+        let mut out = Vec::new();
+        Response::new(200)
+            .with_stream(&b"hello, world"[..], 5)
+            .write_to(&mut out)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello"
+        );
+
+        let error = Response::new(200)
+            .with_stream(&b"hi"[..], 5)
+            .write_to(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,137 @@
+/// An inclusive byte range that has already been checked against the length of the resource.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total)
+    }
+}
+
+/// More ranges than this in one request is more likely abuse than a real download client.
+const MAX_RANGES: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub enum RangeRequest {
+    /// The header is absent, malformed or uses a unit other than bytes; serve the whole resource.
+    Full,
+    Satisfiable(Vec<ByteRange>),
+    /// Every range lies past the end of the resource; answer 416.
+    Unsatisfiable,
+}
+
+/// Interpret a `Range` header for a resource of `total` bytes (RFC 7233).
+///
+/// Ranges that start past the end are dropped and ends past the end are clamped. Overlapping or
+/// adjacent ranges are merged, which also keeps a client from asking for the same bytes many times.
+pub fn parse_range(header: &str, total: u64) -> RangeRequest {
+    let specs = match header.trim().strip_prefix("bytes=") {
+        Some(specs) => specs,
+        None => return RangeRequest::Full,
+    };
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        let dash = match spec.find('-') {
+            Some(dash) => dash,
+            None => return RangeRequest::Full,
+        };
+        let (first, last) = (spec[..dash].trim(), spec[dash + 1..].trim());
+
+        let range = if first.is_empty() {
+            // `-500` is the last 500 bytes.
+            let suffix: u64 = match last.parse() {
+                Ok(n) => n,
+                Err(_) => return RangeRequest::Full,
+            };
+            if suffix == 0 || total == 0 {
+                continue;
+            }
+            ByteRange {
+                start: total.saturating_sub(suffix),
+                end: total - 1,
+            }
+        } else {
+            let start: u64 = match first.parse() {
+                Ok(n) => n,
+                Err(_) => return RangeRequest::Full,
+            };
+            let end: u64 = if last.is_empty() {
+                u64::MAX
+            } else {
+                match last.parse() {
+                    Ok(n) => n,
+                    Err(_) => return RangeRequest::Full,
+                }
+            };
+            if end < start {
+                return RangeRequest::Full;
+            }
+            if start >= total {
+                continue;
+            }
+            ByteRange {
+                start,
+                end: end.min(total - 1),
+            }
+        };
+        ranges.push(range);
+    }
+
+    if ranges.len() > MAX_RANGES {
+        return RangeRequest::Full;
+    }
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end + 1 => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    RangeRequest::Satisfiable(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(header: &str, total: u64) -> Vec<(u64, u64)> {
+        match parse_range(header, total) {
+            RangeRequest::Satisfiable(ranges) => ranges.iter().map(|r| (r.start, r.end)).collect(),
+            other => panic!("expected ranges, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_open_suffix_and_clamped_ranges() {
+        assert_eq!(ranges("bytes=0-499", 1000), vec![(0, 499)]);
+        assert_eq!(ranges("bytes=900-", 1000), vec![(900, 999)]);
+        assert_eq!(ranges("bytes=-100", 1000), vec![(900, 999)]);
+        assert_eq!(ranges("bytes=-5000", 1000), vec![(0, 999)]);
+        assert_eq!(ranges("bytes=990-2000", 1000), vec![(990, 999)]);
+    }
+
+    #[test]
+    fn merges_overlapping_ranges() {
+        assert_eq!(
+            ranges("bytes=500-600, 0-99, 100-199, 550-700", 1000),
+            vec![(0, 199), (500, 700)]
+        );
+    }
+
+    #[test]
+    fn reports_unsatisfiable_and_ignores_garbage() {
+        assert_eq!(parse_range("bytes=1000-", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=5-1", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("items=0-1", 1000), RangeRequest::Full);
+    }
+}
@@ -0,0 +1,217 @@
+use super::http::{Handler, Request, Response};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// What identifies a client for rate limiting purposes.
+pub enum ClientKey {
+    /// The peer address of the connection, without the port.
+    Ip,
+    /// The value of a request header such as an API key. Requests without the header fall back to
+    /// the client IP.
+    Header(String),
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Result of taking a token from a client's bucket.
+#[derive(Debug, PartialEq)]
+pub struct Decision {
+    pub allowed: bool,
+    /// Whole tokens left after this request.
+    pub remaining: u32,
+    /// Seconds until a token becomes available again, only meaningful when not allowed.
+    pub retry_after: u64,
+    /// Seconds until the bucket is full again.
+    pub reset: u64,
+}
+
+/// Token bucket rate limiting in front of another handler.
+///
+/// Each client gets a bucket holding up to `burst` tokens that refills at `per_second` tokens a
+/// second; a request takes one token or is refused with `429 Too Many Requests`. Every response
+/// carries `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset`, and refusals also carry
+/// `Retry-After`.
+///
+/// A bucket that has been idle long enough to refill completely is indistinguishable from a new
+/// one, so such buckets are swept out every `sweep_interval` to keep memory bounded.
+pub struct RateLimiter<H> {
+    inner: H,
+    key: ClientKey,
+    burst: u32,
+    per_second: f64,
+    sweep_interval: Duration,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    last_sweep: Mutex<Instant>,
+}
+
+impl<H: Handler> RateLimiter<H> {
+    /// # Panics
+    ///
+    /// Panics if `burst` is zero or `per_second` isn't positive.
+    pub fn new(inner: H, burst: u32, per_second: f64) -> RateLimiter<H> {
+        assert!(burst > 0);
+        assert!(per_second > 0.0);
+
+        RateLimiter {
+            inner,
+            key: ClientKey::Ip,
+            burst,
+            per_second,
+            sweep_interval: Duration::from_secs(60),
+            buckets: Mutex::new(HashMap::new()),
+            last_sweep: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn key(mut self, key: ClientKey) -> RateLimiter<H> {
+        self.key = key;
+        self
+    }
+
+    pub fn sweep_interval(mut self, interval: Duration) -> RateLimiter<H> {
+        self.sweep_interval = interval;
+        self
+    }
+
+    /// Number of clients currently being tracked.
+    pub fn tracked_clients(&self) -> usize {
+        self.buckets.lock().unwrap().len()
+    }
+
+    fn client_key(&self, request: &Request) -> String {
+        if let ClientKey::Header(name) = &self.key {
+            if let Some(value) = request.header(name) {
+                return format!("header:{}", value);
+            }
+        }
+        match request.remote_addr {
+            Some(addr) => format!("ip:{}", addr.ip()),
+            None => String::from("ip:unknown"),
+        }
+    }
+
+    fn time_to_fill(&self, tokens: f64) -> f64 {
+        (self.burst as f64 - tokens) / self.per_second
+    }
+
+    /// Take a token from `client`'s bucket at time `now`.
+    pub fn check(&self, client: &str, now: Instant) -> Decision {
+        self.sweep(now);
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: self.burst as f64,
+            updated: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.burst as f64);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+
+        Decision {
+            allowed,
+            remaining: bucket.tokens.floor() as u32,
+            retry_after: ((1.0 - bucket.tokens).max(0.0) / self.per_second).ceil() as u64,
+            reset: self.time_to_fill(bucket.tokens).ceil() as u64,
+        }
+    }
+
+    /// Drop buckets that would be full by now. Runs at most once per `sweep_interval`.
+    fn sweep(&self, now: Instant) {
+        {
+            let mut last_sweep = self.last_sweep.lock().unwrap();
+            if now.saturating_duration_since(*last_sweep) < self.sweep_interval {
+                return;
+            }
+            *last_sweep = now;
+        }
+
+        self.buckets.lock().unwrap().retain(|_, bucket| {
+            let idle = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            idle < self.time_to_fill(bucket.tokens)
+        });
+    }
+}
+
+impl<H: Handler> Handler for RateLimiter<H> {
+    fn handle(&self, request: &mut Request) -> Response {
+        let decision = self.check(&self.client_key(request), Instant::now());
+
+        let response = if decision.allowed {
+            self.inner.handle(request)
+        } else {
+            Response::new(429)
+                .with_header("Retry-After", &decision.retry_after.to_string())
+                .with_body("Too Many Requests")
+        };
+
+        response
+            .with_header("RateLimit-Limit", &self.burst.to_string())
+            .with_header("RateLimit-Remaining", &decision.remaining.to_string())
+            .with_header("RateLimit-Reset", &decision.reset.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ok(_: &mut Request) -> Response {
+        Response::new(200)
+    }
+
+    #[test]
+    fn refuses_after_burst_and_refills_over_time() {
+        let limiter = RateLimiter::new(ok, 2, 0.5);
+        let start = Instant::now();
+
+        assert!(limiter.check("a", start).allowed);
+        assert!(limiter.check("a", start).allowed);
+
+        let refused = limiter.check("a", start);
+        assert!(!refused.allowed);
+        assert_eq!(refused.retry_after, 2);
+        assert!(limiter.check("b", start).allowed);
+
+        assert!(limiter.check("a", start + Duration::from_secs(2)).allowed);
+    }
+
+    #[test]
+    fn sweeps_buckets_that_have_refilled() {
+        let limiter = RateLimiter::new(ok, 2, 1.0).sweep_interval(Duration::from_secs(10));
+        let start = Instant::now();
+
+        limiter.check("a", start);
+        limiter.check("b", start + Duration::from_millis(9500));
+        assert_eq!(limiter.tracked_clients(), 2);
+
+        limiter.check("c", start + Duration::from_secs(10));
+        assert_eq!(limiter.tracked_clients(), 2);
+    }
+
+    #[test]
+    fn keys_by_header_and_sets_headers() {
+        let limiter = RateLimiter::new(ok, 1, 1.0).key(ClientKey::Header(String::from("X-Api-Key")));
+        let request = || Request::new("GET", "/").with_header("X-Api-Key", "k1");
+
+        let first = limiter.handle(&mut request());
+        assert_eq!(first.status, 200);
+        assert_eq!(first.header("RateLimit-Limit"), Some("1"));
+        assert_eq!(first.header("RateLimit-Remaining"), Some("0"));
+
+        let second = limiter.handle(&mut request());
+        assert_eq!(second.status, 429);
+        assert_eq!(second.header("Retry-After"), Some("1"));
+
+        assert_eq!(limiter.handle(&mut Request::new("GET", "/")).status, 200);
+    }
+}
@@ -0,0 +1,133 @@
+use super::http::{Handler, Request, Response};
+
+enum Pattern {
+    Exact(String),
+    Prefix(String),
+}
+
+struct Route {
+    method: Option<String>,
+    pattern: Pattern,
+    handler: Box<dyn Handler>,
+}
+
+/// Dispatches requests by method and path.
+///
+/// Routes are tried in the order they were added. `route` matches one method and exact path while
+/// `mount` matches any method under a path prefix and hands the rest of the path to its handler.
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router { routes: Vec::new() }
+    }
+
+    pub fn route<H>(mut self, method: &str, path: &str, handler: H) -> Router
+    where
+        H: Handler + 'static,
+    {
+        self.routes.push(Route {
+            method: Some(method.to_string()),
+            pattern: Pattern::Exact(path.to_string()),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    /// Send everything below `prefix` to `handler`. While it runs, `request.path` is the remainder
+    /// (always starting with `/`) and `request.base_path` holds the prefix that was stripped.
+    pub fn mount<H>(mut self, prefix: &str, handler: H) -> Router
+    where
+        H: Handler + 'static,
+    {
+        self.routes.push(Route {
+            method: None,
+            pattern: Pattern::Prefix(prefix.trim_end_matches('/').to_string()),
+            handler: Box::new(handler),
+        });
+        self
+    }
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+impl Handler for Router {
+    fn handle(&self, request: &mut Request) -> Response {
+        let mut path_matched = false;
+
+        for route in &self.routes {
+            match &route.pattern {
+                Pattern::Exact(path) if *path == request.path => {
+                    path_matched = true;
+                    if route.method.as_deref() == Some(request.method.as_str()) {
+                        return route.handler.handle(request);
+                    }
+                }
+                Pattern::Prefix(prefix) => {
+                    let rest = match strip_segment_prefix(&request.path, prefix) {
+                        Some(rest) => rest,
+                        None => continue,
+                    };
+                    let full_path = std::mem::replace(&mut request.path, rest);
+                    let base_path = request.base_path.clone();
+                    request.base_path.push_str(prefix);
+
+                    let response = route.handler.handle(request);
+
+                    request.path = full_path;
+                    request.base_path = base_path;
+                    return response;
+                }
+                _ => {}
+            }
+        }
+
+        if path_matched {
+            Response::new(405)
+        } else {
+            Response::new(404).with_body("Not Found")
+        }
+    }
+}
+
+// `/static` matches `/static` and `/static/app.js` but not `/statics`.
+fn strip_segment_prefix(path: &str, prefix: &str) -> Option<String> {
+    if !path.starts_with(prefix) {
+        return None;
+    }
+    match &path[prefix.len()..] {
+        "" => Some(String::from("/")),
+        rest if rest.starts_with('/') => Some(rest.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn echo(request: &mut Request) -> Response {
+        Response::new(200).with_body(format!("{}|{}", request.base_path, request.path))
+    }
+
+    #[test]
+    fn matches_exact_routes_and_mounts() {
+        let router = Router::new()
+            .route("GET", "/", |_: &mut Request| Response::new(200).with_body("home"))
+            .mount("/static/", echo);
+
+        assert_eq!(router.handle(&mut Request::new("GET", "/")).body, b"home");
+        assert_eq!(router.handle(&mut Request::new("POST", "/")).status, 405);
+        assert_eq!(
+            router.handle(&mut Request::new("GET", "/static/css/a.css")).body,
+            b"/static|/css/a.css"
+        );
+        assert_eq!(router.handle(&mut Request::new("GET", "/statics")).status, 404);
+    }
+}
@@ -0,0 +1,506 @@
+use super::cookie::{Cookie, SameSite};
+use super::http::{Handler, Request, Response};
+use super::sha256::{constant_time_eq, hmac_sha256, to_hex};
+use rand::Rng;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub type SessionData = HashMap<String, String>;
+
+/// The per-client state a handler sees through `Request::session`.
+///
+/// Changes are written back to the store by `SessionMiddleware` once the handler returns.
+pub struct Session {
+    id: String,
+    data: SessionData,
+    is_new: bool,
+    changed: bool,
+    rotate: bool,
+    destroy: bool,
+}
+
+impl Session {
+    fn new(id: String, data: SessionData, is_new: bool) -> Session {
+        Session {
+            id,
+            data,
+            is_new,
+            changed: false,
+            rotate: false,
+            destroy: false,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.data.get(key).map(|v| v.as_str())
+    }
+
+    pub fn insert(&mut self, key: &str, value: &str) {
+        self.data.insert(key.to_string(), value.to_string());
+        self.changed = true;
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let old = self.data.remove(key);
+        self.changed |= old.is_some();
+        old
+    }
+
+    /// Ask for a fresh session id while keeping the data. Call this whenever the privilege level
+    /// changes, such as on login, so an id planted before authentication becomes worthless.
+    pub fn rotate(&mut self) {
+        self.rotate = true;
+    }
+
+    /// Throw the session away, e.g. on logout.
+    pub fn destroy(&mut self) {
+        self.destroy = true;
+    }
+}
+
+/// Where session data lives between requests.
+pub trait SessionStore: Send + Sync {
+    /// Returns `None` for unknown or expired sessions.
+    fn load(&self, id: &str) -> io::Result<Option<SessionData>>;
+    fn save(&self, id: &str, data: &SessionData, ttl: Duration) -> io::Result<()>;
+    fn remove(&self, id: &str) -> io::Result<()>;
+}
+
+/// Keeps sessions in a map. Expired entries are ignored on load and dropped by `purge_expired`.
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, (SystemTime, SessionData)>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore {
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn purge_expired(&self) {
+        let now = SystemTime::now();
+        self.sessions
+            .lock()
+            .unwrap()
+            .retain(|_, (expires, _)| *expires > now);
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> MemoryStore {
+        MemoryStore::new()
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, id: &str) -> io::Result<Option<SessionData>> {
+        let sessions = self.sessions.lock().unwrap();
+        Ok(match sessions.get(id) {
+            Some((expires, data)) if *expires > SystemTime::now() => Some(data.clone()),
+            _ => None,
+        })
+    }
+
+    fn save(&self, id: &str, data: &SessionData, ttl: Duration) -> io::Result<()> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(id.to_string(), (SystemTime::now() + ttl, data.clone()));
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> io::Result<()> {
+        self.sessions.lock().unwrap().remove(id);
+        Ok(())
+    }
+}
+
+/// Keeps one file per session in a directory, so sessions survive a restart.
+///
+/// The first line of each file is the expiry time in seconds since the epoch, followed by one
+/// `key=value` line per entry with `%`, `=` and line breaks percent-encoded.
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+static SAVED: AtomicUsize = AtomicUsize::new(0);
+
+impl FileStore {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> io::Result<FileStore> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(FileStore { dir })
+    }
+
+    fn path(&self, id: &str) -> io::Result<PathBuf> {
+        // Ids come from cookies; only ever let hex digits near the file system.
+        if id.is_empty() || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid session id",
+            ));
+        }
+        Ok(self.dir.join(id))
+    }
+}
+
+impl SessionStore for FileStore {
+    fn load(&self, id: &str) -> io::Result<Option<SessionData>> {
+        let contents = match fs::read_to_string(self.path(id)?) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let mut lines = contents.lines();
+        let expires: u64 = lines.next().and_then(|l| l.parse().ok()).unwrap_or(0);
+        if expires <= unix_now() {
+            fs::remove_file(self.path(id)?).ok();
+            return Ok(None);
+        }
+
+        let mut data = SessionData::new();
+        for line in lines {
+            if let Some(i) = line.find('=') {
+                data.insert(unescape(&line[..i]), unescape(&line[i + 1..]));
+            }
+        }
+        Ok(Some(data))
+    }
+
+    fn save(&self, id: &str, data: &SessionData, ttl: Duration) -> io::Result<()> {
+        let mut contents = format!("{}\n", unix_now() + ttl.as_secs());
+        for (key, value) in data {
+            contents.push_str(&format!("{}={}\n", escape(key), escape(value)));
+        }
+
+        // Write to the side and rename so a concurrent load never sees half a file. Each save gets
+        // its own temporary file, as two requests in one session can be saving at once.
+        let path = self.path(id)?;
+        let tmp = self.dir.join(format!(
+            ".{}.{}.{}.tmp",
+            id,
+            process::id(),
+            SAVED.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::write(&tmp, contents)?;
+        fs::rename(tmp, path)
+    }
+
+    fn remove(&self, id: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(id)?) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('%', "%25")
+        .replace('=', "%3D")
+        .replace('\n', "%0A")
+        .replace('\r', "%0D")
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("%0D", "\r")
+        .replace("%0A", "\n")
+        .replace("%3D", "=")
+        .replace("%25", "%")
+}
+
+/// Wraps a handler, loading the session named by a signed cookie before the handler runs and saving
+/// it afterwards.
+///
+/// The cookie holds `<id>.<hmac>`, where the HMAC-SHA-256 is keyed by the server secret, so a
+/// client can't make up or tamper with ids. A new session only gets a cookie once something is
+/// stored in it.
+pub struct SessionMiddleware<S, H> {
+    store: S,
+    secret: Vec<u8>,
+    cookie_name: String,
+    ttl: Duration,
+    secure: bool,
+    inner: H,
+}
+
+impl<S, H> SessionMiddleware<S, H>
+where
+    S: SessionStore,
+    H: Handler,
+{
+    /// Sessions default to a `sid` cookie that lasts 30 minutes.
+    pub fn new(store: S, secret: &[u8], inner: H) -> SessionMiddleware<S, H> {
+        SessionMiddleware {
+            store,
+            secret: secret.to_vec(),
+            cookie_name: String::from("sid"),
+            ttl: Duration::from_secs(30 * 60),
+            secure: false,
+            inner,
+        }
+    }
+
+    pub fn cookie_name(mut self, name: &str) -> SessionMiddleware<S, H> {
+        self.cookie_name = name.to_string();
+        self
+    }
+
+    pub fn ttl(mut self, ttl: Duration) -> SessionMiddleware<S, H> {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Only send the cookie over HTTPS.
+    pub fn secure(mut self, secure: bool) -> SessionMiddleware<S, H> {
+        self.secure = secure;
+        self
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    fn sign(&self, id: &str) -> String {
+        format!("{}.{}", id, to_hex(&hmac_sha256(&self.secret, id.as_bytes())))
+    }
+
+    fn verify<'v>(&self, value: &'v str) -> Option<&'v str> {
+        let i = value.rfind('.')?;
+        let id = &value[..i];
+        let expected = self.sign(id);
+        if constant_time_eq(expected.as_bytes(), value.as_bytes()) {
+            Some(id)
+        } else {
+            None
+        }
+    }
+
+    fn cookie(&self, id: &str) -> Cookie {
+        Cookie::new(&self.cookie_name, &self.sign(id))
+            .path("/")
+            .max_age(self.ttl)
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(SameSite::Lax)
+    }
+
+    fn load(&self, request: &Request) -> io::Result<Session> {
+        let id = request
+            .cookie(&self.cookie_name)
+            .and_then(|value| self.verify(&value).map(|id| id.to_string()));
+
+        if let Some(id) = id {
+            if let Some(data) = self.store.load(&id)? {
+                return Ok(Session::new(id, data, false));
+            }
+        }
+        Ok(Session::new(new_session_id(), SessionData::new(), true))
+    }
+
+    fn finish(&self, session: Session, response: &mut Response) -> io::Result<()> {
+        if session.destroy {
+            self.store.remove(&session.id)?;
+            if !session.is_new {
+                response.set_cookie(&Cookie::removal(&self.cookie_name).path("/"));
+            }
+        } else if session.rotate {
+            self.store.remove(&session.id)?;
+            let id = new_session_id();
+            self.store.save(&id, &session.data, self.ttl)?;
+            response.set_cookie(&self.cookie(&id));
+        } else if !session.is_new {
+            // Saving on every request slides the expiry forward while the client stays active.
+            // The cookie is sent again too, or the browser would still drop it `ttl` after it
+            // was first issued.
+            self.store.save(&session.id, &session.data, self.ttl)?;
+            response.set_cookie(&self.cookie(&session.id));
+        } else if session.changed {
+            self.store.save(&session.id, &session.data, self.ttl)?;
+            response.set_cookie(&self.cookie(&session.id));
+        }
+        Ok(())
+    }
+}
+
+impl<S, H> Handler for SessionMiddleware<S, H>
+where
+    S: SessionStore,
+    H: Handler,
+{
+    fn handle(&self, request: &mut Request) -> Response {
+        match self.load(request) {
+            Ok(session) => request.session = Some(session),
+            Err(_) => return Response::new(500),
+        }
+
+        let mut response = self.inner.handle(request);
+
+        if let Some(session) = request.session.take() {
+            if self.finish(session, &mut response).is_err() {
+                return Response::new(500);
+            }
+        }
+        response
+    }
+}
+
+fn new_session_id() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn login(request: &mut Request) -> Response {
+        let session = request.session.as_mut().unwrap();
+        if request.path == "/login" {
+            session.insert("user", "ferris");
+            session.rotate();
+        }
+        Response::new(200).with_body(session.get("user").unwrap_or("anonymous"))
+    }
+
+    fn sid(response: &Response) -> String {
+        let cookie = Cookie::parse(response.header("Set-Cookie").unwrap()).unwrap();
+        assert!(cookie.http_only);
+        format!("sid={}", cookie.value)
+    }
+
+    #[test]
+    fn anonymous_requests_get_no_cookie() {
+        let app = SessionMiddleware::new(MemoryStore::new(), b"secret", login);
+        let response = app.handle(&mut Request::new("GET", "/"));
+
+        assert_eq!(response.header("Set-Cookie"), None);
+        assert!(app.store().is_empty());
+    }
+
+    #[test]
+    fn login_rotates_and_session_is_restored() {
+        let app = SessionMiddleware::new(MemoryStore::new(), b"secret", login);
+
+        let first = app.handle(&mut Request::new("GET", "/"));
+        assert!(first.header("Set-Cookie").is_none());
+
+        let logged_in = app.handle(&mut Request::new("POST", "/login"));
+        let cookie = sid(&logged_in);
+
+        let again = app.handle(&mut Request::new("GET", "/").with_header("Cookie", &cookie));
+        assert_eq!(again.body, b"ferris");
+
+        let relogin =
+            app.handle(&mut Request::new("POST", "/login").with_header("Cookie", &cookie));
+        let rotated = sid(&relogin);
+        assert_ne!(rotated, cookie);
+        assert_eq!(app.store().len(), 1);
+
+        let stale = app.handle(&mut Request::new("GET", "/").with_header("Cookie", &cookie));
+        assert_eq!(stale.body, b"anonymous");
+    }
+
+    #[test]
+    fn active_sessions_get_their_cookie_renewed() {
+        let app = SessionMiddleware::new(MemoryStore::new(), b"secret", login)
+            .ttl(Duration::from_secs(600));
+        let cookie = sid(&app.handle(&mut Request::new("POST", "/login")));
+
+        let later = app.handle(&mut Request::new("GET", "/").with_header("Cookie", &cookie));
+        let renewed = Cookie::parse(later.header("Set-Cookie").unwrap()).unwrap();
+
+        assert_eq!(format!("sid={}", renewed.value), cookie);
+        assert_eq!(renewed.max_age, Some(Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn tampered_cookie_is_ignored() {
+        let app = SessionMiddleware::new(MemoryStore::new(), b"secret", login);
+        let cookie = sid(&app.handle(&mut Request::new("POST", "/login")));
+        let last = if cookie.ends_with('0') { '1' } else { '0' };
+        let forged = format!("{}{}", &cookie[..cookie.len() - 1], last);
+
+        let response = app.handle(&mut Request::new("GET", "/").with_header("Cookie", &forged));
+        assert_eq!(response.body, b"anonymous");
+    }
+
+    #[test]
+    fn memory_store_expires_entries() {
+        let store = MemoryStore::new();
+        store.save("ab", &SessionData::new(), Duration::from_secs(0)).unwrap();
+
+        assert!(store.load("ab").unwrap().is_none());
+        store.purge_expired();
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn file_store_round_trips_and_rejects_bad_ids() {
+        let dir = std::env::temp_dir().join(format!("sessions-{}", new_session_id()));
+        let store = FileStore::new(&dir).unwrap();
+        let mut data = SessionData::new();
+        data.insert(String::from("note"), String::from("a=b\n100%"));
+
+        store.save("abc123", &data, Duration::from_secs(60)).unwrap();
+        assert_eq!(store.load("abc123").unwrap(), Some(data));
+        assert!(store.load("../etc/passwd").is_err());
+
+        store.remove("abc123").unwrap();
+        assert_eq!(store.load("abc123").unwrap(), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn file_store_saves_one_session_from_several_threads() {
+        let dir = std::env::temp_dir().join(format!("sessions-{}", new_session_id()));
+        let store = std::sync::Arc::new(FileStore::new(&dir).unwrap());
+
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    let mut data = SessionData::new();
+                    data.insert(String::from("n"), i.to_string());
+                    for _ in 0..50 {
+                        store.save("abc123", &data, Duration::from_secs(60)).unwrap();
+                        assert!(store.load("abc123").unwrap().is_some());
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,291 @@
+use super::http::{http_date, Handler, Request, Response};
+use super::range::{parse_range, ByteRange, RangeRequest};
+use rand::Rng;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Serves files below a root directory, with `index.html` standing in for directories.
+///
+/// Byte ranges are supported so large downloads can be resumed: every response advertises
+/// `Accept-Ranges: bytes` along with an `ETag` and `Last-Modified` usable in `If-Range`.
+pub struct StaticFiles {
+    root: PathBuf,
+}
+
+impl StaticFiles {
+    pub fn new<P: Into<PathBuf>>(root: P) -> StaticFiles {
+        StaticFiles { root: root.into() }
+    }
+
+    /// Map a request path onto the file system, refusing anything that would climb out of the root.
+    fn resolve(&self, request_path: &str) -> Option<PathBuf> {
+        let decoded = percent_decode(request_path)?;
+        let mut path = self.root.clone();
+
+        for component in Path::new(decoded.trim_start_matches('/')).components() {
+            match component {
+                Component::Normal(part) => path.push(part),
+                Component::CurDir => {}
+                _ => return None,
+            }
+        }
+
+        if path.is_dir() {
+            path.push("index.html");
+        }
+        Some(path)
+    }
+}
+
+impl Handler for StaticFiles {
+    fn handle(&self, request: &mut Request) -> Response {
+        if request.method != "GET" {
+            return Response::new(405).with_header("Allow", "GET");
+        }
+
+        let path = match self.resolve(&request.path) {
+            Some(path) => path,
+            None => return Response::new(404).with_body("Not Found"),
+        };
+
+        match serve_file(request, &path) {
+            Ok(response) => response,
+            Err(_) => Response::new(404).with_body("Not Found"),
+        }
+    }
+}
+
+/// Answer with the whole file or, when the client sent a `Range` header that still applies, with
+/// just the requested bytes. Only the requested bytes are read from disk, and only as the response
+/// is written, so that large files aren't held in memory.
+fn serve_file(request: &Request, path: &Path) -> io::Result<Response> {
+    let mut file = File::open(path)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "not a file"));
+    }
+
+    let total = metadata.len();
+    let content_type = content_type(path);
+    let modified = metadata.modified().ok();
+    let mtime = modified
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let etag = format!("\"{:x}-{:x}\"", total, mtime);
+    let last_modified = modified.map(http_date);
+
+    let ranges = match request.header("Range") {
+        Some(range) if if_range_matches(request, &etag, last_modified.as_deref()) => {
+            parse_range(range, total)
+        }
+        _ => RangeRequest::Full,
+    };
+
+    let mut response = match ranges {
+        RangeRequest::Full => Response::new(200)
+            .with_header("Content-Type", content_type)
+            .with_stream(file, total),
+        RangeRequest::Unsatisfiable => {
+            Response::new(416).with_header("Content-Range", &format!("bytes */{}", total))
+        }
+        RangeRequest::Satisfiable(ranges) if ranges.len() == 1 => {
+            file.seek(SeekFrom::Start(ranges[0].start))?;
+            Response::new(206)
+                .with_header("Content-Type", content_type)
+                .with_header("Content-Range", &ranges[0].content_range(total))
+                .with_stream(file, range_len(ranges[0]))
+        }
+        RangeRequest::Satisfiable(ranges) => {
+            let boundary = format!("{:016x}", rand::thread_rng().gen::<u64>());
+            let mut body: Box<dyn Read + Send> = Box::new(io::empty());
+            let mut len = 0;
+            for range in ranges {
+                let head = format!(
+                    "--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                    boundary,
+                    content_type,
+                    range.content_range(total)
+                );
+                // Each part gets a file of its own, as they'd all share one position otherwise.
+                let mut part = File::open(path)?;
+                part.seek(SeekFrom::Start(range.start))?;
+                len += head.len() as u64 + range_len(range) + 2;
+                body = Box::new(
+                    body.chain(io::Cursor::new(head))
+                        .chain(part.take(range_len(range)))
+                        .chain(&b"\r\n"[..]),
+                );
+            }
+            let end = format!("--{}--\r\n", boundary);
+            len += end.len() as u64;
+
+            Response::new(206)
+                .with_header(
+                    "Content-Type",
+                    &format!("multipart/byteranges; boundary={}", boundary),
+                )
+                .with_stream(body.chain(io::Cursor::new(end)), len)
+        }
+    };
+
+    response = response
+        .with_header("Accept-Ranges", "bytes")
+        .with_header("ETag", &etag);
+    if let Some(last_modified) = last_modified {
+        response = response.with_header("Last-Modified", &last_modified);
+    }
+    Ok(response)
+}
+
+// A `Range` only applies if the `If-Range` validator, when present, still names this version of the
+// file. Otherwise the client's partial copy is stale and it gets the whole thing.
+fn if_range_matches(request: &Request, etag: &str, last_modified: Option<&str>) -> bool {
+    match request.header("If-Range") {
+        None => true,
+        Some(validator) if validator.starts_with("W/") => false,
+        Some(validator) => validator == etag || Some(validator) == last_modified,
+    }
+}
+
+fn range_len(range: ByteRange) -> u64 {
+    range.end - range.start + 1
+}
+
+pub fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css",
+        Some("js") => "application/javascript",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("svg") => "image/svg+xml",
+        Some("gz") => "application/gzip",
+        Some("zip") => "application/zip",
+        Some("tar") => "application/x-tar",
+        _ => "application/octet-stream",
+    }
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn fixture(contents: &[u8]) -> (StaticFiles, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("static-{}", rand::thread_rng().gen::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("artifact.bin"), contents).unwrap();
+        (StaticFiles::new(&dir), dir)
+    }
+
+    /// The body as it would be sent, streamed or not, checked against its `Content-Length`.
+    fn body(mut response: Response) -> Vec<u8> {
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        let start = out.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let body = out.split_off(start);
+        let head = String::from_utf8(out).unwrap();
+        assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())));
+        body
+    }
+
+    fn get(files: &StaticFiles, headers: &[(&str, &str)]) -> Response {
+        let mut request = Request::new("GET", "/artifact.bin");
+        for (name, value) in headers {
+            request = request.with_header(name, value);
+        }
+        files.handle(&mut request)
+    }
+
+    #[test]
+    fn serves_single_and_unsatisfiable_ranges() {
+        let (files, dir) = fixture(b"0123456789");
+
+        let full = get(&files, &[]);
+        assert_eq!(full.status, 200);
+        assert_eq!(full.header("Accept-Ranges"), Some("bytes"));
+        assert!(full.body.is_empty());
+        assert_eq!(body(full), b"0123456789");
+
+        let partial = get(&files, &[("Range", "bytes=2-4")]);
+        assert_eq!(partial.status, 206);
+        assert_eq!(partial.header("Content-Range"), Some("bytes 2-4/10"));
+        assert_eq!(body(partial), b"234");
+
+        let past_end = get(&files, &[("Range", "bytes=10-")]);
+        assert_eq!(past_end.status, 416);
+        assert_eq!(past_end.header("Content-Range"), Some("bytes */10"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn serves_multipart_ranges() {
+        let (files, dir) = fixture(b"0123456789");
+
+        let response = get(&files, &[("Range", "bytes=0-1,-2")]);
+        let content_type = response.header("Content-Type").unwrap();
+        let boundary = content_type.split("boundary=").nth(1).unwrap().to_string();
+        assert_eq!(response.status, 206);
+        let body = String::from_utf8(body(response)).unwrap();
+
+        assert!(body.contains("Content-Range: bytes 0-1/10\r\n\r\n01\r\n"));
+        assert!(body.contains("Content-Range: bytes 8-9/10\r\n\r\n89\r\n"));
+        assert!(body.ends_with(&format!("--{}--\r\n", boundary)));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stale_if_range_gets_the_whole_file() {
+        let (files, dir) = fixture(b"0123456789");
+        let etag = get(&files, &[]).header("ETag").unwrap().to_string();
+
+        let current = get(&files, &[("Range", "bytes=0-0"), ("If-Range", &etag)]);
+        assert_eq!(current.status, 206);
+
+        let stale = get(&files, &[("Range", "bytes=0-0"), ("If-Range", "\"old\"")]);
+        assert_eq!(stale.status, 200);
+        assert_eq!(body(stale), b"0123456789");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_to_leave_the_root() {
+        let files = StaticFiles::new("/srv/www");
+
+        assert_eq!(
+            files.resolve("/css/a%20b.css"),
+            Some(PathBuf::from("/srv/www/css/a b.css"))
+        );
+        assert_eq!(files.resolve("/../etc/passwd"), None);
+        assert_eq!(files.resolve("/%2e%2e/etc/passwd"), None);
+    }
+}
@@ -0,0 +1,170 @@
+use super::http::{Handler, Request, Response};
+
+enum HostPattern {
+    Exact(String),
+    /// `*.example.com`, stored as `.example.com`.
+    Wildcard(String),
+}
+
+impl HostPattern {
+    fn parse(pattern: &str) -> HostPattern {
+        let pattern = pattern.to_ascii_lowercase();
+        if pattern.starts_with("*.") {
+            HostPattern::Wildcard(pattern[1..].to_string())
+        } else {
+            HostPattern::Exact(pattern)
+        }
+    }
+}
+
+/// Picks a handler by the `Host` header, so one server can serve several sites.
+///
+/// An exact host name beats a wildcard, and among wildcards the longest suffix wins. A wildcard
+/// such as `*.example.com` matches any subdomain but not `example.com` itself. Requests for a host
+/// nobody claimed go to the fallback handler, or get `421 Misdirected Request` in strict mode.
+pub struct VirtualHosts {
+    hosts: Vec<(HostPattern, Box<dyn Handler>)>,
+    fallback: Option<Box<dyn Handler>>,
+    strict: bool,
+}
+
+impl VirtualHosts {
+    pub fn new() -> VirtualHosts {
+        VirtualHosts {
+            hosts: Vec::new(),
+            fallback: None,
+            strict: false,
+        }
+    }
+
+    pub fn host<H>(mut self, pattern: &str, handler: H) -> VirtualHosts
+    where
+        H: Handler + 'static,
+    {
+        self.hosts
+            .push((HostPattern::parse(pattern), Box::new(handler)));
+        self
+    }
+
+    pub fn fallback<H>(mut self, handler: H) -> VirtualHosts
+    where
+        H: Handler + 'static,
+    {
+        self.fallback = Some(Box::new(handler));
+        self
+    }
+
+    /// In strict mode an unknown `Host` is answered with 421 instead of the fallback handler, which
+    /// then only sees requests that carry no `Host` header at all.
+    pub fn strict(mut self, strict: bool) -> VirtualHosts {
+        self.strict = strict;
+        self
+    }
+
+    fn find(&self, host: &str) -> Option<&dyn Handler> {
+        let exact = self.hosts.iter().find(|(pattern, _)| match pattern {
+            HostPattern::Exact(name) => name == host,
+            _ => false,
+        });
+        if let Some((_, handler)) = exact {
+            return Some(&**handler);
+        }
+
+        self.hosts
+            .iter()
+            .filter_map(|(pattern, handler)| match pattern {
+                HostPattern::Wildcard(suffix)
+                    if host.ends_with(suffix.as_str()) && host.len() > suffix.len() =>
+                {
+                    Some((suffix.len(), handler))
+                }
+                _ => None,
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, handler)| &**handler)
+    }
+}
+
+impl Default for VirtualHosts {
+    fn default() -> VirtualHosts {
+        VirtualHosts::new()
+    }
+}
+
+impl Handler for VirtualHosts {
+    fn handle(&self, request: &mut Request) -> Response {
+        let handler = match request.header("Host").map(normalize_host) {
+            Some(host) => match self.find(&host) {
+                Some(handler) => Some(handler),
+                None if self.strict => return Response::new(421),
+                None => self.fallback.as_deref(),
+            },
+            None => self.fallback.as_deref(),
+        };
+
+        match handler {
+            Some(handler) => handler.handle(request),
+            None => Response::new(404).with_body("Not Found"),
+        }
+    }
+}
+
+// Drops the port and any trailing dot and lowercases, so `Example.COM.:8080` becomes `example.com`.
+// Bracketed IPv6 literals keep their brackets.
+fn normalize_host(host: &str) -> String {
+    let host = host.trim();
+    let without_port = if host.starts_with('[') {
+        match host.find(']') {
+            Some(i) => &host[..=i],
+            None => host,
+        }
+    } else {
+        match host.rfind(':') {
+            Some(i) => &host[..i],
+            None => host,
+        }
+    };
+    without_port.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn site(name: &'static str) -> impl Handler {
+        move |_: &mut Request| Response::new(200).with_body(name)
+    }
+
+    fn get(hosts: &VirtualHosts, host: Option<&str>) -> Response {
+        let mut request = Request::new("GET", "/");
+        if let Some(host) = host {
+            request = request.with_header("Host", host);
+        }
+        hosts.handle(&mut request)
+    }
+
+    #[test]
+    fn exact_beats_wildcard_and_longest_wildcard_wins() {
+        let hosts = VirtualHosts::new()
+            .host("*.example.com", site("any"))
+            .host("*.eu.example.com", site("eu"))
+            .host("www.example.com", site("www"))
+            .fallback(site("default"));
+
+        assert_eq!(get(&hosts, Some("WWW.example.com:8080")).body, b"www");
+        assert_eq!(get(&hosts, Some("api.example.com")).body, b"any");
+        assert_eq!(get(&hosts, Some("cdn.eu.example.com")).body, b"eu");
+        assert_eq!(get(&hosts, Some("example.com")).body, b"default");
+    }
+
+    #[test]
+    fn strict_mode_rejects_unknown_hosts() {
+        let hosts = VirtualHosts::new()
+            .host("example.com", site("main"))
+            .fallback(site("default"))
+            .strict(true);
+
+        assert_eq!(get(&hosts, Some("evil.test")).status, 421);
+        assert_eq!(get(&hosts, None).body, b"default");
+    }
+}